@@ -0,0 +1,101 @@
+//! Instruction throughput of the interpreter, with the decode cache and with the block engine
+//!
+//! Run with `cargo bench -p chip8_core --bench throughput`.
+
+use chip8_core::blocks::Engine;
+use chip8_core::quirks::QuirkPresets;
+use chip8_core::Chip8;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// Register math in a tight loop, where decoding is a large part of each instruction
+#[rustfmt::skip]
+const MATH: &[u8] = &[
+    0x70, 0x01, // 200: V0 += 1
+    0x81, 0x04, // 202: V1 += V0
+    0x82, 0x13, // 204: V2 ^= V1
+    0x83, 0x26, // 206: V3 = V2 >> 1
+    0x12, 0x00, // 208: jump to 0x200
+];
+
+/// Like [`MATH`], but also draws a sprite every 256 times around
+#[rustfmt::skip]
+const DRAW: &[u8] = &[
+    0x70, 0x01, // 200: V0 += 1
+    0x81, 0x04, // 202: V1 += V0
+    0x82, 0x13, // 204: V2 ^= V1
+    0x83, 0x26, // 206: V3 = V2 >> 1
+    0x30, 0x00, // 208: skip if V0 == 0
+    0x12, 0x00, // 20A: jump to 0x200
+    0xA0, 0x00, // 20C: I = the 0 glyph
+    0xD1, 0x25, // 20E: draw it at V1, V2
+    0x12, 0x00, // 210: jump to 0x200
+];
+
+/// Counts V0 up to 255 and starts over, which the block engine runs as a single fused loop
+#[rustfmt::skip]
+const COUNT: &[u8] = &[
+    0x60, 0x00, // 200: V0 = 0
+    0x70, 0x01, // 202: V0 += 1
+    0x30, 0xFF, // 204: skip if V0 == 255
+    0x12, 0x02, // 206: jump to 0x202
+    0x71, 0x01, // 208: V1 += 1
+    0x12, 0x00, // 20A: start over
+];
+
+/// Ways of running instructions to compare
+#[derive(Clone, Copy)]
+enum Setup {
+    Interpreter,
+    DecodeCache,
+    Blocks,
+}
+
+impl Setup {
+    const ALL: [(Setup, &'static str); 3] = [
+        (Setup::Interpreter, "interpreter"),
+        (Setup::DecodeCache, "decode cache"),
+        (Setup::Blocks, "blocks"),
+    ];
+}
+
+const CYCLES_PER_FRAME: usize = 1000;
+const FRAMES: usize = 10;
+
+fn system(rom: &[u8], setup: Setup) -> Chip8 {
+    let mut system = Chip8::new();
+    system.quirks.use_preset(QuirkPresets::Chip8);
+    // drawing ends the frame with this on, which would make the loop mostly frame overhead
+    system.quirks.display_wait = false;
+    system.seed_rng(0);
+    system.load_rom(rom, 0x200).unwrap();
+    match setup {
+        Setup::Interpreter => {}
+        Setup::DecodeCache => system.enable_decode_cache(),
+        Setup::Blocks => system.set_engine(Engine::Blocks),
+    }
+    system
+}
+
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements((CYCLES_PER_FRAME * FRAMES) as u64));
+    for (name, rom) in [("math", MATH), ("draw", DRAW), ("count", COUNT)] {
+        for (setup, label) in Setup::ALL {
+            group.bench_with_input(BenchmarkId::new(label, name), rom, |b, rom| {
+                b.iter_batched_ref(
+                    || system(rom, setup),
+                    |system| {
+                        for _ in 0..FRAMES {
+                            system.run_frame(CYCLES_PER_FRAME).unwrap();
+                        }
+                    },
+                    criterion::BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
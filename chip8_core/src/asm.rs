@@ -0,0 +1,531 @@
+//! A simple assembler and disassembler using the common CHIP-8 mnemonics
+//!
+//! ```text
+//! ; comments start with a semicolon
+//! start:
+//!     LD V0, 0x05
+//!     LD I, sprite
+//!     DRW V0, V0, 5
+//!     JP start
+//! sprite:
+//!     DB 0xF0, 0x90, 0x90, 0x90, 0xF0
+//! ```
+//!
+//! Numbers can be decimal, or hex with a `0x`, `#` or `$` prefix, or binary with `0b`.
+//! The disassembler's output assembles back into the same bytes.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::fmt::Write;
+
+use crate::prelude::*;
+
+use thiserror::Error;
+
+use crate::coverage::SourceMap;
+use crate::instruction::{Instruction, MathOperation};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AssembleError {
+    #[error("Unknown mnemonic {mnemonic:?} on line {line}")]
+    UnknownMnemonic { line: usize, mnemonic: String },
+    #[error("Invalid operands for {mnemonic} on line {line}")]
+    InvalidOperands { line: usize, mnemonic: String },
+    #[error("Can't parse operand {operand:?} on line {line}")]
+    InvalidOperand { line: usize, operand: String },
+    #[error("{value:#X} doesn't fit in {bits} bits on line {line}")]
+    OutOfRange { line: usize, value: u16, bits: u8 },
+    #[error("Undefined label {label:?} on line {line}")]
+    UndefinedLabel { line: usize, label: String },
+    #[error("Label {label:?} on line {line} is already defined")]
+    DuplicateLabel { line: usize, label: String },
+    #[error("The program goes past the end of memory on line {line}")]
+    OutOfMemory { line: usize },
+}
+
+/// The output of the assembler
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    /// Where each instruction came from, data directives aren't included
+    pub source_map: SourceMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand<'a> {
+    Register(u8),
+    /// `I`
+    Pointer,
+    /// `[I]`
+    PointerMemory,
+    /// `DT`
+    DelayTimer,
+    /// `ST`
+    SoundTimer,
+    /// `K`
+    Key,
+    /// `F`
+    Font,
+    /// `B`
+    Bcd,
+    Number(u16),
+    Label(&'a str),
+}
+
+/// A parsed source line, with labels and comments removed
+struct Statement<'a> {
+    line: usize,
+    address: u16,
+    mnemonic: String,
+    operands: Vec<Operand<'a>>,
+}
+
+/// Assembles source text into bytes that should be loaded at `load_address`
+///
+/// `file` is only used to fill in the source map.
+pub fn assemble(source: &str, file: &str, load_address: u16) -> Result<Assembly, AssembleError> {
+    // first pass: find the address of every label and statement
+    let mut labels = BTreeMap::new();
+    let mut statements = Vec::new();
+    let mut address = load_address as usize;
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut text = text.split(';').next().unwrap_or_default().trim();
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                break;
+            }
+            if labels.insert(label, address as u16).is_some() {
+                return Err(AssembleError::DuplicateLabel {
+                    line,
+                    label: label.to_owned(),
+                });
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let operands = operands
+            .split(',')
+            .map(str::trim)
+            .filter(|operand| !operand.is_empty())
+            .map(|operand| parse_operand(operand, line))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let size = match mnemonic.as_str() {
+            "DB" => operands.len(),
+            "DW" => operands.len() * 2,
+            _ => 2,
+        };
+        if address + size > 0x1000 {
+            return Err(AssembleError::OutOfMemory { line });
+        }
+        statements.push(Statement {
+            line,
+            address: address as u16,
+            mnemonic,
+            operands,
+        });
+        address += size;
+    }
+
+    // second pass: encode everything now that all the labels are known
+    let mut assembly = Assembly::default();
+    for statement in &statements {
+        let line = statement.line;
+        let value = |operand: &Operand, bits: u8| -> Result<u16, AssembleError> {
+            let value = match *operand {
+                Operand::Number(value) => value,
+                Operand::Label(label) => {
+                    *labels
+                        .get(label)
+                        .ok_or_else(|| AssembleError::UndefinedLabel {
+                            line,
+                            label: label.to_owned(),
+                        })?
+                }
+                _ => {
+                    return Err(AssembleError::InvalidOperands {
+                        line,
+                        mnemonic: statement.mnemonic.clone(),
+                    })
+                }
+            };
+            if bits < 16 && value >> bits != 0 {
+                return Err(AssembleError::OutOfRange { line, value, bits });
+            }
+            Ok(value)
+        };
+
+        match statement.mnemonic.as_str() {
+            "DB" => {
+                for operand in &statement.operands {
+                    assembly.bytes.push(value(operand, 8)? as u8);
+                }
+            }
+            "DW" => {
+                for operand in &statement.operands {
+                    assembly
+                        .bytes
+                        .extend_from_slice(&value(operand, 16)?.to_be_bytes());
+                }
+            }
+            _ => {
+                let instruction = encode(statement, value)?;
+                assembly.source_map.insert(statement.address, file, line);
+                assembly
+                    .bytes
+                    .extend_from_slice(&instruction.opcode().to_be_bytes());
+            }
+        }
+    }
+    Ok(assembly)
+}
+
+/// Turns a statement into an instruction, `value` resolves a number or label to a value of at most `bits` bits
+fn encode<'a>(
+    statement: &Statement<'a>,
+    value: impl Fn(&Operand<'a>, u8) -> Result<u16, AssembleError>,
+) -> Result<Instruction, AssembleError> {
+    use Operand::*;
+
+    let invalid = || AssembleError::InvalidOperands {
+        line: statement.line,
+        mnemonic: statement.mnemonic.clone(),
+    };
+    let math = |operation, destination, source| Instruction::Math {
+        source,
+        destination,
+        operation,
+    };
+
+    let instruction = match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+        ("CLS", []) => Instruction::ClearDisplay,
+        ("RET", []) => Instruction::Return,
+        ("HALT", []) => Instruction::Halt,
+        ("AUDIO", []) => Instruction::LoadAudioPattern,
+        ("SYS", [address]) => Instruction::MachineCodeCall(value(address, 12)?),
+        ("JP", [Register(0), offset]) => Instruction::JumpRelative {
+            offset: value(offset, 12)?,
+        },
+        ("JP", [address]) => Instruction::Goto {
+            address: value(address, 12)?,
+        },
+        ("CALL", [address]) => Instruction::Call {
+            address: value(address, 12)?,
+        },
+        ("SE", [Register(x), Register(y)]) => Instruction::RegistersEqual(*x, *y),
+        ("SE", [Register(register), byte]) => Instruction::RegisterEqualToConst {
+            register: *register,
+            value: value(byte, 8)? as u8,
+        },
+        ("SNE", [Register(x), Register(y)]) => Instruction::RegistersNotEqual(*x, *y),
+        ("SNE", [Register(register), byte]) => Instruction::RegisterNotEqualToConst {
+            register: *register,
+            value: value(byte, 8)? as u8,
+        },
+        ("LD", [Register(x), Register(y)]) => math(MathOperation::Assign, *x, *y),
+        ("LD", [Register(x), DelayTimer]) => Instruction::GetDelayTimer(*x),
+        ("LD", [Register(x), Key]) => Instruction::WaitKeyPress(*x),
+        ("LD", [Register(x), PointerMemory]) => Instruction::RegisterLoad(*x),
+        ("LD", [Register(register), byte]) => Instruction::SetRegister {
+            register: *register,
+            value: value(byte, 8)? as u8,
+        },
+        ("LD", [Pointer, address]) => Instruction::SetPointer(value(address, 12)?),
+        ("LD", [DelayTimer, Register(x)]) => Instruction::SetDelayTimer(*x),
+        ("LD", [SoundTimer, Register(x)]) => Instruction::SetSoundTimer(*x),
+        ("LD", [Font, Register(x)]) => Instruction::SetPointerToLetter(*x),
+        ("LD", [Bcd, Register(x)]) => Instruction::SplitNumber(*x),
+        ("LD", [PointerMemory, Register(x)]) => Instruction::RegisterDump(*x),
+        ("ADD", [Register(x), Register(y)]) => math(MathOperation::Add, *x, *y),
+        ("ADD", [Register(register), byte]) => Instruction::AddConst {
+            register: *register,
+            value: value(byte, 8)? as u8,
+        },
+        ("ADD", [Pointer, Register(x)]) => Instruction::AddToPointer(*x),
+        ("OR", [Register(x), Register(y)]) => math(MathOperation::BitwiseOr, *x, *y),
+        ("AND", [Register(x), Register(y)]) => math(MathOperation::BitwiseAnd, *x, *y),
+        ("XOR", [Register(x), Register(y)]) => math(MathOperation::BitwiseXor, *x, *y),
+        ("SUB", [Register(x), Register(y)]) => math(MathOperation::Subtract, *x, *y),
+        ("SUBN", [Register(x), Register(y)]) => math(MathOperation::Difference, *x, *y),
+        ("SHR", [Register(x)]) => math(MathOperation::BitshiftRight, *x, *x),
+        ("SHR", [Register(x), Register(y)]) => math(MathOperation::BitshiftRight, *x, *y),
+        ("SHL", [Register(x)]) => math(MathOperation::BitshiftLeft, *x, *x),
+        ("SHL", [Register(x), Register(y)]) => math(MathOperation::BitshiftLeft, *x, *y),
+        ("RND", [Register(register), mask]) => Instruction::Random {
+            register: *register,
+            mask: value(mask, 8)? as u8,
+        },
+        ("DRW", [Register(x), Register(y), height]) => Instruction::Draw {
+            position: (*x, *y),
+            height: value(height, 4)? as u8,
+        },
+        ("SKP", [Register(x)]) => Instruction::KeyPressed(*x),
+        ("SKNP", [Register(x)]) => Instruction::KeyNotPressed(*x),
+        ("PITCH", [Register(x)]) => Instruction::SetPitch(*x),
+        (
+            "CLS" | "RET" | "HALT" | "AUDIO" | "SYS" | "JP" | "CALL" | "SE" | "SNE" | "LD" | "ADD"
+            | "OR" | "AND" | "XOR" | "SUB" | "SUBN" | "SHR" | "SHL" | "RND" | "DRW" | "SKP"
+            | "SKNP" | "PITCH",
+            _,
+        ) => return Err(invalid()),
+        (mnemonic, _) => {
+            return Err(AssembleError::UnknownMnemonic {
+                line: statement.line,
+                mnemonic: mnemonic.to_owned(),
+            })
+        }
+    };
+    Ok(instruction)
+}
+
+fn parse_operand(operand: &str, line: usize) -> Result<Operand<'_>, AssembleError> {
+    let upper = operand.to_ascii_uppercase();
+    let parsed = match upper.as_str() {
+        "I" => Operand::Pointer,
+        "[I]" => Operand::PointerMemory,
+        "DT" => Operand::DelayTimer,
+        "ST" => Operand::SoundTimer,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "B" => Operand::Bcd,
+        _ => {
+            if let Some(register) = upper
+                .strip_prefix('V')
+                .filter(|digit| digit.len() == 1)
+                .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+            {
+                Operand::Register(register)
+            } else if let Some(number) = parse_number(&upper) {
+                Operand::Number(number)
+            } else if is_identifier(operand) {
+                Operand::Label(operand)
+            } else {
+                return Err(AssembleError::InvalidOperand {
+                    line,
+                    operand: operand.to_owned(),
+                });
+            }
+        }
+    };
+    Ok(parsed)
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    if let Some(hex) = text
+        .strip_prefix("0X")
+        .or_else(|| text.strip_prefix('#'))
+        .or_else(|| text.strip_prefix('$'))
+    {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0B") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The mnemonic form of an instruction, with addresses written as hex numbers
+pub fn mnemonic(instruction: &Instruction) -> String {
+    format_instruction(instruction, &|address| format!("{address:#05X}"))
+}
+
+/// Formats an instruction, using `address_name` to name any address it refers to
+fn format_instruction(instruction: &Instruction, address_name: &dyn Fn(u16) -> String) -> String {
+    use MathOperation::*;
+
+    match *instruction {
+        Instruction::MachineCodeCall(address) => format!("SYS {address:#05X}"),
+        Instruction::Halt => "HALT".to_owned(),
+        Instruction::ClearDisplay => "CLS".to_owned(),
+        Instruction::Return => "RET".to_owned(),
+        Instruction::Goto { address } => format!("JP {}", address_name(address)),
+        Instruction::Call { address } => format!("CALL {}", address_name(address)),
+        Instruction::RegisterEqualToConst { register, value } => {
+            format!("SE V{register:X}, {value:#04X}")
+        }
+        Instruction::RegisterNotEqualToConst { register, value } => {
+            format!("SNE V{register:X}, {value:#04X}")
+        }
+        Instruction::RegistersEqual(x, y) => format!("SE V{x:X}, V{y:X}"),
+        Instruction::SetRegister { register, value } => format!("LD V{register:X}, {value:#04X}"),
+        Instruction::AddConst { register, value } => format!("ADD V{register:X}, {value:#04X}"),
+        Instruction::Math {
+            source: y,
+            destination: x,
+            operation,
+        } => match operation {
+            Assign => format!("LD V{x:X}, V{y:X}"),
+            BitwiseOr => format!("OR V{x:X}, V{y:X}"),
+            BitwiseAnd => format!("AND V{x:X}, V{y:X}"),
+            BitwiseXor => format!("XOR V{x:X}, V{y:X}"),
+            Add => format!("ADD V{x:X}, V{y:X}"),
+            Subtract => format!("SUB V{x:X}, V{y:X}"),
+            BitshiftRight => format!("SHR V{x:X}, V{y:X}"),
+            Difference => format!("SUBN V{x:X}, V{y:X}"),
+            BitshiftLeft => format!("SHL V{x:X}, V{y:X}"),
+            UnknownOperation(opcode) => format!("DW {opcode:#06X}"),
+        },
+        Instruction::RegistersNotEqual(x, y) => format!("SNE V{x:X}, V{y:X}"),
+        Instruction::SetPointer(address) => format!("LD I, {}", address_name(address)),
+        Instruction::JumpRelative { offset } => format!("JP V0, {offset:#05X}"),
+        Instruction::Random { register, mask } => format!("RND V{register:X}, {mask:#04X}"),
+        Instruction::Draw {
+            position: (x, y),
+            height,
+        } => format!("DRW V{x:X}, V{y:X}, {height}"),
+        Instruction::KeyPressed(x) => format!("SKP V{x:X}"),
+        Instruction::KeyNotPressed(x) => format!("SKNP V{x:X}"),
+        Instruction::GetDelayTimer(x) => format!("LD V{x:X}, DT"),
+        Instruction::WaitKeyPress(x) => format!("LD V{x:X}, K"),
+        Instruction::SetDelayTimer(x) => format!("LD DT, V{x:X}"),
+        Instruction::SetSoundTimer(x) => format!("LD ST, V{x:X}"),
+        Instruction::AddToPointer(x) => format!("ADD I, V{x:X}"),
+        Instruction::SetPointerToLetter(x) => format!("LD F, V{x:X}"),
+        Instruction::SplitNumber(x) => format!("LD B, V{x:X}"),
+        Instruction::RegisterDump(x) => format!("LD [I], V{x:X}"),
+        Instruction::RegisterLoad(x) => format!("LD V{x:X}, [I]"),
+        Instruction::LoadAudioPattern => "AUDIO".to_owned(),
+        Instruction::SetPitch(x) => format!("PITCH V{x:X}"),
+        Instruction::UndefinedOperation(opcode) => format!("DW {opcode:#06X}"),
+    }
+}
+
+/// Disassembles a ROM loaded at `load_address` into source the assembler accepts
+///
+/// Every two bytes are treated as an instruction, so sprite data shows up as nonsense instructions,
+/// but the output still assembles back into the same ROM.
+/// Jump, call and `LD I` targets inside the ROM get labels.
+pub fn disassemble(rom: &[u8], load_address: u16) -> String {
+    let start = load_address as usize;
+    // a trailing odd byte is written as data, so it can't be labeled
+    let end = start + rom.len() - rom.len() % 2;
+    let instructions: Vec<(u16, u16)> = rom
+        .chunks_exact(2)
+        .enumerate()
+        .map(|(index, bytes)| {
+            let address = (start + index * 2) as u16;
+            (address, u16::from_be_bytes([bytes[0], bytes[1]]))
+        })
+        .collect();
+
+    // only addresses where an instruction starts can be labeled
+    let labels: BTreeSet<u16> = instructions
+        .iter()
+        .filter_map(|&(_, opcode)| match Instruction::from(opcode) {
+            Instruction::Goto { address }
+            | Instruction::Call { address }
+            | Instruction::SetPointer(address) => Some(address),
+            _ => None,
+        })
+        .filter(|&address| {
+            (start..end).contains(&(address as usize))
+                && (address as usize - start).is_multiple_of(2)
+        })
+        .collect();
+    let address_name = |address: u16| {
+        if labels.contains(&address) {
+            format!("L{address:03X}")
+        } else {
+            format!("{address:#05X}")
+        }
+    };
+
+    let mut output = String::new();
+    for &(address, opcode) in &instructions {
+        if labels.contains(&address) {
+            let _ = writeln!(output, "L{address:03X}:");
+        }
+        let instruction = Instruction::from(opcode);
+        // opcodes the decoder is lenient about wouldn't assemble back into the same bytes
+        let text = if instruction.opcode() == opcode {
+            format_instruction(&instruction, &address_name)
+        } else {
+            format!("DW {opcode:#06X}")
+        };
+        let _ = writeln!(output, "    {text:<20} ; {address:03X}: {opcode:04X}");
+    }
+    if rom.len() % 2 == 1 {
+        let _ = writeln!(output, "    DB {:#04X}", rom[rom.len() - 1]);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assemble_with_labels() {
+        let source = "
+            ; draw a box forever
+            start: LD V0, 5
+                LD I, sprite
+                DRW V0, V0, 5
+                JP start
+            sprite:
+                DB 0xF0, #90, $90, 0b10010000, 240
+        ";
+        let assembly = assemble(source, "box.8o", 0x200).unwrap();
+        assert_eq!(
+            assembly.bytes,
+            [0x60, 0x05, 0xA2, 0x08, 0xD0, 0x05, 0x12, 0x00, 0xF0, 0x90, 0x90, 0x90, 0xF0]
+        );
+        assert_eq!(assembly.source_map.get(0x200).unwrap().line, 3);
+        assert_eq!(assembly.source_map.get(0x206).unwrap().line, 6);
+        assert_eq!(assembly.source_map.get(0x208), None, "Data isn't mapped");
+    }
+
+    #[test]
+    fn assemble_errors() {
+        assert_eq!(
+            assemble("JP nowhere", "", 0x200),
+            Err(AssembleError::UndefinedLabel {
+                line: 1,
+                label: "nowhere".to_owned()
+            })
+        );
+        assert_eq!(
+            assemble("\nLD V0, 0x100", "", 0x200),
+            Err(AssembleError::OutOfRange {
+                line: 2,
+                value: 0x100,
+                bits: 8
+            })
+        );
+        assert!(matches!(
+            assemble("DRW V0, 1, 2", "", 0x200),
+            Err(AssembleError::InvalidOperands { line: 1, .. })
+        ));
+        assert!(matches!(
+            assemble("MOV V0, V1", "", 0x200),
+            Err(AssembleError::UnknownMnemonic { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn disassembly_round_trip() {
+        let rom: Vec<u8> = (0..=u16::MAX)
+            .step_by(7)
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .take(0xDFF)
+            .collect();
+        let source = disassemble(&rom, 0x200);
+        let assembly = assemble(&source, "rom.asm", 0x200).unwrap();
+        assert_eq!(assembly.bytes, rom);
+    }
+}
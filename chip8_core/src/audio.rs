@@ -0,0 +1,462 @@
+//! Sound generation shared by all frontends
+//!
+//! The CHIP-8 only knows whether the sound timer is running, so this module turns that
+//! into PCM samples. Samples are `f32` in the range -1.0 to 1.0.
+
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::Chip8;
+
+pub use crate::DEFAULT_PITCH;
+
+/// Number of bits in an XO-CHIP audio pattern
+const PATTERN_BITS: f32 = 128.0;
+
+/// How long it takes the output to fade in or out when the sound turns on or off, in seconds.
+/// Without this, starting or stopping the tone mid-cycle produces an audible click.
+const RAMP_TIME: f32 = 0.002;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+}
+
+/// The tone played while the sound timer is active
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    /// Frequency in Hz
+    pub frequency: f32,
+    /// Output volume from 0.0 to 1.0
+    pub volume: f32,
+    /// Fraction of each cycle the square wave is high, from 0.0 to 1.0
+    pub duty_cycle: f32,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+            duty_cycle: 0.5,
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl Tone {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(waveform: Waveform, frequency: f32, volume: f32) -> Self {
+        Self {
+            waveform,
+            frequency,
+            volume,
+            ..Default::default()
+        }
+    }
+}
+
+/// Turns the sound timer state into a stream of samples.
+///
+/// The oscillator phase is kept between calls to [`AudioGenerator::render`],
+/// so rendering a frame at a time produces the same output as rendering everything at once.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct AudioGenerator {
+    sample_rate: u32,
+    tone: Tone,
+    /// Position in the current cycle, from 0.0 to 1.0
+    phase: f32,
+    /// Current envelope level, ramps towards 1.0 when the sound is on and 0.0 when it's off
+    gain: f32,
+    /// Fractions of a sample left over from earlier frames, in units of 1 / frame rate
+    frame_remainder: u32,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl AudioGenerator {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            tone: Tone::default(),
+            phase: 0.0,
+            gain: 0.0,
+            frame_remainder: 0,
+        }
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn tone(&self) -> Tone {
+        self.tone
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone;
+    }
+
+    /// The number of samples that make up the next frame at the given frame rate
+    ///
+    /// When the sample rate doesn't divide evenly, the leftover fraction of a sample is carried
+    /// over to later frames, so some frames get one sample more and the total keeps up with time.
+    pub fn samples_per_frame(&mut self, frame_rate: u32) -> usize {
+        self.frame_remainder += self.sample_rate % frame_rate;
+        let extra = self.frame_remainder / frame_rate;
+        self.frame_remainder %= frame_rate;
+        (self.sample_rate / frame_rate + extra) as usize
+    }
+
+    /// Fills `output` with samples, playing the tone if `sound_on` is true and silence otherwise
+    pub fn render(&mut self, sound_on: bool, output: &mut [f32]) {
+        let phase_step = self.tone.frequency / self.sample_rate as f32;
+        let gain_step = 1.0 / (RAMP_TIME * self.sample_rate as f32).max(1.0);
+        let target = if sound_on { 1.0 } else { 0.0 };
+
+        for sample in output.iter_mut() {
+            self.step_gain(target, gain_step);
+
+            *sample = if self.gain > 0.0 {
+                self.oscillator(phase_step) * self.gain * self.tone.volume
+            } else {
+                0.0
+            };
+
+            self.phase = (self.phase + phase_step).fract();
+        }
+    }
+}
+
+impl AudioGenerator {
+    /// Fills `output` with the sound the CHIP-8 is currently making.
+    ///
+    /// Plays the XO-CHIP audio pattern if one was loaded, otherwise the configured tone.
+    pub fn render_chip8(&mut self, chip8: &Chip8, output: &mut [f32]) {
+        let sound_on = chip8.timers.is_sound_on();
+        match &chip8.audio_pattern {
+            Some(pattern) => self.render_pattern(sound_on, pattern, chip8.pitch, output),
+            None => self.render(sound_on, output),
+        }
+    }
+
+    /// Fills `output` by playing a 1-bit XO-CHIP audio pattern at the rate given by `pitch`.
+    ///
+    /// The position in the pattern is kept between calls, so swapping the pattern
+    /// between frames continues from the same bit rather than restarting.
+    pub fn render_pattern(
+        &mut self,
+        sound_on: bool,
+        pattern: &[u8; 16],
+        pitch: u8,
+        output: &mut [f32],
+    ) {
+        let bit_step = playback_rate(pitch) / self.sample_rate as f32;
+        let phase_step = bit_step / PATTERN_BITS;
+        let gain_step = 1.0 / (RAMP_TIME * self.sample_rate as f32).max(1.0);
+        let target = if sound_on { 1.0 } else { 0.0 };
+        let bit_value = |index: usize| {
+            let index = index % PATTERN_BITS as usize;
+            if pattern[index / 8] & (0x80 >> (index % 8)) != 0 {
+                1.0
+            } else {
+                -1.0
+            }
+        };
+
+        for sample in output.iter_mut() {
+            self.step_gain(target, gain_step);
+
+            *sample = if self.gain > 0.0 {
+                let position = self.phase * PATTERN_BITS;
+                let index = position as usize;
+                let bit_phase = position.fract();
+                let mut value = bit_value(index);
+                // Bits are too short to smooth once the pattern plays faster than half the sample rate
+                if bit_step < 0.5 {
+                    if bit_phase < bit_step {
+                        let previous = bit_value(index + PATTERN_BITS as usize - 1);
+                        value += (value - previous) / 2.0 * poly_blep(bit_phase, bit_step);
+                    } else if bit_phase > 1.0 - bit_step {
+                        let next = bit_value(index + 1);
+                        value += (next - value) / 2.0 * poly_blep(bit_phase, bit_step);
+                    }
+                }
+                value * self.gain * self.tone.volume
+            } else {
+                0.0
+            };
+
+            self.phase = (self.phase + phase_step).fract();
+        }
+    }
+
+    /// Moves the envelope one sample closer to `target`
+    fn step_gain(&mut self, target: f32, step: f32) {
+        if self.gain < target {
+            self.gain = (self.gain + step).min(target);
+        } else if self.gain > target {
+            self.gain = (self.gain - step).max(target);
+        }
+    }
+
+    /// Fills `output` with 16 bit samples, see [`AudioGenerator::render`]
+    pub fn render_i16(&mut self, sound_on: bool, output: &mut [i16]) {
+        let mut samples = vec![0.0; output.len()];
+        self.render(sound_on, &mut samples);
+        for (out, sample) in output.iter_mut().zip(samples) {
+            *out = to_i16(sample);
+        }
+    }
+
+    /// Fills `output` with 16 bit samples, see [`AudioGenerator::render_chip8`]
+    pub fn render_chip8_i16(&mut self, chip8: &Chip8, output: &mut [i16]) {
+        let mut samples = vec![0.0; output.len()];
+        self.render_chip8(chip8, &mut samples);
+        for (out, sample) in output.iter_mut().zip(samples) {
+            *out = to_i16(sample);
+        }
+    }
+
+    /// The band-limited value of the oscillator at the current phase
+    fn oscillator(&self, phase_step: f32) -> f32 {
+        use std::f32::consts::TAU;
+        let phase = self.phase;
+        match self.tone.waveform {
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Square => {
+                let duty = self.tone.duty_cycle.clamp(0.01, 0.99);
+                let naive = if phase < duty { 1.0 } else { -1.0 };
+                // smooth out the rising edge at 0 and the falling edge at the duty cycle
+                naive + poly_blep(phase, phase_step)
+                    - poly_blep((phase - duty + 1.0).fract(), phase_step)
+            }
+            Waveform::Sawtooth => {
+                let naive = 2.0 * phase - 1.0;
+                naive - poly_blep(phase, phase_step)
+            }
+            // The triangle's harmonics fall off quickly enough that aliasing isn't audible
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+/// Polynomial band-limited step, used to remove the aliasing caused by the discontinuities in a naive waveform.
+///
+/// `phase` is the distance from the discontinuity as a fraction of the cycle,
+/// and `step` is how far the phase moves per sample.
+fn poly_blep(phase: f32, step: f32) -> f32 {
+    if step <= 0.0 {
+        0.0
+    } else if phase < step {
+        let t = phase / step;
+        t + t - t * t - 1.0
+    } else if phase > 1.0 - step {
+        let t = (phase - 1.0) / step;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// The XO-CHIP audio pattern playback rate in bits per second for a pitch register value
+pub fn playback_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+/// Writes 16 bit mono samples as a WAV file
+pub fn write_wav<W: std::io::Write>(
+    mut writer: W,
+    sample_rate: u32,
+    samples: &[i16],
+) -> std::io::Result<()> {
+    const CHANNELS: u16 = 1;
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_size = (samples.len() * block_align as usize) as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM format
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&CHANNELS.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Converts a sample from -1.0..1.0 to the full range of an i16
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silent_when_off() {
+        let mut generator = AudioGenerator::new(44100);
+        let mut buffer = [1.0; 512];
+        generator.render(false, &mut buffer);
+        assert!(buffer.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn frames_add_up_to_the_sample_rate() {
+        for sample_rate in [44100, 48000, 22050, 11025] {
+            let mut generator = AudioGenerator::new(sample_rate);
+            let frames: Vec<usize> = (0..60).map(|_| generator.samples_per_frame(60)).collect();
+            assert_eq!(frames.iter().sum::<usize>(), sample_rate as usize);
+            let shortest = sample_rate as usize / 60;
+            assert!(frames
+                .iter()
+                .all(|&len| len == shortest || len == shortest + 1));
+        }
+    }
+
+    #[test]
+    fn phase_continuous_across_frames() {
+        let mut whole = AudioGenerator::new(44100);
+        let mut split = whole.clone();
+
+        let mut expected = [0.0; 1470];
+        whole.render(true, &mut expected);
+
+        let mut actual = [0.0; 1470];
+        let (first, second) = actual.split_at_mut(735);
+        split.render(true, first);
+        split.render(true, second);
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn square_wave_frequency() {
+        let mut generator = AudioGenerator::new(48000);
+        generator.set_tone(Tone::new(Waveform::Square, 480.0, 1.0));
+        let mut buffer = vec![0.0; 48000];
+        generator.render(true, &mut buffer);
+
+        let rising_edges = buffer
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!((479..=481).contains(&rising_edges), "{rising_edges}");
+        assert!(buffer.iter().all(|sample| sample.abs() <= 1.0));
+    }
+
+    #[test]
+    fn pattern_playback_rate() {
+        assert_eq!(playback_rate(DEFAULT_PITCH), 4000.0);
+        assert!((playback_rate(112) - 8000.0).abs() < 0.01);
+        assert!((playback_rate(16) - 2000.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn pattern_phase_kept_across_reloads() {
+        // alternating bytes of ones and zeros, then the same with every bit flipped
+        let pattern: [u8; 16] = [0xFF, 0x00].repeat(8).try_into().unwrap();
+        let inverted = pattern.map(|byte| !byte);
+        let mut whole = AudioGenerator::new(44100);
+        let mut reloaded = whole.clone();
+
+        let mut expected = [0.0; 1470];
+        whole.render_pattern(true, &pattern, 100, &mut expected);
+
+        let mut actual = [0.0; 1470];
+        let (first, second) = actual.split_at_mut(735);
+        reloaded.render_pattern(true, &pattern, 100, first);
+        let mut restarted = reloaded.clone();
+        restarted.phase = 0.0;
+        // loading a new pattern mid-playback continues from the same bit, so every sample flips sign
+        reloaded.render_pattern(true, &inverted, 100, second);
+
+        assert_eq!(expected[..735], actual[..735]);
+        let flipped: Vec<f32> = expected[735..].iter().map(|&sample| -sample).collect();
+        assert_eq!(flipped, actual[735..]);
+
+        // restarting the pattern would have sounded different
+        let mut from_the_start = [0.0; 735];
+        restarted.render_pattern(true, &inverted, 100, &mut from_the_start);
+        assert_ne!(from_the_start[..], actual[735..]);
+        assert!(expected.iter().any(|&sample| sample > 0.2));
+        assert!(expected.iter().any(|&sample| sample < -0.2));
+    }
+
+    #[test]
+    fn chip8_loads_pattern() {
+        let mut chip8 = Chip8::new();
+        chip8.pointer = 0x300;
+        chip8.memory[0x300..0x310].copy_from_slice(&[0xAA; 16]);
+        chip8.registers[3] = 80;
+        chip8
+            .handle_instruction(0xF002.into())
+            .expect("Loading the audio pattern failed");
+        chip8
+            .handle_instruction(0xF33A.into())
+            .expect("Setting the pitch failed");
+
+        assert_eq!(chip8.audio_pattern, Some([0xAA; 16]));
+        assert_eq!(chip8.pitch, 80);
+    }
+
+    #[test]
+    fn pattern_at_the_end_of_memory_wraps() {
+        let mut chip8 = Chip8::new();
+        let len = chip8.memory.len();
+        chip8.pointer = (len - 8) as u16;
+        chip8.memory[len - 8..].fill(0xFF);
+        chip8.memory[..8].fill(0x0F);
+        chip8
+            .handle_instruction(0xF002.into())
+            .expect("Loading the audio pattern failed");
+
+        let mut expected = [0xFF; 16];
+        expected[8..].fill(0x0F);
+        assert_eq!(chip8.audio_pattern, Some(expected));
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 44100, &[0, 1, -1]).unwrap();
+
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..12], b"WAVE");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 6);
+        assert_eq!(&wav[48..50], &(-1i16).to_le_bytes());
+    }
+
+    #[test]
+    fn i16_conversion() {
+        assert_eq!(to_i16(1.0), i16::MAX);
+        assert_eq!(to_i16(-2.0), -i16::MAX);
+        assert_eq!(to_i16(0.0), 0);
+    }
+}
@@ -0,0 +1,509 @@
+//! An execution engine that compiles basic blocks into chains of closures
+//!
+//! Select it with [`Chip8::set_engine`](crate::Chip8::set_engine). A block starts wherever the
+//! program counter lands and runs until the next jump or skip, or until an instruction that does
+//! more than change registers, such as drawing, which is left to the interpreter. A few common
+//! patterns are fused into a single closure:
+//! - runs of `7XNN` adding to the same register
+//! - a skip followed by a jump, which becomes a conditional branch
+//! - loops that count a register up to a constant with `7XNN`, `3XNN` or `4XNN`, then `1NNN` back
+//!
+//! Blocks are thrown away when they're overwritten, and code the program wrote itself is left
+//! to the interpreter from then on.
+
+use alloc::rc::Rc;
+use core::ops::Range;
+
+use num_traits::FromPrimitive;
+use rand::Rng;
+
+use crate::instruction::{Instruction, MathOperation, SkipCondition};
+use crate::keypad::Key;
+use crate::prelude::*;
+use crate::{font, Chip8};
+
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// How [`Chip8::run_frame`] executes instructions
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Decodes and runs one instruction at a time
+    #[default]
+    Interpreter,
+    /// Runs compiled basic blocks, falling back to the interpreter for everything else
+    Blocks,
+}
+
+/// Runs part of a block given how many instructions are left in the frame
+/// # Returns
+/// Returns how many instructions it ran
+type Op = Box<dyn Fn(&mut Chip8, usize) -> usize>;
+
+/// Runs an instruction that doesn't affect the program counter
+type Effect = Box<dyn Fn(&mut Chip8)>;
+
+/// Checks whether a skip instruction skips
+type Condition = Box<dyn Fn(&Chip8) -> bool>;
+
+/// Longest block to compile, in instructions
+const MAX_BLOCK_LEN: usize = 32;
+
+/// Compiled code starting at one address
+struct Block {
+    ops: Vec<Op>,
+    /// The most instructions the block can run, not counting extra iterations of a fused loop
+    len: usize,
+    /// The bytes the block was compiled from
+    bytes: Range<usize>,
+}
+
+/// Blocks compiled so far, by start address
+pub struct BlockCache {
+    blocks: Vec<Option<Rc<Block>>>,
+    /// Bytes the program wrote to, which are left to the interpreter
+    written: Vec<bool>,
+    /// Bytes in the longest block compiled, which can run past [`MAX_BLOCK_LEN`] instructions when a skip is fused
+    longest: usize,
+}
+
+impl BlockCache {
+    /// An empty cache for `size` bytes of memory
+    pub fn new(size: usize) -> Self {
+        Self {
+            blocks: vec![None; size],
+            written: vec![false; size],
+            longest: 0,
+        }
+    }
+
+    /// Throws away the blocks compiled from any of the bytes in `range`
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        // only blocks starting this far back can reach the range
+        let first = range.start.saturating_sub(self.longest);
+        let last = range.end.min(self.blocks.len());
+        for entry in &mut self.blocks[first..last] {
+            if entry
+                .as_ref()
+                .is_some_and(|block| block.bytes.start < range.end && range.start < block.bytes.end)
+            {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Throws away the blocks in `range` like [`BlockCache::invalidate`],
+    /// and interprets those bytes from now on since the program is modifying its own code
+    pub fn self_modified(&mut self, range: Range<usize>) {
+        self.invalidate(range.clone());
+        let end = range.end.min(self.written.len());
+        self.written[range.start..end].fill(true);
+    }
+
+    /// Forgets every block, and which bytes the program wrote to
+    pub fn clear(&mut self) {
+        self.blocks.fill(None);
+        self.written.fill(false);
+    }
+
+    /// How many blocks are compiled
+    pub fn len(&self) -> usize {
+        self.blocks.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&mut self, memory: &[u8], address: u16) -> Rc<Block> {
+        let entry = &mut self.blocks[address as usize];
+        entry
+            .get_or_insert_with(|| {
+                let block = compile(memory, &self.written, address);
+                self.longest = self.longest.max(block.bytes.len());
+                Rc::new(block)
+            })
+            .clone()
+    }
+}
+
+/// Decodes the instruction at `address`, unless it's past the end of memory or the program wrote to it
+fn decode_at(memory: &[u8], written: &[bool], address: usize) -> Option<Instruction> {
+    if address + 1 >= memory.len() || written[address] || written[address + 1] {
+        return None;
+    }
+    Some(u16::from_be_bytes([memory[address], memory[address + 1]]).into())
+}
+
+fn compile(memory: &[u8], written: &[bool], start: u16) -> Block {
+    let decode = |address: u16| decode_at(memory, written, address as usize);
+    if let Some(op) = fuse_loop(start, decode) {
+        return Block {
+            ops: vec![op],
+            len: 3,
+            bytes: start as usize..start as usize + 6,
+        };
+    }
+
+    let mut ops = Vec::new();
+    let mut len = 0;
+    let mut address = start;
+    while len < MAX_BLOCK_LEN {
+        let Some(instruction) = decode(address) else {
+            break;
+        };
+        let next = address + 2;
+        match instruction {
+            Instruction::AddConst { register, value } => {
+                // fold the following adds to the same register into this one
+                let mut total = value;
+                let mut count = 1;
+                while len + count < MAX_BLOCK_LEN {
+                    match decode(address + 2 * count as u16) {
+                        Some(Instruction::AddConst {
+                            register: other,
+                            value,
+                        }) if other == register => total = total.wrapping_add(value),
+                        _ => break,
+                    }
+                    count += 1;
+                }
+                let register = register as usize;
+                let end = address + 2 * count as u16;
+                ops.push(Box::new(move |vm: &mut Chip8, _| {
+                    vm.registers[register] = vm.registers[register].wrapping_add(total);
+                    vm.pc = end;
+                    count
+                }) as Op);
+                len += count;
+                address = end;
+                continue;
+            }
+            Instruction::Goto { address: target } => {
+                ops.push(Box::new(move |vm: &mut Chip8, _| {
+                    vm.pc = target;
+                    1
+                }));
+                len += 1;
+                break;
+            }
+            Instruction::Call { address: target } => {
+                ops.push(Box::new(move |vm: &mut Chip8, _| {
+                    // the interpreter reports the overflow
+                    if vm.is_stack_full() {
+                        vm.pc = address;
+                        return 0;
+                    }
+                    vm.stack.push(next);
+                    vm.pc = target;
+                    1
+                }));
+                len += 1;
+                break;
+            }
+            _ => {}
+        }
+        if let Some(skips) = condition(instruction) {
+            if let Some(Instruction::Goto { address: target }) = decode(next) {
+                // taking the skip jumps over the jump
+                ops.push(Box::new(move |vm: &mut Chip8, _| {
+                    if skips(vm) {
+                        vm.pc = next + 2;
+                        1
+                    } else {
+                        vm.pc = target;
+                        2
+                    }
+                }));
+                len += 2;
+                address = next;
+            } else {
+                ops.push(Box::new(move |vm: &mut Chip8, _| {
+                    vm.pc = if skips(vm) { next + 2 } else { next };
+                    1
+                }));
+                len += 1;
+            }
+            break;
+        }
+        let Some(run) = straight_line(instruction) else {
+            break;
+        };
+        ops.push(Box::new(move |vm: &mut Chip8, _| {
+            run(vm);
+            vm.pc = next;
+            1
+        }));
+        len += 1;
+        address = next;
+    }
+    Block {
+        ops,
+        len,
+        bytes: start as usize..address as usize + 2,
+    }
+}
+
+/// Compiles instructions that only change registers or the pointer, leaving the program counter to the caller
+fn straight_line(instruction: Instruction) -> Option<Effect> {
+    Some(match instruction {
+        Instruction::SetRegister { register, value } => {
+            Box::new(move |vm| vm.registers[register as usize] = value)
+        }
+        Instruction::Math {
+            source,
+            destination,
+            operation,
+        } if !matches!(operation, MathOperation::UnknownOperation(_)) => Box::new(move |vm| {
+            vm.handle_math(source, destination, operation)
+                .expect("Only known math operations are compiled")
+        }),
+        Instruction::SetPointer(address) => Box::new(move |vm| vm.pointer = address),
+        Instruction::AddToPointer(register) => {
+            Box::new(move |vm| vm.pointer += vm.registers[register as usize] as u16)
+        }
+        Instruction::SetPointerToLetter(register) => Box::new(move |vm| {
+            vm.pointer = vm.font_address + font::get_letter_address(vm.registers[register as usize])
+        }),
+        Instruction::GetDelayTimer(register) => {
+            Box::new(move |vm| vm.registers[register as usize] = vm.timers.delay as u8)
+        }
+        Instruction::Random { register, mask } => Box::new(move |vm| {
+            let rand: u8 = vm.rng.gen();
+            vm.registers[register as usize] = rand & mask
+        }),
+        Instruction::RegisterLoad(register) => Box::new(move |vm| {
+            let start = vm.pointer as usize;
+            let end = start + register as usize + 1;
+            vm.registers[..=register as usize].copy_from_slice(&vm.memory[start..end]);
+            if vm.quirks.save_load_set_pointer {
+                vm.pointer += (register as u16) + 1;
+            }
+        }),
+        _ => return None,
+    })
+}
+
+/// The condition under which a skip instruction skips, or None if it isn't one
+fn condition(instruction: Instruction) -> Option<Condition> {
+    Some(match instruction.skip_condition()? {
+        SkipCondition::Const {
+            register,
+            value,
+            equal,
+        } => Box::new(move |vm| (vm.registers[register as usize] == value) == equal),
+        SkipCondition::Registers { x, y, equal } => {
+            Box::new(move |vm| (vm.registers[x as usize] == vm.registers[y as usize]) == equal)
+        }
+        SkipCondition::Key { register, pressed } => Box::new(move |vm| {
+            let key = Key::from_u8(vm.registers[register as usize])
+                .expect("Register contains value not in keypad range (0-15)");
+            vm.keypad.is_key_pressed(key) == pressed
+        }),
+    })
+}
+
+/// Fuses a loop that adds to a register until a skip leaves it:
+/// `7XNN` at `start`, then `3XKK` or `4XKK`, then a `1NNN` back to `start`
+fn fuse_loop(start: u16, decode: impl Fn(u16) -> Option<Instruction>) -> Option<Op> {
+    let Some(Instruction::AddConst { register, value }) = decode(start) else {
+        return None;
+    };
+    let (exits_when_equal, target) = match decode(start + 2)? {
+        Instruction::RegisterEqualToConst {
+            register: other,
+            value,
+        } if other == register => (true, value),
+        Instruction::RegisterNotEqualToConst {
+            register: other,
+            value,
+        } if other == register => (false, value),
+        _ => return None,
+    };
+    if decode(start + 4)? != (Instruction::Goto { address: start }) {
+        return None;
+    }
+    let register = register as usize;
+    Some(Box::new(move |vm: &mut Chip8, budget| {
+        let mut current = vm.registers[register];
+        let mut ran = 0;
+        // only whole iterations, so the interpreter can finish a frame that ends partway through one
+        let exited = loop {
+            if budget - ran < 3 {
+                break false;
+            }
+            current = current.wrapping_add(value);
+            if (current == target) == exits_when_equal {
+                ran += 2;
+                break true;
+            }
+            ran += 3;
+        };
+        vm.registers[register] = current;
+        vm.pc = if exited { start + 6 } else { start };
+        ran
+    }))
+}
+
+impl Chip8 {
+    /// Runs the block at the program counter for [`Chip8::run_frame_with`], compiling it if needed
+    /// # Returns
+    /// Returns how many instructions ran, which is 0 if the block doesn't fit in `budget`
+    /// or the instruction there has to be interpreted
+    pub(crate) fn run_block(&mut self, budget: usize) -> usize {
+        let pc = self.pc;
+        let Some(block) = self
+            .blocks
+            .as_mut()
+            .map(|blocks| blocks.get(&self.memory, pc))
+        else {
+            return 0;
+        };
+        if block.ops.is_empty() || block.len > budget {
+            return 0;
+        }
+        let mut remaining = budget;
+        for op in &block.ops {
+            remaining -= op(self, remaining);
+        }
+        budget - remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::QuirkPresets;
+
+    fn systems(rom: &[u8]) -> (Chip8, Chip8) {
+        let mut blocks = Chip8::new();
+        let mut plain = Chip8::new();
+        for system in [&mut blocks, &mut plain] {
+            system.quirks.use_preset(QuirkPresets::Chip8);
+            system.seed_rng(0);
+            system.load_rom(rom, 0x200).unwrap();
+        }
+        blocks.set_engine(Engine::Blocks);
+        (blocks, plain)
+    }
+
+    /// Runs the same program on both engines, comparing the VMs after every frame
+    fn assert_same_as_interpreter(rom: &[u8], frames: usize, cycles: usize) -> Chip8 {
+        let (mut blocks, mut plain) = systems(rom);
+        for frame in 0..frames {
+            blocks.run_frame(cycles).unwrap();
+            plain.run_frame(cycles).unwrap();
+            assert_eq!(blocks.pc, plain.pc, "frame {frame}");
+            assert_eq!(blocks.registers, plain.registers, "frame {frame}");
+            assert_eq!(blocks.pointer, plain.pointer, "frame {frame}");
+            assert_eq!(blocks.stack, plain.stack, "frame {frame}");
+            assert_eq!(blocks.memory, plain.memory, "frame {frame}");
+            assert_eq!(blocks.display, plain.display, "frame {frame}");
+            assert_eq!(blocks.running, plain.running, "frame {frame}");
+        }
+        blocks
+    }
+
+    #[test]
+    fn counting_loop() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x00, // 200: V0 = 0
+            0x70, 0x03, // 202: V0 += 3
+            0x30, 0x2A, // 204: skip if V0 == 42
+            0x12, 0x02, // 206: jump to 0x202
+            0x71, 0x01, // 208: V1 += 1
+            0x12, 0x00, // 20A: start over
+        ];
+        // frames that end partway through a loop iteration
+        for cycles in [1, 2, 5, 7, 10, 11, 100] {
+            let system = assert_same_as_interpreter(&rom, 40, cycles);
+            assert!(!system.compiled_blocks().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn fused_patterns() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x05, // 200: V0 = 5
+            0x70, 0x01, // 202: V0 += 1
+            0x70, 0x02, // 204: V0 += 2
+            0x70, 0xFF, // 206: V0 += 255
+            0x81, 0x04, // 208: V1 += V0, with carry
+            0xC2, 0x0F, // 20A: V2 = random & 0xF
+            0xA0, 0x00, // 20C: I = 0
+            0xF1, 0x65, // 20E: load V0 and V1 from the font
+            0x41, 0x00, // 210: skip if V1 != 0
+            0x12, 0x18, // 212: jump to 0x218
+            0xE2, 0xA1, // 214: skip if the key in V2 isn't pressed
+            0x12, 0x00, // 216: jump to the start
+            0x22, 0x1C, // 218: call 0x21C
+            0x12, 0x00, // 21A: jump to the start
+            0x00, 0xEE, // 21C: return
+        ];
+        for cycles in [1, 3, 4, 9, 50] {
+            assert_same_as_interpreter(&rom, 20, cycles);
+        }
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x71, // 200: V0 = 0x71
+            0x61, 0x05, // 202: V1 = 5
+            0x22, 0x10, // 204: call 0x210
+            0xA2, 0x10, // 206: I = 0x210
+            0xF1, 0x55, // 208: store V0 and V1 at 0x210, turning the add into V1 += 5
+            0x22, 0x10, // 20A: call 0x210 again
+            0x12, 0x0C, // 20C: loop forever
+            0x00, 0x00,
+            0x72, 0x01, // 210: V2 += 1
+            0x00, 0xEE, // 212: return
+        ];
+        let system = assert_same_as_interpreter(&rom, 3, 5);
+        assert_eq!(system.registers[1..3], [10, 1]);
+        // the overwritten subroutine is interpreted from then on
+        assert!(system.compiled_blocks().unwrap().written[0x210]);
+    }
+
+    #[test]
+    fn external_writes() {
+        let mut system = Chip8::new();
+        system.set_engine(Engine::Blocks);
+        system.load_rom(&[0x60, 0x01, 0x12, 0x00], 0x200).unwrap();
+        system.run_frame(4).unwrap();
+        system.write_memory(0x201, &[0x09]).unwrap();
+        system.run_frame(1).unwrap();
+        assert_eq!(system.registers[0], 9);
+
+        system.set_engine(Engine::Interpreter);
+        assert!(system.compiled_blocks().is_none());
+    }
+
+    #[test]
+    fn writes_to_the_end_of_a_fused_block() {
+        // 31 loads, then a skip fused with the jump after it, for a block of 33 instructions
+        let mut rom: Vec<u8> = (0..31).flat_map(|value| [0x61, value]).collect();
+        rom.extend([
+            0x40, 0x00, // 23E: skip if V0 != 0
+            0x12, 0x00, // 240: jump to the start
+            0x60, 0x07, // 242: V0 = 7
+            0x12, 0x44, // 244: loop forever
+        ]);
+        let mut system = Chip8::new();
+        system.set_engine(Engine::Blocks);
+        system.load_rom(&rom, 0x200).unwrap();
+        system.run_frame(33).unwrap();
+        assert_eq!(system.pc, 0x200);
+
+        // point the jump, the block's last byte, past the loop
+        system.write_memory(0x241, &[0x42]).unwrap();
+        system.run_frame(34).unwrap();
+        assert_eq!(system.registers[0], 7);
+    }
+}
@@ -0,0 +1,323 @@
+//! Configuring a [`Chip8`] before it starts
+//!
+//! ```
+//! use chip8_core::quirks::QuirkPresets;
+//! use chip8_core::Chip8Builder;
+//!
+//! let system = Chip8Builder::new()
+//!     .platform(QuirkPresets::SuperChip)
+//!     .seed(1)
+//!     .stack_depth(16)
+//!     .rom(&[0x12, 0x00])
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(system.pc, 0x200);
+//! ```
+
+use thiserror::Error;
+
+use crate::blocks::Engine;
+use crate::handler::Hooks;
+use crate::keypad::KeyWait;
+use crate::prelude::*;
+use crate::quirks::{QuirkConfig, QuirkPresets};
+use crate::time::{ClockSource, Timers};
+use crate::{font, Chip8, LoadError, DEFAULT_PITCH};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// The least memory a VM can have, since instructions can address anywhere in the first 4 KiB
+pub const MIN_MEMORY_SIZE: usize = 0x1000;
+
+/// The most memory 16 bit addresses can reach
+pub const MAX_MEMORY_SIZE: usize = 0x10000;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    #[error("Memory can't be {size} bytes, it has to be between {MIN_MEMORY_SIZE:#X} and {MAX_MEMORY_SIZE:#X} bytes")]
+    MemorySize { size: usize },
+    #[error("A font is {} bytes, 5 for each digit, not {size}", font::FONT_SIZE)]
+    FontSize { size: usize },
+    #[error("The font at {address:#X} doesn't fit in {memory_size} bytes of memory")]
+    FontOutsideMemory { address: u16, memory_size: usize },
+    #[error("The font at {font:#X} overlaps the ROM loaded at {rom:#X}")]
+    FontOverlapsRom { font: u16, rom: u16 },
+    #[error("The stack has to hold at least one return address")]
+    EmptyStack,
+    #[error(transparent)]
+    Load(#[from] LoadError),
+}
+
+/// Options for a new [`Chip8`], checked together by [`Chip8Builder::build`]
+#[derive(Clone, Debug)]
+pub struct Chip8Builder {
+    pub(crate) quirks: QuirkConfig,
+    pub(crate) memory_size: usize,
+    pub(crate) font: Option<Vec<u8>>,
+    pub(crate) font_address: u16,
+    pub(crate) load_address: u16,
+    pub(crate) seed: Option<u64>,
+    pub(crate) clock: ClockSource,
+    pub(crate) stack_depth: Option<usize>,
+    pub(crate) engine: Engine,
+    pub(crate) rom: Option<Vec<u8>>,
+}
+
+impl Default for Chip8Builder {
+    fn default() -> Self {
+        Self {
+            quirks: QuirkConfig::default(),
+            memory_size: 0x1000,
+            font: None,
+            font_address: 0,
+            load_address: 0x200,
+            seed: None,
+            clock: ClockSource::default(),
+            stack_depth: None,
+            engine: Engine::default(),
+            rom: None,
+        }
+    }
+}
+
+impl Chip8Builder {
+    /// A CHIP-8 with 4 KiB of memory, the built in font at 0, and an unbounded stack
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses the quirks and memory size of a platform, which later calls can override
+    ///
+    /// XO-CHIP has 64 KiB of memory, the others 4 KiB.
+    pub fn platform(mut self, platform: QuirkPresets) -> Self {
+        self.quirks.use_preset(platform);
+        self.memory_size = match platform {
+            QuirkPresets::Chip8 | QuirkPresets::SuperChip => 0x1000,
+            QuirkPresets::XoChip => MAX_MEMORY_SIZE,
+        };
+        self
+    }
+
+    pub fn quirks(mut self, quirks: QuirkConfig) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// The size of memory in bytes, from 4 KiB up to 64 KiB
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.memory_size = size;
+        self
+    }
+
+    /// Replaces the built in font, which has to be 5 bytes for each digit from 0 to F
+    pub fn font(mut self, font: &[u8]) -> Self {
+        self.font = Some(font.to_vec());
+        self
+    }
+
+    /// Where the font is loaded, 0 by default
+    pub fn font_address(mut self, address: u16) -> Self {
+        self.font_address = address;
+        self
+    }
+
+    /// Where the ROM is loaded and execution starts, 0x200 by default
+    pub fn load_address(mut self, address: u16) -> Self {
+        self.load_address = address;
+        self
+    }
+
+    /// Seeds the random number generator, so runs can be reproduced
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn clock(mut self, clock: ClockSource) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Limits how many calls can be nested, like the 12 or 16 levels of the original interpreters
+    pub fn stack_depth(mut self, depth: usize) -> Self {
+        self.stack_depth = Some(depth);
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// The ROM to load at the load address
+    pub fn rom(mut self, rom: &[u8]) -> Self {
+        self.rom = Some(rom.to_vec());
+        self
+    }
+
+    /// Creates the VM with the font and ROM loaded
+    /// # Errors
+    /// Returns an error if the font or ROM don't fit in memory, if they overlap,
+    /// or if the memory size or stack depth are unusable
+    pub fn build(&self) -> Result<Chip8, BuildError> {
+        let font = self.font.as_deref();
+        let font_size = font.map_or(font::FONT_SIZE, <[u8]>::len);
+        if font_size != font::FONT_SIZE {
+            return Err(BuildError::FontSize { size: font_size });
+        }
+        if !(MIN_MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&self.memory_size) {
+            return Err(BuildError::MemorySize {
+                size: self.memory_size,
+            });
+        }
+        let font_range = self.font_address as usize..self.font_address as usize + font_size;
+        if font_range.end > self.memory_size {
+            return Err(BuildError::FontOutsideMemory {
+                address: self.font_address,
+                memory_size: self.memory_size,
+            });
+        }
+        let rom_range = self.load_address as usize
+            ..self.load_address as usize + self.rom.as_ref().map_or(0, Vec::len);
+        if font_range.start < rom_range.end && rom_range.start < font_range.end {
+            return Err(BuildError::FontOverlapsRom {
+                font: self.font_address,
+                rom: self.load_address,
+            });
+        }
+        if self.stack_depth == Some(0) {
+            return Err(BuildError::EmptyStack);
+        }
+
+        let mut system = Chip8 {
+            memory: vec![0; self.memory_size],
+            font_address: self.font_address,
+            font: [0; font::FONT_SIZE],
+            rom: Vec::new(),
+            load_address: self.load_address,
+            registers: [0; 16],
+            pointer: 0,
+            pc: self.load_address,
+            stack: Vec::new(),
+            stack_depth: self.stack_depth,
+            timers: Timers::new(),
+            clock: self.clock,
+            display: Default::default(),
+            keypad: Default::default(),
+            running: true,
+            key_wait: KeyWait::Idle,
+            quirks: self.quirks.clone(),
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
+            coverage: None,
+            decode_cache: None,
+            blocks: None,
+            recording: None,
+            rng: match self.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => crate::new_rng(),
+            },
+            hooks: Hooks::default(),
+        };
+        match font {
+            Some(font) => system.font.copy_from_slice(font),
+            None => font::load_font(&mut system.font),
+        }
+        system.memory[font_range].copy_from_slice(&system.font);
+        match &self.rom {
+            Some(rom) => system.load_rom(rom, self.load_address)?,
+            None if rom_range.start >= self.memory_size => {
+                return Err(LoadError::InvalidAddress {
+                    address: self.load_address,
+                }
+                .into());
+            }
+            None => {}
+        }
+        system.set_engine(self.engine);
+        Ok(system)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_and_default_agree() {
+        for system in [Chip8::new(), Chip8::default()] {
+            assert_eq!(system.pc, 0x200);
+            assert_eq!(system.memory.len(), 0x1000);
+            assert_eq!(system.memory[..5], font::letters::LETTER_0);
+        }
+    }
+
+    #[test]
+    fn configures_the_machine() {
+        let font: Vec<u8> = (0..font::FONT_SIZE as u8).collect();
+        let mut system = Chip8Builder::new()
+            .platform(QuirkPresets::XoChip)
+            .font(&font)
+            .font_address(0x50)
+            .load_address(0x300)
+            .rom(&[0xF1, 0x29, 0x23, 0x04, 0x23, 0x04])
+            .stack_depth(1)
+            .seed(7)
+            .build()
+            .unwrap();
+        assert_eq!(system.memory.len(), MAX_MEMORY_SIZE);
+        assert!(system.quirks.partial_wrap);
+        assert_eq!(system.memory[0x50..0xA0], font);
+        assert_eq!(system.rng, StdRng::seed_from_u64(7));
+
+        system.registers[1] = 2;
+        system.step().unwrap();
+        assert_eq!(system.pointer, 0x50 + 10);
+        system.step().unwrap();
+        assert!(system.step().is_err());
+        assert!(!system.running);
+    }
+
+    #[test]
+    fn rejects_invalid_combinations() {
+        let error = |builder: Chip8Builder| builder.build().err().unwrap();
+        assert_eq!(
+            error(Chip8Builder::new().memory_size(0x200)),
+            BuildError::MemorySize { size: 0x200 }
+        );
+        assert_eq!(
+            error(Chip8Builder::new().memory_size(0x10001)),
+            BuildError::MemorySize { size: 0x10001 }
+        );
+        assert_eq!(
+            error(Chip8Builder::new().font(&[0xF0; 5])),
+            BuildError::FontSize { size: 5 }
+        );
+        assert_eq!(
+            error(Chip8Builder::new().font_address(0xFC0)),
+            BuildError::FontOutsideMemory {
+                address: 0xFC0,
+                memory_size: 0x1000
+            }
+        );
+        assert_eq!(
+            error(Chip8Builder::new().font_address(0x1F0).rom(&[0; 2])),
+            BuildError::FontOverlapsRom {
+                font: 0x1F0,
+                rom: 0x200
+            }
+        );
+        assert_eq!(
+            error(Chip8Builder::new().stack_depth(0)),
+            BuildError::EmptyStack
+        );
+        assert_eq!(
+            error(Chip8Builder::new().load_address(0x1000)),
+            BuildError::Load(LoadError::InvalidAddress { address: 0x1000 })
+        );
+        assert!(matches!(
+            error(Chip8Builder::new().rom(&[0; 0xE01])),
+            BuildError::Load(LoadError::TooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,344 @@
+//! PNG screenshots and animated GIF/APNG recordings of the display
+//!
+//! Frames are stored as palette indices rather than RGBA, and repeated frames are merged,
+//! so a long recording of a mostly static screen stays small.
+
+use std::borrow::Cow;
+
+use thiserror::Error;
+
+use crate::display::Display;
+use crate::render::Palette;
+
+/// Frames per second the display is captured at
+pub const FRAME_RATE: u32 = 60;
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error("Error encoding PNG: {0}")]
+    Png(#[from] png::EncodingError),
+    #[error("Error encoding GIF: {0}")]
+    Gif(#[from] gif::EncodingError),
+    #[error("There are no frames to encode")]
+    NoFrames,
+    #[error("The image is too large ({width}x{height})")]
+    TooLarge { width: usize, height: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordingFormat {
+    Gif,
+    Apng,
+}
+
+/// A single image, one palette index per pixel
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Frame {
+    indices: Vec<u8>,
+    /// How many 60 Hz frames this image was shown for
+    duration: u32,
+}
+
+/// Collects display frames and encodes them as an animation
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    pub palette: Palette,
+    pub scale: usize,
+    width: usize,
+    height: usize,
+    frames: Vec<Frame>,
+}
+
+impl Recorder {
+    pub fn new(palette: Palette, scale: usize) -> Self {
+        Self {
+            palette,
+            scale: scale.max(1),
+            width: 0,
+            height: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Number of 60 Hz frames recorded so far
+    pub fn frame_count(&self) -> u32 {
+        self.frames.iter().map(|frame| frame.duration).sum()
+    }
+
+    /// Records the display as it is shown for one 60 Hz frame.
+    ///
+    /// If the display size changes, the frames recorded so far are discarded.
+    pub fn capture(&mut self, display: &Display) {
+        let (width, height) = (display.get_width(), display.get_height());
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            self.frames.clear();
+        }
+
+        let indices = palette_indices(display);
+        match self.frames.last_mut() {
+            Some(last) if last.indices == indices => last.duration += 1,
+            _ => self.frames.push(Frame {
+                indices,
+                duration: 1,
+            }),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn encode(&self, format: RecordingFormat) -> Result<Vec<u8>, CaptureError> {
+        match format {
+            RecordingFormat::Gif => self.encode_gif(),
+            RecordingFormat::Apng => self.encode_apng(),
+        }
+    }
+
+    /// Encodes the recording as a looping GIF.
+    ///
+    /// GIF delays are in hundredths of a second, so the delays are rounded
+    /// against the running total to keep the animation in sync with 60 Hz.
+    /// Browsers slow down frames shorter than [`MIN_GIF_DELAY`], so a frame that would be
+    /// shown for less than that is dropped, and the next one is shown in its place.
+    /// Fast flicker can look uneven as a result. [`Recorder::encode_apng`] keeps every frame.
+    pub fn encode_gif(&self) -> Result<Vec<u8>, CaptureError> {
+        if self.frames.is_empty() {
+            return Err(CaptureError::NoFrames);
+        }
+        let (width, height) = self.scaled_size()?;
+
+        let mut output = Vec::new();
+        {
+            let mut encoder =
+                gif::Encoder::new(&mut output, width, height, &rgb_palette(&self.palette))?;
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+
+            let mut elapsed_frames = 0;
+            // when the frames written so far end, in hundredths of a second
+            let mut written = 0;
+            for (index, frame) in self.frames.iter().enumerate() {
+                elapsed_frames += frame.duration;
+                let end = centiseconds(elapsed_frames);
+                let is_last = index + 1 == self.frames.len();
+                if end - written < MIN_GIF_DELAY && !is_last {
+                    continue;
+                }
+                let delay = (end - written).max(MIN_GIF_DELAY);
+                written = end;
+
+                encoder.write_frame(&gif::Frame {
+                    width,
+                    height,
+                    delay: delay.min(u16::MAX as u32) as u16,
+                    buffer: Cow::Owned(self.scale_indices(&frame.indices)),
+                    ..Default::default()
+                })?;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Encodes the recording as a looping animated PNG with exact 60 Hz frame timing
+    pub fn encode_apng(&self) -> Result<Vec<u8>, CaptureError> {
+        if self.frames.is_empty() {
+            return Err(CaptureError::NoFrames);
+        }
+        let (width, height) = self.scaled_size()?;
+
+        let mut output = Vec::new();
+        {
+            let mut encoder = indexed_png_encoder(&mut output, width, height, &self.palette);
+            encoder.set_animated(self.frames.len() as u32, 0)?;
+            let mut writer = encoder.write_header()?;
+            for frame in &self.frames {
+                let duration = frame.duration.min(u16::MAX as u32) as u16;
+                writer.set_frame_delay(duration, FRAME_RATE as u16)?;
+                writer.write_image_data(&self.scale_indices(&frame.indices))?;
+            }
+            writer.finish()?;
+        }
+        Ok(output)
+    }
+
+    fn scaled_size(&self) -> Result<(u16, u16), CaptureError> {
+        let (width, height) = (self.width * self.scale, self.height * self.scale);
+        match (u16::try_from(width), u16::try_from(height)) {
+            (Ok(scaled_width), Ok(scaled_height)) => Ok((scaled_width, scaled_height)),
+            _ => Err(CaptureError::TooLarge { width, height }),
+        }
+    }
+
+    fn scale_indices(&self, indices: &[u8]) -> Vec<u8> {
+        scale_indices(indices, self.width, self.scale)
+    }
+}
+
+/// Encodes the current display as a PNG
+pub fn screenshot_png(
+    display: &Display,
+    palette: &Palette,
+    scale: usize,
+) -> Result<Vec<u8>, CaptureError> {
+    let scale = scale.max(1);
+    let (width, height) = (display.get_width() * scale, display.get_height() * scale);
+    let (Ok(png_width), Ok(png_height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(CaptureError::TooLarge { width, height });
+    };
+
+    let indices = scale_indices(&palette_indices(display), display.get_width(), scale);
+    let mut output = Vec::new();
+    {
+        let encoder = indexed_png_encoder(&mut output, png_width, png_height, palette);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&indices)?;
+        writer.finish()?;
+    }
+    Ok(output)
+}
+
+fn palette_indices(display: &Display) -> Vec<u8> {
+    display.pixel_iter().map(|pixel| pixel as u8).collect()
+}
+
+/// Scales an image of palette indices up so each pixel becomes a `scale` by `scale` square
+fn scale_indices(indices: &[u8], width: usize, scale: usize) -> Vec<u8> {
+    if scale == 1 {
+        return indices.to_vec();
+    }
+    let mut scaled = Vec::with_capacity(indices.len() * scale * scale);
+    for row in indices.chunks(width) {
+        let start = scaled.len();
+        for &index in row {
+            scaled.extend(std::iter::repeat_n(index, scale));
+        }
+        let row_length = scaled.len() - start;
+        for _ in 1..scale {
+            scaled.extend_from_within(start..start + row_length);
+        }
+    }
+    scaled
+}
+
+fn rgb_palette(palette: &Palette) -> Vec<u8> {
+    palette
+        .colors
+        .iter()
+        .flat_map(|color| color[..3].iter().copied())
+        .collect()
+}
+
+fn indexed_png_encoder<'a>(
+    output: &'a mut Vec<u8>,
+    width: u16,
+    height: u16,
+    palette: &Palette,
+) -> png::Encoder<'a, &'a mut Vec<u8>> {
+    let mut encoder = png::Encoder::new(output, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(rgb_palette(palette));
+    encoder
+}
+
+/// The shortest GIF delay in hundredths of a second, browsers show shorter frames for a tenth of a second
+pub const MIN_GIF_DELAY: u32 = 2;
+
+/// Time at the start of a 60 Hz frame, rounded to hundredths of a second
+fn centiseconds(frames: u32) -> u32 {
+    (frames * 100 + FRAME_RATE / 2) / FRAME_RATE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screenshot_is_png() {
+        let mut display = Display::default();
+        display.draw_sprite(0, 0, 1, &[0xFF], false);
+        let png = screenshot_png(&display, &Palette::default(), 2).unwrap();
+
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().width, 128);
+        assert_eq!(reader.info().height, 64);
+    }
+
+    #[test]
+    fn merges_repeated_frames() {
+        let mut display = Display::default();
+        let mut recorder = Recorder::new(Palette::default(), 1);
+        recorder.capture(&display);
+        recorder.capture(&display);
+        display.draw_sprite(0, 0, 1, &[0xFF], false);
+        recorder.capture(&display);
+
+        assert_eq!(recorder.frames.len(), 2);
+        assert_eq!(recorder.frame_count(), 3);
+    }
+
+    #[test]
+    fn gif_timing_adds_up() {
+        // 60 single frames should take exactly one second
+        let total: u32 = (0..60)
+            .map(|frame| centiseconds(frame + 1) - centiseconds(frame))
+            .sum();
+        assert_eq!(total, 100);
+
+        let mut display = Display::default();
+        let mut recorder = Recorder::new(Palette::default(), 1);
+        for _ in 0..3 {
+            recorder.capture(&display);
+            display.draw_sprite(0, 0, 1, &[0xFF], false);
+        }
+        let gif = recorder.encode(RecordingFormat::Gif).unwrap();
+        assert_eq!(&gif[..6], b"GIF89a");
+    }
+
+    #[test]
+    fn gif_delays_are_never_clamped() {
+        // a sprite flickering every frame for a second
+        let mut display = Display::default();
+        let mut recorder = Recorder::new(Palette::default(), 1);
+        for _ in 0..60 {
+            display.draw_sprite(0, 0, 1, &[0xFF], false);
+            recorder.capture(&display);
+        }
+        let gif = recorder.encode(RecordingFormat::Gif).unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(gif.as_slice()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay as u32);
+        }
+        assert!(delays.iter().all(|&delay| delay >= MIN_GIF_DELAY));
+        assert_eq!(delays.iter().sum::<u32>(), 100);
+    }
+
+    #[test]
+    fn apng_is_animated() {
+        let mut display = Display::default();
+        let mut recorder = Recorder::new(Palette::default(), 1);
+        recorder.capture(&display);
+        display.draw_sprite(0, 0, 1, &[0xFF], false);
+        recorder.capture(&display);
+
+        let apng = recorder.encode(RecordingFormat::Apng).unwrap();
+        let reader = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 2);
+    }
+
+    #[test]
+    fn empty_recording() {
+        let recorder = Recorder::new(Palette::default(), 1);
+        assert!(matches!(
+            recorder.encode(RecordingFormat::Apng),
+            Err(CaptureError::NoFrames)
+        ));
+    }
+}
@@ -0,0 +1,300 @@
+//! Code coverage tracking for ROMs
+//!
+//! Coverage is keyed by the address an instruction was fetched from. Skip instructions
+//! additionally track how often the skip was taken, so test ROMs can check that both
+//! sides of each branch were exercised.
+
+use alloc::collections::BTreeMap;
+use core::fmt::Write;
+
+use crate::prelude::*;
+
+use crate::instruction::Instruction;
+
+/// Coverage counters for a single address
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddressCoverage {
+    /// How many times an instruction was executed from this address
+    pub executions: u64,
+    /// How many times the instruction at this address skipped the next instruction
+    pub skip_taken: u64,
+    /// How many times the instruction at this address didn't skip the next instruction
+    pub skip_not_taken: u64,
+}
+
+/// A location in an assembler source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+}
+
+/// Maps instruction addresses back to the source lines they were assembled from
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    locations: BTreeMap<u16, SourceLocation>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, address: u16, file: impl Into<String>, line: usize) {
+        self.locations.insert(
+            address,
+            SourceLocation {
+                file: file.into(),
+                line,
+            },
+        );
+    }
+
+    pub fn get(&self, address: u16) -> Option<&SourceLocation> {
+        self.locations.get(&address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &SourceLocation)> {
+        self.locations
+            .iter()
+            .map(|(&address, location)| (address, location))
+    }
+
+    /// Parses a source map in the text format `<hex address> <file>:<line>`, one entry per line.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    /// Returns the line number (starting at 1) of the first malformed entry on failure.
+    pub fn parse(text: &str) -> Result<Self, usize> {
+        let mut map = Self::new();
+        for (index, entry) in text.lines().enumerate() {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let parsed = entry
+                .split_once(char::is_whitespace)
+                .and_then(|(address, location)| {
+                    let address = address.trim_start_matches("0x");
+                    let address = u16::from_str_radix(address, 16).ok()?;
+                    let (file, line) = location.trim().rsplit_once(':')?;
+                    Some((address, file.to_owned(), line.parse().ok()?))
+                });
+
+            match parsed {
+                Some((address, file, line)) => map.insert(address, file, line),
+                None => return Err(index + 1),
+            }
+        }
+        Ok(map)
+    }
+}
+
+impl core::fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (address, location) in self.iter() {
+            writeln!(f, "{:#05X} {}:{}", address, location.file, location.line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Line number -> (hits, skip taken and not taken counts if the line is a skip)
+type LineCounts = BTreeMap<usize, (u64, Option<(u64, u64)>)>;
+
+/// Execution counts for every address that has run since coverage was enabled
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    addresses: BTreeMap<u16, AddressCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an executed instruction
+    /// # Arguments
+    /// * `address` - The address the instruction was fetched from
+    /// * `instruction` - The decoded instruction
+    /// * `pc_after` - The program counter after the instruction was executed
+    pub fn record(&mut self, address: u16, instruction: Instruction, pc_after: u16) {
+        let entry = self.addresses.entry(address).or_default();
+        entry.executions += 1;
+        if instruction.is_skip() {
+            // the pc has already moved past the skip instruction itself,
+            // so a taken skip lands one more instruction further
+            if pc_after == address.wrapping_add(4) {
+                entry.skip_taken += 1;
+            } else {
+                entry.skip_not_taken += 1;
+            }
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<&AddressCoverage> {
+        self.addresses.get(&address)
+    }
+
+    pub fn is_executed(&self, address: u16) -> bool {
+        self.addresses.contains_key(&address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &AddressCoverage)> {
+        self.addresses
+            .iter()
+            .map(|(&address, coverage)| (address, coverage))
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses.clear();
+    }
+
+    /// Disassembles `memory[start..end]`, marking each instruction as executed (`[x]`) or not (`[ ]`)
+    /// along with its execution count and, for skips, how often the skip was taken.
+    pub fn annotated_disassembly(&self, memory: &[u8], start: u16, end: usize) -> String {
+        let end = end.min(memory.len());
+        let mut output = String::new();
+        for address in (start as usize..end.saturating_sub(1)).step_by(2) {
+            let opcode = u16::from_be_bytes([memory[address], memory[address + 1]]);
+            let instruction = Instruction::from(opcode);
+            let coverage = self.get(address as u16).copied().unwrap_or_default();
+            let marker = if coverage.executions > 0 { 'x' } else { ' ' };
+
+            let _ = write!(
+                output,
+                "{:#05X}  {:04X}  [{}] {:>6}  {}",
+                address, opcode, marker, coverage.executions, instruction
+            );
+            if instruction.is_skip() {
+                let _ = write!(
+                    output,
+                    "  (skip taken: {}, not taken: {})",
+                    coverage.skip_taken, coverage.skip_not_taken
+                );
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Generates an lcov tracefile, using `source_map` to translate addresses into source lines.
+    ///
+    /// Every address in the source map counts as an instrumented line.
+    /// Skip instructions (decoded from `memory`) are reported as branches with two outcomes: taken and not taken.
+    pub fn lcov(&self, memory: &[u8], source_map: &SourceMap, test_name: &str) -> String {
+        let mut files: BTreeMap<&str, LineCounts> = BTreeMap::new();
+        for (address, location) in source_map.iter() {
+            let coverage = self.get(address).copied().unwrap_or_default();
+            let line = files
+                .entry(location.file.as_str())
+                .or_default()
+                .entry(location.line)
+                .or_insert((0, None));
+            line.0 += coverage.executions;
+            let is_skip = memory
+                .get(address as usize..address as usize + 2)
+                .map(|bytes| Instruction::from(u16::from_be_bytes([bytes[0], bytes[1]])).is_skip())
+                .unwrap_or(false);
+            if is_skip {
+                let branches = line.1.get_or_insert((0, 0));
+                branches.0 += coverage.skip_taken;
+                branches.1 += coverage.skip_not_taken;
+            }
+        }
+
+        let mut output = String::new();
+        for (file, lines) in files {
+            let _ = writeln!(output, "TN:{test_name}");
+            let _ = writeln!(output, "SF:{file}");
+            let (mut branches_found, mut branches_hit) = (0, 0);
+            for (&line, &(_, branches)) in &lines {
+                if let Some((taken, not_taken)) = branches {
+                    for (branch, count) in [taken, not_taken].into_iter().enumerate() {
+                        let _ = writeln!(output, "BRDA:{line},0,{branch},{count}");
+                        branches_found += 1;
+                        if count > 0 {
+                            branches_hit += 1;
+                        }
+                    }
+                }
+            }
+            let _ = writeln!(output, "BRF:{branches_found}");
+            let _ = writeln!(output, "BRH:{branches_hit}");
+            for (line, (hits, _)) in &lines {
+                let _ = writeln!(output, "DA:{line},{hits}");
+            }
+            let _ = writeln!(output, "LF:{}", lines.len());
+            let _ = writeln!(
+                output,
+                "LH:{}",
+                lines.values().filter(|(hits, _)| *hits > 0).count()
+            );
+            output.push_str("end_of_record\n");
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_skips() {
+        let mut coverage = Coverage::new();
+        let skip = Instruction::from(0x3A02);
+
+        coverage.record(0x200, skip, 0x204);
+        coverage.record(0x200, skip, 0x202);
+        coverage.record(0x200, skip, 0x204);
+        coverage.record(0x202, Instruction::from(0x6A02), 0x204);
+
+        let entry = coverage.get(0x200).unwrap();
+        assert_eq!(entry.executions, 3);
+        assert_eq!(entry.skip_taken, 2);
+        assert_eq!(entry.skip_not_taken, 1);
+
+        let entry = coverage.get(0x202).unwrap();
+        assert_eq!(entry.skip_taken + entry.skip_not_taken, 0);
+        assert!(!coverage.is_executed(0x204));
+    }
+
+    #[test]
+    fn annotated_disassembly_markers() {
+        let mut memory = [0u8; 0x206];
+        memory[0x200..0x206].copy_from_slice(&[0x3A, 0x02, 0x6A, 0x02, 0x12, 0x00]);
+        let mut coverage = Coverage::new();
+        coverage.record(0x200, Instruction::from(0x3A02), 0x204);
+
+        let listing = coverage.annotated_disassembly(&memory, 0x200, 0x206);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("[x]"));
+        assert!(lines[0].contains("skip taken: 1, not taken: 0"));
+        assert!(lines[1].contains("[ ]"));
+    }
+
+    #[test]
+    fn lcov_report() {
+        let source_map = SourceMap::parse("0x200 test.8o:1\n0x202 test.8o:2\n# comment\n").unwrap();
+        let mut memory = [0u8; 0x204];
+        memory[0x200..0x204].copy_from_slice(&[0x3A, 0x02, 0x6A, 0x02]);
+        let mut coverage = Coverage::new();
+        coverage.record(0x200, Instruction::from(0x3A02), 0x202);
+
+        let report = coverage.lcov(&memory, &source_map, "skip");
+        assert!(report.contains("SF:test.8o"));
+        assert!(report.contains("DA:1,1"));
+        assert!(report.contains("DA:2,0"));
+        assert!(report.contains("BRDA:1,0,0,0"));
+        assert!(report.contains("BRDA:1,0,1,1"));
+        assert!(report.contains("LH:1"));
+        assert!(report.ends_with("end_of_record\n"));
+    }
+
+    #[test]
+    fn parse_bad_source_map() {
+        assert_eq!(SourceMap::parse("0x200 test.8o:1\nnonsense"), Err(2));
+    }
+}
@@ -0,0 +1,165 @@
+//! The line based debugger, which reads commands and key presses from stdin
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use num_traits::FromPrimitive;
+
+use crate::frontend::{AudioSink, Input, InputSource, RunStatus, Runner, Sound, VideoSink};
+use crate::instruction::Instruction;
+use crate::keypad::Key;
+use crate::{Chip8, DecodingError};
+
+/// Prints the display and the next instruction after every frame
+struct Console;
+
+impl VideoSink for Console {
+    fn present(&mut self, system: &Chip8, _: &RunStatus) -> io::Result<()> {
+        println!();
+        println!("{}", system.display);
+        println!(
+            "Instruction: {:X?}",
+            Instruction::from(system.get_u16(system.pc as usize))
+        );
+        Ok(())
+    }
+}
+
+/// The debugger doesn't play the tone
+struct Silent;
+
+impl AudioSink for Silent {
+    fn update(&mut self, _: &Chip8, _: Sound) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Key presses and resets entered at the prompt, handled at the start of the next frame
+#[derive(Clone, Default)]
+struct Prompt(Rc<RefCell<VecDeque<Input>>>);
+
+impl Prompt {
+    fn push(&self, input: Input) {
+        self.0.borrow_mut().push_back(input);
+    }
+}
+
+impl InputSource for Prompt {
+    fn poll(&mut self) -> io::Result<Option<Input>> {
+        Ok(self.0.borrow_mut().pop_front())
+    }
+}
+
+impl Chip8 {
+    /// Runs until the VM halts, printing the display after every instruction
+    /// and reading debugger commands from stdin
+    ///
+    /// Every instruction runs as its own frame, so the timers count down once per instruction.
+    pub fn run(&mut self) -> Result<(), DecodingError> {
+        let error = Rc::new(RefCell::new(None));
+        let hook = self.on_error({
+            let error = error.clone();
+            move |err| *error.borrow_mut() = Some(err.clone())
+        });
+        let prompt = Prompt::default();
+        let mut runner = Runner::new(Console, Silent, prompt.clone(), 1);
+        let mut skip_debug = false;
+        while self.running {
+            runner.step_frame(self).expect("Error writing to stdout");
+            if error.borrow().is_some() {
+                break;
+            }
+
+            if self.is_key_waiting() {
+                print!("Enter key: ");
+                loop {
+                    let mut key = String::new();
+                    std::io::stdin().read_line(&mut key).unwrap();
+                    if let Some(key) = u8::from_str_radix(key.trim(), 16)
+                        .ok()
+                        .and_then(Key::from_u8)
+                    {
+                        // a full press, so the wait finishes whether it's on press or release
+                        prompt.push(Input::Press(key));
+                        prompt.push(Input::Release(key));
+                        break;
+                    }
+                    print!("Error parsing, is this a single hex character?\nTry again: ");
+                }
+            }
+
+            let mut user_input = String::new();
+            loop {
+                if skip_debug {
+                    break;
+                }
+                std::io::stdin()
+                    .read_line(&mut user_input)
+                    .expect("Error reading from stdin");
+
+                if user_input.starts_with("instruction") {
+                    let suffix = user_input.split_once("instruction").unwrap().1.trim();
+                    let mut address = self.pc;
+                    if suffix.is_empty() {
+                        // print current pc instruction
+                        address = self.pc;
+                    } else if suffix.starts_with('-') {
+                        let offset =
+                            u16::from_str_radix(suffix.split_once('-').unwrap().1, 16).unwrap();
+                        address -= 2 * offset;
+                    } else if suffix.starts_with('+') {
+                        let offset =
+                            u16::from_str_radix(suffix.split_once('-').unwrap().1, 16).unwrap();
+                        address += 2 * offset;
+                    }
+                    user_input = format!("memory {:#x}", address);
+                }
+
+                if user_input.starts_with("registers") {
+                    println!("Registers: \n{}", self.registers_to_string())
+                } else if user_input.starts_with("pc") {
+                    println!("Program counter: {:#6X}", self.pc);
+                } else if user_input.starts_with("memory 0x") {
+                    let hex_str = user_input.split("0x").nth(1).unwrap().trim();
+                    let address = usize::from_str_radix(hex_str, 16).unwrap();
+                    let mem = &self.memory[address..address + 2];
+
+                    println!(
+                        "Memory at address: {:#X}: {:#X}",
+                        address,
+                        u16::from_be_bytes([mem[0], mem[1]])
+                    );
+                } else if user_input.starts_with("set 0x") {
+                    let raw_input = user_input.split_once("0x").unwrap().1;
+                    let (address_str, val_str) = raw_input.split_once("0x").unwrap();
+                    let address =
+                        usize::from_str_radix(address_str.trim(), 16).expect("Invalid usize hex");
+                    let val = u8::from_str_radix(val_str.trim(), 16).expect("Invalid u8 hex");
+                    self.write_memory(address as u16, &[val])
+                        .expect("Address outside of memory");
+                    println!("Set address {:X} to {:X}", address, val);
+                } else if user_input.starts_with("skip") {
+                    skip_debug = true;
+                    println!("Skipping debug until halt");
+                    break;
+                } else if user_input.starts_with("pointer") {
+                    println!("Pointer: {:X}", self.pointer);
+                } else if user_input.starts_with("reset") {
+                    prompt.push(Input::Reset);
+                    println!("Restarting the ROM");
+                } else {
+                    break;
+                }
+                user_input.clear();
+            }
+        }
+        self.remove_hook(hook);
+        if let Some(error) = error.take() {
+            return Err(error);
+        }
+        println!("Halted");
+        Ok(())
+    }
+}
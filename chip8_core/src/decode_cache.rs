@@ -0,0 +1,170 @@
+//! Instructions decoded ahead of time, so hot loops don't decode the same opcodes every cycle
+//!
+//! Enable it with [`Chip8::enable_decode_cache`](crate::Chip8::enable_decode_cache).
+//! Entries are invalidated when instructions write to memory and when ROMs are loaded,
+//! but writes straight to [`Chip8::memory`](crate::Chip8::memory) have to go through
+//! [`Chip8::write_memory`](crate::Chip8::write_memory) to be noticed.
+
+use core::ops::Range;
+
+use crate::instruction::Instruction;
+use crate::prelude::*;
+
+/// The decoded instruction starting at each address, if it has been executed
+#[derive(Clone, Debug)]
+pub struct DecodeCache {
+    entries: Vec<Option<Instruction>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl DecodeCache {
+    /// An empty cache for `size` bytes of memory
+    pub fn new(size: usize) -> Self {
+        Self {
+            entries: vec![None; size],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Gets the instruction at `address`, decoding it from `memory` if it isn't cached
+    pub fn get(&mut self, memory: &[u8], address: u16) -> Instruction {
+        let index = address as usize;
+        if let Some(instruction) = self.entries[index] {
+            self.hits += 1;
+            return instruction;
+        }
+        self.misses += 1;
+        let instruction = u16::from_be_bytes([memory[index], memory[index + 1]]).into();
+        self.entries[index] = Some(instruction);
+        instruction
+    }
+
+    /// Forgets the instructions that overlap the bytes in `range`
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        // the instruction starting on the byte before the range includes its first byte
+        let start = range.start.saturating_sub(1);
+        let end = range.end.min(self.entries.len());
+        if start < end {
+            self.entries[start..end].fill(None);
+        }
+    }
+
+    /// Forgets every instruction
+    pub fn clear(&mut self) {
+        self.entries.fill(None);
+    }
+
+    /// How many lookups were answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// How many lookups had to decode the instruction
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::QuirkPresets;
+    use crate::Chip8;
+
+    #[test]
+    fn invalidates_overlapping_entries() {
+        let mut memory = [0u8; 8];
+        memory[..6].copy_from_slice(&[0x60, 0x01, 0x61, 0x02, 0x62, 0x03]);
+        let mut cache = DecodeCache::new(memory.len());
+        for address in [0, 2, 4] {
+            cache.get(&memory, address);
+        }
+        // a write to byte 3 changes the instruction at 2, but not the ones at 0 and 4
+        cache.invalidate(3..4);
+        memory[3] = 0x05;
+        assert_eq!(
+            cache.get(&memory, 2),
+            Instruction::SetRegister {
+                register: 1,
+                value: 5
+            }
+        );
+        cache.get(&memory, 0);
+        cache.get(&memory, 4);
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+    }
+
+    /// Runs the same program with and without the cache, comparing the whole VM after every instruction
+    fn assert_same_as_interpreter(rom: &[u8], steps: usize) -> Chip8 {
+        let mut cached = Chip8::new();
+        let mut plain = Chip8::new();
+        for system in [&mut cached, &mut plain] {
+            system.quirks.use_preset(QuirkPresets::Chip8);
+            system.seed_rng(0);
+            system.load_rom(rom, 0x200).unwrap();
+        }
+        cached.enable_decode_cache();
+        for step in 0..steps {
+            let instruction = cached.step().unwrap();
+            assert_eq!(instruction, plain.step().unwrap(), "step {step}");
+            assert_eq!(cached.pc, plain.pc, "step {step}");
+            assert_eq!(cached.registers, plain.registers, "step {step}");
+            assert_eq!(cached.pointer, plain.pointer, "step {step}");
+            assert_eq!(cached.memory, plain.memory, "step {step}");
+            assert_eq!(cached.display, plain.display, "step {step}");
+        }
+        cached
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        #[rustfmt::skip]
+        let rom = [
+            0x60, 0x71, // 200: V0 = 0x71
+            0x61, 0x05, // 202: V1 = 5
+            0x22, 0x10, // 204: call 0x210
+            0xA2, 0x10, // 206: I = 0x210
+            0xF1, 0x55, // 208: store V0 and V1 at 0x210, turning the add into V1 += 5
+            0x22, 0x10, // 20A: call 0x210 again
+            0x12, 0x0C, // 20C: loop forever
+            0x00, 0x00,
+            0x72, 0x01, // 210: V2 += 1
+            0x00, 0xEE, // 212: return
+        ];
+        let system = assert_same_as_interpreter(&rom, 14);
+        assert_eq!(system.registers[1..3], [10, 1]);
+        assert!(system.decode_cache.as_ref().unwrap().hits() > 0);
+    }
+
+    #[test]
+    fn bcd_over_code() {
+        #[rustfmt::skip]
+        let rom = [
+            0xA2, 0x08, // 200: I = 0x208
+            0x22, 0x08, // 202: call 0x208
+            0xF0, 0x33, // 204: BCD of V0 = 0 into 0x208..0x20B, turning the return into a halt
+            0x12, 0x08, // 206: jump to 0x208
+            0x00, 0xEE, // 208: return
+        ];
+        let system = assert_same_as_interpreter(&rom, 6);
+        assert!(!system.running);
+    }
+
+    #[test]
+    fn external_writes() {
+        let mut system = Chip8::new();
+        system.enable_decode_cache();
+        system.load_rom(&[0x60, 0x01, 0x12, 0x00], 0x200).unwrap();
+        system.step().unwrap();
+        system.step().unwrap();
+        system.write_memory(0x201, &[0x09]).unwrap();
+        system.step().unwrap();
+        assert_eq!(system.registers[0], 9);
+
+        system.load_rom(&[0x60, 0x04, 0x12, 0x00], 0x200).unwrap();
+        system.step().unwrap();
+        assert_eq!(system.registers[0], 4);
+    }
+}
@@ -1,68 +1,198 @@
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::wasm_bindgen;
 
-// We duplicate display so that we can have wasm_bindgen(skip) on pixels
-// Without this, we need https://github.com/rust-lang/rust/issues/82679 (cfg_eval macro) to be stabilized
+use crate::prelude::*;
 
-#[cfg(feature = "wasm")]
-#[wasm_bindgen]
-pub struct Display {
-    #[wasm_bindgen(skip)]
-    pub pixels: Vec<bool>,
-    width: usize,
-    height: usize,
+/// The widest display a row can hold, one bit per pixel
+pub const MAX_WIDTH: usize = 128;
+
+/// A rectangle of pixels that changed since the dirty state was last reset
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyRegion {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
 }
 
-#[cfg(not(feature = "wasm"))]
+/// The framebuffer, stored as one bit-packed word per row.
+///
+/// The leftmost pixel of a row is the most significant bit of its word,
+/// so a sprite row can be shifted into place and XORed onto the screen in one operation.
+///
+/// The display also tracks which rows and columns changed, so frontends only need to redraw those.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Display {
-    pub pixels: Vec<bool>,
+    rows: Vec<u128>,
     width: usize,
     height: usize,
+    /// Incremented every time the contents of the display change
+    frame: u32,
+    /// Rows changed since the last reset
+    dirty_rows: Vec<bool>,
+    /// Every column changed since the last reset, in the same layout as a row
+    dirty_columns: u128,
 }
 
 #[cfg(feature = "wasm")]
 #[wasm_bindgen]
 impl Display {
+    /// One byte per pixel, 1 for on and 0 for off
+    #[wasm_bindgen(getter = pixels)]
+    pub fn pixel_bytes(&self) -> Vec<u8> {
+        self.pixel_iter().map(|val| val as u8).collect()
+    }
+
+    /// The packed framebuffer, see [`Display::to_packed_bytes`]
     #[wasm_bindgen(getter)]
-    pub fn pixels(&self) -> Vec<u8> {
-        self.pixels.iter().map(|&val| val as u8).collect()
+    pub fn packed_pixels(&self) -> Vec<u8> {
+        self.to_packed_bytes()
     }
 }
 
 impl Display {
-    // Could definitely use a more efficient bitpacking algorithm here.
-    // Make collision detection easy
-    // Just AND slice of pixels and slice of sprite and test if greater than 1
-    // (all 1s are where old frame already on and new sprite is flipping)
-    // Then just XOR the two slices for the new values that will invert any bits where the sprite is one
-    // We can use bitvec, with bitfields
     pub fn new(width: usize, height: usize) -> Self {
         debug_assert_eq!(width % 8, 0, "Width must be a multiple of 8");
+        debug_assert!(width <= MAX_WIDTH, "Width must be at most {MAX_WIDTH}");
         Display {
-            pixels: vec![false; width * height],
+            rows: vec![0; height],
             width,
             height,
+            frame: 0,
+            dirty_rows: vec![false; height],
+            dirty_columns: 0,
+        }
+    }
+
+    /// Records that the pixels in `columns` of row `y` changed
+    fn mark_dirty(&mut self, y: usize, columns: u128) {
+        if columns == 0 {
+            return;
+        }
+        self.dirty_rows[y] = true;
+        self.dirty_columns |= columns;
+    }
+
+    /// Bumps the frame counter if anything was drawn since the last call
+    fn finish_change(&mut self, changed: bool) {
+        if changed {
+            self.frame = self.frame.wrapping_add(1);
+        }
+    }
+
+    /// A counter that increases every time the display contents change.
+    /// Frontends can compare this against the last value they rendered to skip unchanged frames.
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Whether anything changed since the dirty state was last reset
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_columns != 0
+    }
+
+    /// Indices of the rows that changed since the dirty state was last reset
+    pub fn dirty_rows(&self) -> impl Iterator<Item = usize> + '_ {
+        self.dirty_rows
+            .iter()
+            .enumerate()
+            .filter_map(|(y, &dirty)| dirty.then_some(y))
+    }
+
+    /// The smallest rectangle containing every pixel that changed since the dirty state was last reset
+    pub fn dirty_region(&self) -> Option<DirtyRegion> {
+        if !self.is_dirty() {
+            return None;
         }
+        let first_row = self.dirty_rows().next()?;
+        let last_row = self.dirty_rows().last()?;
+        let first_column = self.dirty_columns.leading_zeros() as usize;
+        let last_column = MAX_WIDTH - 1 - self.dirty_columns.trailing_zeros() as usize;
+        Some(DirtyRegion {
+            x: first_column,
+            y: first_row,
+            width: last_column - first_column + 1,
+            height: last_row - first_row + 1,
+        })
     }
 
-    fn get_offset(&self, x: usize, y: usize) -> usize {
-        (y * self.width) + x
+    /// Marks the whole display as clean, call this after the frontend has redrawn the dirty region
+    pub fn reset_dirty(&mut self) {
+        self.dirty_rows.iter_mut().for_each(|dirty| *dirty = false);
+        self.dirty_columns = 0;
     }
 
-    /// Flips pixel at (x, y) to it's opposite state
+    /// Mask with a bit set for every column that is on screen
+    fn width_mask(&self) -> u128 {
+        !0 << (MAX_WIDTH - self.width)
+    }
+
+    /// Places a sprite row so its leftmost pixel lands on column `x`
     /// # Arguments
-    /// * `x` - The x position of the pixel to flip
-    /// * `y` - The y position of the pixel to flip
+    /// * `sprite_row` - 8 pixels of sprite data, leftmost pixel in the most significant bit
+    /// * `x` - The column of the leftmost pixel, must be less than the display width
+    /// * `wrap_sprite` - Whether pixels past the right edge wrap around to the left edge
     /// # Returns
-    /// Returns the new value of the pixel
-    fn flip_pixel(&mut self, x: usize, y: usize) -> bool {
-        let offset = self.get_offset(x, y);
-        // log::info!(
-        //     "Offset: {offset} = x({x}) + (y({y}) * width({})",
-        //     self.width
-        // );
-        self.pixels[offset] = !self.pixels[offset];
-        self.pixels[offset]
+    /// Returns the sprite row as a mask in the same layout as a display row
+    fn sprite_mask(&self, sprite_row: u8, x: usize, wrap_sprite: bool) -> u128 {
+        let sprite_row = sprite_row as u128;
+        let placed = if x + 8 <= MAX_WIDTH {
+            sprite_row << (MAX_WIDTH - 8 - x)
+        } else {
+            sprite_row >> (x + 8 - MAX_WIDTH)
+        };
+        let mut mask = placed & self.width_mask();
+
+        if wrap_sprite && x + 8 > self.width {
+            // the lowest bits of the sprite row are the pixels that went past the edge
+            let overflow = x + 8 - self.width;
+            let wrapped = sprite_row & ((1 << overflow) - 1);
+            mask |= wrapped << (MAX_WIDTH - overflow);
+        }
+        mask
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.rows[y] & (1 << (MAX_WIDTH - 1 - x)) != 0
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, value: bool) {
+        let bit = 1 << (MAX_WIDTH - 1 - x);
+        let old_row = self.rows[y];
+        if value {
+            self.rows[y] |= bit;
+        } else {
+            self.rows[y] &= !bit;
+        }
+        self.mark_dirty(y, old_row ^ self.rows[y]);
+        self.finish_change(old_row != self.rows[y]);
+    }
+
+    /// The packed rows of the display. Pixel `x` of a row is bit `127 - x`.
+    pub fn rows(&self) -> &[u128] {
+        &self.rows
+    }
+
+    /// Iterates over every pixel, row by row
+    pub fn pixel_iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.height).flat_map(move |y| (0..self.width).map(move |x| self.get_pixel(x, y)))
+    }
+
+    /// One bool per pixel, row by row
+    pub fn pixels(&self) -> Vec<bool> {
+        self.pixel_iter().collect()
+    }
+
+    /// The framebuffer with 8 pixels per byte, row by row.
+    /// Each row is `width / 8` bytes, with the leftmost pixel in the most significant bit.
+    pub fn to_packed_bytes(&self) -> Vec<u8> {
+        let row_bytes = self.width / 8;
+        self.rows
+            .iter()
+            .flat_map(|row| row.to_be_bytes().into_iter().take(row_bytes))
+            .collect()
     }
 
     /// Draws a sprite from memory to the screen
@@ -70,7 +200,7 @@ impl Display {
     /// * `pos_x` - The position of the sprite on the x-axis. Wraps if greater than self.width
     /// * `pos_y` - The position of the sprite on the y-axis. Wraps if greater than self.height
     /// * `sprite_height` - The height of the sprite 1-16. Certain modes can have 0 mean a 16x16 sprite, otherwise width is 8.
-    /// * `memory` - A slice of the memory containing the sprite data, should be
+    /// * `memory` - A slice of the memory containing the sprite data, one byte per row
     /// * `wrap_sprite` - Whether the sprite should wrap partially
     /// # Returns
     /// Returns true if a bit is flipped from on to off, false otherwise.
@@ -82,37 +212,30 @@ impl Display {
         memory: &[u8],
         wrap_sprite: bool,
     ) -> bool {
-        let sprite_height = sprite_height as usize;
-        let sprite_width: usize = 8;
         let pos_x = pos_x as usize % self.width;
         let pos_y = pos_y as usize % self.height;
         let mut collide_check = false;
+        let mut changed = false;
 
-        for (row_index, mut y) in (pos_y..(pos_y + sprite_height)).enumerate() {
-            let row: u8 = memory[row_index];
-            let mut mask: u8 = 0b10000000;
-            for mut x in pos_x..(pos_x + sprite_width) {
-                if (row & mask) != 0 {
-                    // modulo coordinates, so that it wraps around the screen
-                    if wrap_sprite {
-                        x %= self.width;
-                        y %= self.height;
-                    }
-
-                    if x < self.width && y < self.height {
-                        let result = self.flip_pixel(x, y);
-                        // if a bit is flipped from on to off, this function should return true
-                        if !result {
-                            collide_check = true;
-                        }
-                    } else {
-                        break;
-                    }
+        for (row_index, &sprite_row) in memory.iter().take(sprite_height as usize).enumerate() {
+            let mut y = pos_y + row_index;
+            if y >= self.height {
+                if wrap_sprite {
+                    y %= self.height;
+                } else {
+                    break;
                 }
-                mask >>= 1;
             }
+
+            let mask = self.sprite_mask(sprite_row, pos_x, wrap_sprite);
+            // any bit set in both is a pixel that the XOR will turn off
+            collide_check |= self.rows[y] & mask != 0;
+            self.rows[y] ^= mask;
+            self.mark_dirty(y, mask);
+            changed |= mask != 0;
         }
 
+        self.finish_change(changed);
         collide_check
     }
 
@@ -124,16 +247,21 @@ impl Display {
     }
 
     pub fn clear(&mut self) {
-        // set every pixel to false
-        self.pixels.iter_mut().for_each(|pixel| *pixel = false);
+        let mut changed = false;
+        for y in 0..self.height {
+            let old_row = core::mem::take(&mut self.rows[y]);
+            self.mark_dirty(y, old_row);
+            changed |= old_row != 0;
+        }
+        self.finish_change(changed);
     }
 }
 
-impl std::fmt::Display for Display {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for line in self.pixels.as_slice().chunks(self.width) {
-            for &pixel in line {
-                let symbol = if pixel { '█' } else { '░' };
+impl core::fmt::Display for Display {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let symbol = if self.get_pixel(x, y) { '█' } else { '░' };
                 write!(f, "{}", symbol)?;
             }
             writeln!(f)?;
@@ -147,3 +275,88 @@ impl Default for Display {
         Display::new(64, 32)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw_and_collide() {
+        let mut display = Display::default();
+        assert!(!display.draw_sprite(3, 2, 2, &[0b1100_0001, 0xFF], false));
+        assert!(display.get_pixel(3, 2));
+        assert!(display.get_pixel(4, 2));
+        assert!(!display.get_pixel(5, 2));
+        assert!(display.get_pixel(10, 2));
+        assert!(display.get_pixel(3, 3) && display.get_pixel(10, 3));
+
+        // drawing the same sprite again erases it and reports the collision
+        assert!(display.draw_sprite(3, 2, 2, &[0b1100_0001, 0xFF], false));
+        assert!(display.pixel_iter().all(|pixel| !pixel));
+    }
+
+    #[test]
+    fn clip_and_wrap() {
+        let mut clipped = Display::default();
+        clipped.draw_sprite(60, 31, 2, &[0xFF, 0xFF], false);
+        assert_eq!(clipped.pixels().iter().filter(|&&p| p).count(), 4);
+        assert!(clipped.get_pixel(63, 31));
+
+        let mut wrapped = Display::default();
+        wrapped.draw_sprite(60, 31, 2, &[0xFF, 0xFF], true);
+        assert_eq!(wrapped.pixels().iter().filter(|&&p| p).count(), 16);
+        assert!(wrapped.get_pixel(0, 31));
+        assert!(wrapped.get_pixel(3, 0));
+        assert!(!wrapped.get_pixel(4, 0));
+    }
+
+    #[test]
+    fn position_wraps() {
+        let mut display = Display::default();
+        display.draw_sprite(64 + 1, 32 + 1, 1, &[0x80], false);
+        assert!(display.get_pixel(1, 1));
+    }
+
+    #[test]
+    fn dirty_tracking() {
+        let mut display = Display::default();
+        assert_eq!(display.dirty_region(), None);
+
+        display.draw_sprite(10, 4, 2, &[0b1000_0001, 0b0100_0000], false);
+        display.draw_sprite(2, 20, 1, &[0b0010_0000], false);
+        assert_eq!(display.frame(), 2);
+        assert_eq!(display.dirty_rows().collect::<Vec<_>>(), vec![4, 5, 20]);
+        assert_eq!(
+            display.dirty_region(),
+            Some(DirtyRegion {
+                x: 4,
+                y: 4,
+                width: 14,
+                height: 17
+            })
+        );
+
+        display.reset_dirty();
+        assert!(!display.is_dirty());
+
+        // clearing marks only the rows that had pixels on as dirty, and advances the frame
+        display.clear();
+        assert_eq!(display.frame(), 3);
+        assert_eq!(display.dirty_rows().collect::<Vec<_>>(), vec![4, 5, 20]);
+
+        // clearing a blank display changes nothing, so the frame stays the same
+        display.reset_dirty();
+        display.clear();
+        assert_eq!(display.frame(), 3);
+        assert!(!display.is_dirty());
+    }
+
+    #[test]
+    fn packed_bytes() {
+        let mut display = Display::new(128, 64);
+        display.draw_sprite(124, 0, 1, &[0b1010_1010], false);
+        let bytes = display.to_packed_bytes();
+        assert_eq!(bytes.len(), 16 * 64);
+        assert_eq!(bytes[15], 0b0000_1010);
+    }
+}
@@ -0,0 +1,208 @@
+//! Post-processing to reduce the flicker caused by games erasing and redrawing sprites every frame
+//!
+//! Effects turn the on/off pixels of the display into a brightness from 0.0 to 1.0 per pixel,
+//! which [`crate::render::Renderer::render_intensity`] blends between the background and foreground colors.
+
+use alloc::collections::VecDeque;
+
+use crate::prelude::*;
+
+use crate::display::Display;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum BlendMode {
+    /// A pixel is fully lit if it was on in any of the blended frames
+    Or,
+    /// A pixel's brightness is the fraction of blended frames it was on in
+    Average,
+}
+
+/// With the `serde` feature, the mode is named by an `effect` field next to its settings,
+/// such as `{ effect = "phosphor", decay = 0.3 }`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "effect", rename_all = "kebab-case"))]
+pub enum PersistenceMode {
+    /// Pixels are either fully on or fully off
+    #[default]
+    None,
+    /// Pixels fade out like the phosphor of a CRT instead of turning off immediately.
+    /// `decay` is the fraction of brightness lost each frame, from 0.0 to 1.0.
+    Phosphor { decay: f32 },
+    /// Combines the last `frames` frames
+    Blend { frames: usize, mode: BlendMode },
+}
+
+/// Keeps the display history needed by a [`PersistenceMode`]
+#[derive(Clone, Debug, Default)]
+pub struct Persistence {
+    mode: PersistenceMode,
+    width: usize,
+    height: usize,
+    intensity: Vec<f32>,
+    history: VecDeque<Vec<u128>>,
+    changed: bool,
+}
+
+impl Persistence {
+    pub fn new(mode: PersistenceMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    pub fn mode(&self) -> PersistenceMode {
+        self.mode
+    }
+
+    /// Changes the mode, discarding the current history
+    pub fn set_mode(&mut self, mode: PersistenceMode) {
+        self.mode = mode;
+        self.history.clear();
+        self.intensity.clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode != PersistenceMode::None
+    }
+
+    /// Whether any pixel is partially lit, meaning the output can change even if the display doesn't
+    pub fn is_fading(&self) -> bool {
+        self.intensity
+            .iter()
+            .any(|&intensity| intensity > 0.0 && intensity < 1.0)
+    }
+
+    /// Whether the last update changed the brightness of any pixel, so the output needs redrawing
+    ///
+    /// This can be true when the display didn't change, such as when an erased pixel leaves the blended frames.
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Brightness of every pixel as of the last update, row by row
+    pub fn intensity(&self) -> &[f32] {
+        &self.intensity
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Adds a frame to the history and recalculates the brightness of each pixel.
+    /// Call this once per 60 Hz frame, not per instruction.
+    pub fn update(&mut self, display: &Display) -> &[f32] {
+        let (width, height) = (display.get_width(), display.get_height());
+        let previous = self.intensity.clone();
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            self.history.clear();
+            self.intensity = vec![0.0; width * height];
+        }
+
+        match self.mode {
+            PersistenceMode::None => {
+                for (intensity, pixel) in self.intensity.iter_mut().zip(display.pixel_iter()) {
+                    *intensity = pixel as u8 as f32;
+                }
+            }
+            PersistenceMode::Phosphor { decay } => {
+                let remaining = 1.0 - decay.clamp(0.0, 1.0);
+                for (intensity, pixel) in self.intensity.iter_mut().zip(display.pixel_iter()) {
+                    *intensity = if pixel { 1.0 } else { *intensity * remaining };
+                    // snap very dim pixels to black so the display can settle
+                    if *intensity < 1.0 / 256.0 {
+                        *intensity = 0.0;
+                    }
+                }
+            }
+            PersistenceMode::Blend { frames, mode } => {
+                let frames = frames.max(1);
+                self.history.push_front(display.rows().to_vec());
+                self.history.truncate(frames);
+
+                for y in 0..height {
+                    for x in 0..width {
+                        let bit = 1 << (crate::display::MAX_WIDTH - 1 - x);
+                        let lit = self
+                            .history
+                            .iter()
+                            .filter(|rows| rows[y] & bit != 0)
+                            .count();
+                        self.intensity[y * width + x] = match mode {
+                            BlendMode::Or => (lit > 0) as u8 as f32,
+                            BlendMode::Average => lit as f32 / self.history.len() as f32,
+                        };
+                    }
+                }
+            }
+        }
+        self.changed = self.intensity != previous;
+        &self.intensity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phosphor_decay() {
+        let mut display = Display::default();
+        let mut persistence = Persistence::new(PersistenceMode::Phosphor { decay: 0.5 });
+        display.draw_sprite(0, 0, 1, &[0x80], false);
+        assert_eq!(persistence.update(&display)[0], 1.0);
+
+        display.clear();
+        assert_eq!(persistence.update(&display)[0], 0.5);
+        assert_eq!(persistence.update(&display)[0], 0.25);
+        assert!(persistence.is_fading());
+
+        for _ in 0..10 {
+            persistence.update(&display);
+        }
+        assert_eq!(persistence.intensity()[0], 0.0);
+        assert!(!persistence.is_fading());
+    }
+
+    #[test]
+    fn blend_frames() {
+        let mut display = Display::default();
+        let mut or = Persistence::new(PersistenceMode::Blend {
+            frames: 2,
+            mode: BlendMode::Or,
+        });
+        let mut average = Persistence::new(PersistenceMode::Blend {
+            frames: 2,
+            mode: BlendMode::Average,
+        });
+
+        // a sprite flickering on and off every frame
+        display.draw_sprite(0, 0, 1, &[0x80], false);
+        or.update(&display);
+        average.update(&display);
+        display.clear();
+
+        assert_eq!(or.update(&display)[0], 1.0);
+        assert_eq!(average.update(&display)[0], 0.5);
+
+        // the frame with the sprite has left the history
+        assert_eq!(or.update(&display)[0], 0.0);
+        assert_eq!(average.update(&display)[0], 0.0);
+        assert!(
+            or.changed(),
+            "The pixel turned off without the display changing"
+        );
+
+        or.update(&display);
+        assert!(!or.changed());
+    }
+}
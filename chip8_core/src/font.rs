@@ -18,6 +18,9 @@ pub mod letters {
     pub const LETTER_F: [u8; 5] = [0xF0, 0x80, 0xF0, 0x80, 0x80];
 }
 
+/// The size of a font, 5 bytes for each of the 16 digits
+pub const FONT_SIZE: usize = 80;
+
 pub fn load_font(memory: &mut [u8]) {
     use letters::*;
 
@@ -0,0 +1,484 @@
+//! Traits for the parts of a frontend, and a [`Runner`] that drives the VM with them
+//!
+//! A frontend implements a [`VideoSink`] to show the display, an [`AudioSink`] to play the tone,
+//! an [`InputSource`] for the keypad and emulator controls, and optionally a [`TimeSource`]
+//! if it doesn't run in real time. The runner takes care of pacing frames, pausing, muting
+//! and replaying movies. Frontends with their own frame loop, like a browser's animation frames,
+//! call [`Runner::step_frame`] instead of [`Runner::run`].
+
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::keypad::Key;
+use crate::movie::Playback;
+use crate::Chip8;
+
+/// How long each 60 Hz frame lasts
+pub const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Emulator state for a frontend to show alongside the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunStatus<'a> {
+    pub paused: bool,
+    pub muted: bool,
+    /// Whether a movie is driving the keypad
+    pub replaying: bool,
+    /// The last error or warning, empty if there hasn't been one
+    pub message: &'a str,
+}
+
+/// What the tone should do after a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sound {
+    /// Whether the tone is on, which is never the case while muted
+    pub playing: bool,
+    /// Whether the sound timer started during the frame, even if it already stopped again
+    pub started: bool,
+}
+
+/// Something that happened in the frontend
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    Press(Key),
+    Release(Key),
+    /// Pause or unpause
+    Pause,
+    /// Run a single instruction while paused
+    Step,
+    /// Mute or unmute
+    Mute,
+    /// Restart the ROM, see [`Chip8::soft_reset`]
+    Reset,
+    Quit,
+}
+
+pub trait VideoSink {
+    /// Shows the display, called after every frame whether or not it changed
+    fn present(&mut self, system: &Chip8, status: &RunStatus) -> io::Result<()>;
+}
+
+pub trait AudioSink {
+    /// Plays the tone for the next frame, the pattern and pitch can be read from `system`
+    fn update(&mut self, system: &Chip8, sound: Sound) -> io::Result<()>;
+}
+
+pub trait InputSource {
+    /// Returns the next input without blocking, or None once there's nothing left for this frame
+    fn poll(&mut self) -> io::Result<Option<Input>>;
+
+    /// Called at the start of every frame, before polling
+    fn next_frame(&mut self) {}
+}
+
+pub trait TimeSource {
+    /// Time since a fixed point, such as when the source was created
+    fn now(&mut self) -> Duration;
+
+    /// Blocks until [`TimeSource::now`] reaches `deadline`
+    fn sleep_until(&mut self, deadline: Duration);
+}
+
+impl<V: VideoSink + ?Sized> VideoSink for &mut V {
+    fn present(&mut self, system: &Chip8, status: &RunStatus) -> io::Result<()> {
+        (**self).present(system, status)
+    }
+}
+
+impl<A: AudioSink + ?Sized> AudioSink for &mut A {
+    fn update(&mut self, system: &Chip8, sound: Sound) -> io::Result<()> {
+        (**self).update(system, sound)
+    }
+}
+
+impl<I: InputSource + ?Sized> InputSource for &mut I {
+    fn poll(&mut self) -> io::Result<Option<Input>> {
+        (**self).poll()
+    }
+
+    fn next_frame(&mut self) {
+        (**self).next_frame()
+    }
+}
+
+impl<T: TimeSource + ?Sized> TimeSource for &mut T {
+    fn now(&mut self) -> Duration {
+        (**self).now()
+    }
+
+    fn sleep_until(&mut self, deadline: Duration) {
+        (**self).sleep_until(deadline)
+    }
+}
+
+/// Wall clock time
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl TimeSource for SystemClock {
+    fn now(&mut self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep_until(&mut self, deadline: Duration) {
+        std::thread::sleep(deadline.saturating_sub(self.now()));
+    }
+}
+
+/// Runs a VM at a fixed number of instructions per 60 Hz frame, connected to a frontend
+pub struct Runner<'a, V, A, I, T = SystemClock> {
+    video: V,
+    audio: A,
+    input: I,
+    time: T,
+    cycles_per_frame: usize,
+    paused: bool,
+    muted: bool,
+    message: String,
+    playback: Option<Playback<'a>>,
+}
+
+impl<'a, V: VideoSink, A: AudioSink, I: InputSource> Runner<'a, V, A, I> {
+    pub fn new(video: V, audio: A, input: I, cycles_per_frame: usize) -> Self {
+        Self::with_time(
+            video,
+            audio,
+            input,
+            SystemClock::default(),
+            cycles_per_frame,
+        )
+    }
+}
+
+impl<'a, V: VideoSink, A: AudioSink, I: InputSource, T: TimeSource> Runner<'a, V, A, I, T> {
+    pub fn with_time(video: V, audio: A, input: I, time: T, cycles_per_frame: usize) -> Self {
+        Self {
+            video,
+            audio,
+            input,
+            time,
+            cycles_per_frame,
+            paused: false,
+            muted: false,
+            message: String::new(),
+            playback: None,
+        }
+    }
+
+    /// Drives the keypad from a movie until it finishes, ignoring key input meanwhile
+    pub fn replay(&mut self, playback: Playback<'a>) {
+        self.playback = Some(playback);
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.playback
+            .as_ref()
+            .is_some_and(|playback| !playback.is_finished())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn cycles_per_frame(&self) -> usize {
+        self.cycles_per_frame
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: usize) {
+        self.cycles_per_frame = cycles;
+    }
+
+    pub fn video(&self) -> &V {
+        &self.video
+    }
+
+    pub fn video_mut(&mut self) -> &mut V {
+        &mut self.video
+    }
+
+    /// Runs frames in real time until the input source quits
+    pub fn run(&mut self, system: &mut Chip8) -> io::Result<()> {
+        self.watch_sound(system, Self::run_frames)
+    }
+
+    /// Handles input, then runs and presents a single frame without waiting,
+    /// for frontends that decide when frames happen themselves
+    /// # Returns
+    /// Returns false if the input source quit
+    pub fn step_frame(&mut self, system: &mut Chip8) -> io::Result<bool> {
+        self.watch_sound(system, Self::frame)
+    }
+
+    /// Calls `run` with a flag that's set whenever the sound timer starts
+    fn watch_sound<R>(
+        &mut self,
+        system: &mut Chip8,
+        run: impl FnOnce(&mut Self, &mut Chip8, &Cell<bool>) -> R,
+    ) -> R {
+        let started = Rc::new(Cell::new(false));
+        let hook = system.on_sound_start({
+            let started = started.clone();
+            move || started.set(true)
+        });
+        let result = run(self, system, &started);
+        system.remove_hook(hook);
+        result
+    }
+
+    fn run_frames(&mut self, system: &mut Chip8, sound_started: &Cell<bool>) -> io::Result<()> {
+        let mut next_frame = self.time.now();
+        while self.frame(system, sound_started)? {
+            next_frame += FRAME_TIME;
+            // don't try to catch up after falling far behind, such as after the process was suspended
+            let now = self.time.now();
+            if next_frame < now {
+                next_frame = now + FRAME_TIME;
+            }
+            self.time.sleep_until(next_frame);
+        }
+        Ok(())
+    }
+
+    /// [`Runner::step_frame`] once the sound hook is subscribed
+    fn frame(&mut self, system: &mut Chip8, sound_started: &Cell<bool>) -> io::Result<bool> {
+        self.input.next_frame();
+        while let Some(input) = self.input.poll()? {
+            if !self.handle_input(system, input) {
+                return Ok(false);
+            }
+        }
+
+        if !self.paused && system.running {
+            if let Some(playback) = &mut self.playback {
+                playback.apply_inputs(system);
+            }
+            if let Err(err) = system.run_frame(self.cycles_per_frame) {
+                self.message = err.to_string();
+                system.running = false;
+            }
+        }
+
+        let sound = Sound {
+            playing: system.timers.is_sound_on() && !self.muted,
+            started: sound_started.take() && !self.muted,
+        };
+        self.audio.update(system, sound)?;
+        let status = RunStatus {
+            paused: self.paused,
+            muted: self.muted,
+            replaying: self.is_replaying(),
+            message: &self.message,
+        };
+        self.video.present(system, &status)?;
+        Ok(true)
+    }
+
+    /// # Returns
+    /// Returns false for [`Input::Quit`]
+    fn handle_input(&mut self, system: &mut Chip8, input: Input) -> bool {
+        match input {
+            // the movie has the keypad to itself
+            Input::Press(_) | Input::Release(_) if self.is_replaying() => {}
+            Input::Press(key) => system.press_key(key),
+            Input::Release(key) => system.release_key(key),
+            Input::Pause => self.paused = !self.paused,
+            Input::Mute => self.muted = !self.muted,
+            Input::Step if !self.paused => {}
+            // single steps would put the movie out of sync with the frames it was recorded on
+            Input::Step if system.recording.is_some() || self.is_replaying() => {
+                self.message = "Can't step while recording or replaying a movie".into();
+            }
+            Input::Step => {
+                if let Err(err) = system.step() {
+                    self.message = err.to_string();
+                }
+            }
+            Input::Reset if system.recording.is_some() || self.is_replaying() => {
+                self.message = "Can't reset while recording or replaying a movie".into();
+            }
+            Input::Reset => {
+                system.soft_reset();
+                self.message.clear();
+            }
+            Input::Quit => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Inputs to give on each frame, then quits
+    #[derive(Default)]
+    struct Script {
+        frames: VecDeque<Vec<Input>>,
+        current: VecDeque<Input>,
+    }
+
+    impl InputSource for Script {
+        fn poll(&mut self) -> io::Result<Option<Input>> {
+            Ok(self.current.pop_front())
+        }
+
+        fn next_frame(&mut self) {
+            self.current = self.frames.pop_front().unwrap_or(vec![Input::Quit]).into();
+        }
+    }
+
+    /// The program counter and whether the runner was paused after each frame
+    #[derive(Default)]
+    struct Frames(Vec<(u16, bool)>);
+
+    impl VideoSink for Frames {
+        fn present(&mut self, system: &Chip8, status: &RunStatus) -> io::Result<()> {
+            self.0.push((system.pc, status.paused));
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Sounds(Vec<(bool, bool)>);
+
+    impl AudioSink for Sounds {
+        fn update(&mut self, _: &Chip8, sound: Sound) -> io::Result<()> {
+            self.0.push((sound.playing, sound.started));
+            Ok(())
+        }
+    }
+
+    /// Skips ahead instead of sleeping
+    #[derive(Default)]
+    struct FakeClock(Duration);
+
+    impl TimeSource for FakeClock {
+        fn now(&mut self) -> Duration {
+            self.0
+        }
+
+        fn sleep_until(&mut self, deadline: Duration) {
+            self.0 = deadline;
+        }
+    }
+
+    #[test]
+    fn runs_frames() {
+        let mut system = Chip8::new();
+        // start a 2 frame beep, then count up in V0
+        let mut rom = vec![0x60, 0x02, 0xF0, 0x18];
+        rom.extend([0x70, 0x01].repeat(20));
+        system.load_rom(&rom, 0x200).unwrap();
+
+        let mut input = Script {
+            frames: [
+                vec![],
+                vec![Input::Pause],
+                vec![Input::Step, Input::Step],
+                vec![Input::Pause, Input::Press(Key::Key3)],
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let mut video = Frames::default();
+        let mut audio = Sounds::default();
+        let mut clock = FakeClock::default();
+        Runner::with_time(&mut video, &mut audio, &mut input, &mut clock, 4)
+            .run(&mut system)
+            .unwrap();
+
+        // 4 instructions a frame, except for the steps while paused
+        assert_eq!(
+            video.0,
+            [(0x208, false), (0x208, true), (0x20C, true), (0x214, false)]
+        );
+        assert_eq!(
+            audio.0,
+            [(true, true), (true, false), (true, false), (false, false)]
+        );
+        assert!(system.keypad.is_key_pressed(Key::Key3));
+        assert_eq!(clock.0, FRAME_TIME * 4);
+    }
+
+    #[test]
+    fn steps_frames() {
+        let mut system = Chip8::new();
+        // beep for a frame, then loop
+        system
+            .load_rom(&[0x60, 0x01, 0xF0, 0x18, 0x12, 0x04], 0x200)
+            .unwrap();
+        let mut input = Script {
+            frames: [vec![], vec![Input::Mute], vec![]].into(),
+            ..Default::default()
+        };
+        let mut runner = Runner::with_time(
+            Frames::default(),
+            Sounds::default(),
+            &mut input,
+            FakeClock::default(),
+            2,
+        );
+
+        assert!(runner.step_frame(&mut system).unwrap());
+        runner.set_cycles_per_frame(1);
+        assert!(runner.step_frame(&mut system).unwrap());
+        assert!(runner.step_frame(&mut system).unwrap());
+        assert!(!runner.step_frame(&mut system).unwrap());
+
+        assert_eq!(runner.video().0, [(0x204, false); 3]);
+        assert_eq!(
+            runner.audio.0,
+            [(false, true), (false, false), (false, false)]
+        );
+        assert_eq!(runner.time.0, Duration::ZERO, "Stepping never sleeps");
+    }
+
+    #[test]
+    fn replays_movies() {
+        let mut system = Chip8::new();
+        // wait for a key, then halt
+        system.load_rom(&[0xF0, 0x0A], 0x200).unwrap();
+        let mut movie = crate::movie::Movie::new(0, system.quirks.clone());
+        movie.cycles_per_frame = 1;
+        movie.frames = 3;
+        movie.events = vec![crate::movie::InputEvent {
+            frame: 1,
+            key: Key::Key7,
+            pressed: true,
+        }];
+
+        let mut input = Script {
+            frames: [vec![Input::Press(Key::Key2)], vec![], vec![Input::Step]].into(),
+            ..Default::default()
+        };
+        let mut runner = Runner::with_time(
+            Frames::default(),
+            Sounds::default(),
+            &mut input,
+            FakeClock::default(),
+            1,
+        );
+        runner.replay(movie.start_playback(&mut system));
+        runner.run(&mut system).unwrap();
+
+        assert!(
+            !system.keypad.is_key_pressed(Key::Key2),
+            "Input is ignored while replaying"
+        );
+        assert!(system.keypad.is_key_pressed(Key::Key7));
+        assert!(!runner.is_replaying());
+    }
+}
@@ -1,9 +1,234 @@
-// No args
-struct EventHandler {
-    // This is static lifetime by default, but it can be implemented otherwise
-    callback: Option<Box<dyn FnMut()>>,
+//! Observer hooks for things happening inside the VM
+//!
+//! Frontends and tools subscribe with the `on_*` methods on [`Chip8`](crate::Chip8)
+//! rather than polling its fields every frame, then stop listening with
+//! [`Chip8::remove_hook`](crate::Chip8::remove_hook).
+//!
+//! Callbacks don't have to be `Send`, so they can share state through `Rc` or wrap JavaScript functions.
+//! In exchange, a VM can't be moved to another thread once it's created.
+
+use core::ops::Range;
+
+use crate::prelude::*;
+
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// Identifies a subscribed callback, so it can be removed later
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HookId(u64);
+
+/// A sprite was drawn with DXYN
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrawEvent {
+    pub x: u8,
+    pub y: u8,
+    pub height: u8,
+    /// Whether the sprite turned off any pixels, which is what VF is set to
+    pub collision: bool,
+}
+
+/// The display was replaced with one of a different size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResolutionEvent {
+    pub width: usize,
+    pub height: usize,
+}
+
+/// The program wrote to memory, such as with FX33 or FX55
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl MemoryWrite {
+    /// The addresses written to, which can end just past 0xFFFF on 64 KiB of memory
+    pub fn range(&self) -> Range<usize> {
+        let start = self.address as usize;
+        start..start + self.bytes.len()
+    }
+}
+
+type Callback<T> = Box<dyn FnMut(&T)>;
+
+/// Callbacks for one kind of event, called in the order they were added
+pub struct EventHandler<T> {
+    callbacks: Vec<(HookId, Callback<T>)>,
+}
+
+impl<T> Default for EventHandler<T> {
+    fn default() -> Self {
+        Self {
+            callbacks: Vec::new(),
+        }
+    }
 }
 
-impl EventHandler {
-    fn set_callback(&mut self, cb: impl FnMut()) {}
+impl<T> EventHandler<T> {
+    fn add(&mut self, id: HookId, callback: Callback<T>) {
+        self.callbacks.push((id, callback));
+    }
+
+    fn remove(&mut self, id: HookId) -> bool {
+        let count = self.callbacks.len();
+        self.callbacks.retain(|(hook, _)| *hook != id);
+        self.callbacks.len() != count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    pub(crate) fn emit(&mut self, event: &T) {
+        for (_, callback) in &mut self.callbacks {
+            callback(event);
+        }
+    }
+}
+
+/// Every hook subscribed to a VM
+#[derive(Default)]
+pub struct Hooks {
+    next_id: u64,
+    pub(crate) draw: EventHandler<DrawEvent>,
+    pub(crate) clear: EventHandler<()>,
+    pub(crate) sound_start: EventHandler<()>,
+    pub(crate) sound_stop: EventHandler<()>,
+    /// Gets the register the key will be stored in
+    pub(crate) key_wait: EventHandler<usize>,
+    pub(crate) halt: EventHandler<()>,
+    pub(crate) error: EventHandler<crate::DecodingError>,
+    pub(crate) resolution_change: EventHandler<ResolutionEvent>,
+    pub(crate) memory_write: EventHandler<MemoryWrite>,
+}
+
+impl Hooks {
+    pub(crate) fn add<T>(
+        &mut self,
+        handler: impl FnOnce(&mut Self) -> &mut EventHandler<T>,
+        callback: Callback<T>,
+    ) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        handler(self).add(id, callback);
+        id
+    }
+
+    /// Removes a callback from whichever event it was subscribed to
+    /// # Returns
+    /// Returns false if there was no callback with that id
+    pub(crate) fn remove(&mut self, id: HookId) -> bool {
+        self.draw.remove(id)
+            || self.clear.remove(id)
+            || self.sound_start.remove(id)
+            || self.sound_stop.remove(id)
+            || self.key_wait.remove(id)
+            || self.halt.remove(id)
+            || self.error.remove(id)
+            || self.resolution_change.remove(id)
+            || self.memory_write.remove(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::builder::Chip8Builder;
+    use crate::keypad::Key;
+    use crate::quirks::QuirkPresets;
+    use crate::Chip8;
+
+    #[test]
+    fn hooks_fire() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Chip8::new();
+        vm.quirks.display_wait = false;
+        let log = |name: &'static str| {
+            let events = events.clone();
+            move || events.borrow_mut().push(name.to_owned())
+        };
+        vm.on_clear(log("clear"));
+        let draw = log("draw");
+        vm.on_draw(move |_| draw());
+        vm.on_sound_start(log("sound start"));
+        vm.on_sound_stop(log("sound stop"));
+        let wait = log("key wait");
+        vm.on_key_wait(move |_| wait());
+        vm.on_halt(log("halt"));
+        let writes = events.clone();
+        vm.on_memory_write(0x300..0x302, move |write| {
+            writes
+                .borrow_mut()
+                .push(format!("write {:X?}", write.range()))
+        });
+
+        #[rustfmt::skip]
+        let rom = [
+            0x00, 0xE0, // clear
+            0xD0, 0x05, // draw
+            0x60, 0x02, // V0 = 2
+            0xF0, 0x18, // sound timer = V0
+            0xA3, 0x01, // I = 0x301
+            0xF0, 0x33, // BCD into 0x301..0x304
+            0xA3, 0x10, // I = 0x310
+            0xF0, 0x33, // BCD outside of the range
+            0xF1, 0x0A, // wait for a key
+            0x00, 0x00, // halt
+        ];
+        vm.load_rom(&rom, 0x200).unwrap();
+        vm.run_frame(20).unwrap();
+        vm.run_frame(20).unwrap();
+        vm.press_key(Key::Key1);
+        vm.release_key(Key::Key1);
+        vm.run_frame(20).unwrap();
+
+        assert_eq!(
+            *events.borrow(),
+            [
+                "clear",
+                "draw",
+                "sound start",
+                "write 301..304",
+                "key wait",
+                "sound stop",
+                "halt"
+            ]
+        );
+    }
+
+    #[test]
+    fn write_to_the_end_of_memory() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Chip8Builder::new()
+            .platform(QuirkPresets::XoChip)
+            .rom(&[0xFF, 0x55])
+            .build()
+            .unwrap();
+        let log = writes.clone();
+        vm.on_memory_write(0xFFFF..0x10000, move |write| {
+            log.borrow_mut().push(format!("{:X?}", write.range()))
+        });
+        vm.pointer = 0xFFF0;
+        vm.step().unwrap();
+        assert_eq!(*writes.borrow(), ["FFF0..10000"]);
+        assert_eq!(vm.pointer, 0);
+    }
+
+    #[test]
+    fn remove_hooks() {
+        let draws = Rc::new(RefCell::new(0));
+        let mut vm = Chip8::new();
+        let counter = draws.clone();
+        let id = vm.on_draw(move |_| *counter.borrow_mut() += 1);
+        vm.load_rom(&[0xD0, 0x05, 0xD0, 0x05], 0x200).unwrap();
+        vm.step().unwrap();
+        assert!(vm.remove_hook(id));
+        assert!(!vm.remove_hook(id));
+        vm.step().unwrap();
+        assert_eq!(*draws.borrow(), 1);
+    }
 }
@@ -0,0 +1,337 @@
+//! Running ROMs without a frontend until a condition is met, and checking the resulting screen
+//!
+//! This is meant for automated tests: run a test ROM until it reaches the infinite loop
+//! at the end of its results screen, then compare the display against a known good dump.
+
+use core::fmt::Write;
+use core::str::FromStr;
+
+use crate::prelude::*;
+
+use thiserror::Error;
+
+use crate::display::Display;
+use crate::instruction::Instruction;
+use crate::{Chip8, DecodingError};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error(
+    "Invalid stop condition {0:?}, expected halt, self-jump, pc=<address> or mem=<address>:<value>"
+)]
+pub struct ParseConditionError(String);
+
+/// A reason to stop running before the frame limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopCondition {
+    /// The VM halted, such as with a 0000 instruction
+    Halt,
+    /// The next instruction jumps to itself, which is how most test ROMs end
+    SelfJump,
+    /// The program counter reached an address
+    PcReached(u16),
+    /// A memory address holds a value
+    MemoryEquals { address: u16, value: u8 },
+}
+
+impl StopCondition {
+    pub fn is_met(&self, system: &Chip8) -> bool {
+        match *self {
+            StopCondition::Halt => !system.running,
+            StopCondition::SelfJump => is_self_jump(system),
+            StopCondition::PcReached(address) => system.pc == address,
+            StopCondition::MemoryEquals { address, value } => {
+                system.memory.get(address as usize) == Some(&value)
+            }
+        }
+    }
+}
+
+fn is_self_jump(system: &Chip8) -> bool {
+    let pc = system.pc as usize;
+    pc + 1 < system.memory.len()
+        && Instruction::from(system.get_u16(pc)) == Instruction::Goto { address: system.pc }
+}
+
+impl FromStr for StopCondition {
+    type Err = ParseConditionError;
+
+    /// Parses `halt`, `self-jump`, `pc=<address>` or `mem=<address>:<value>`,
+    /// where numbers are hex with a `0x` prefix, or decimal
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let error = || ParseConditionError(text.to_owned());
+        let number = |text: &str| match text.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => text.parse().ok(),
+        };
+
+        match text.trim().to_ascii_lowercase().as_str() {
+            "halt" => Ok(StopCondition::Halt),
+            "self-jump" => Ok(StopCondition::SelfJump),
+            condition => {
+                if let Some(address) = condition.strip_prefix("pc=") {
+                    number(address)
+                        .map(StopCondition::PcReached)
+                        .ok_or_else(error)
+                } else if let Some(memory) = condition.strip_prefix("mem=") {
+                    let (address, value) = memory.split_once(':').ok_or_else(error)?;
+                    let address = number(address).ok_or_else(error)?;
+                    let value = number(value)
+                        .and_then(|value| u8::try_from(value).ok())
+                        .ok_or_else(error)?;
+                    Ok(StopCondition::MemoryEquals { address, value })
+                } else {
+                    Err(error())
+                }
+            }
+        }
+    }
+}
+
+impl core::fmt::Display for StopCondition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StopCondition::Halt => write!(f, "halt"),
+            StopCondition::SelfJump => write!(f, "self-jump"),
+            StopCondition::PcReached(address) => write!(f, "pc={address:#05X}"),
+            StopCondition::MemoryEquals { address, value } => {
+                write!(f, "mem={address:#05X}:{value:#04X}")
+            }
+        }
+    }
+}
+
+/// Why a headless run stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// One of the requested conditions was met
+    Condition(StopCondition),
+    /// The VM halted without a halt condition being requested.
+    /// Nothing can happen after halting, so there's no point running until the frame limit.
+    Halted,
+    FrameLimit,
+}
+
+/// The result of [`run_until`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub reason: StopReason,
+    /// Number of frames that were started
+    pub frames: usize,
+}
+
+/// Runs frames of `cycles_per_frame` instructions until one of `conditions` is met,
+/// the VM halts, or `max_frames` frames have run
+pub fn run_until(
+    system: &mut Chip8,
+    cycles_per_frame: usize,
+    max_frames: usize,
+    conditions: &[StopCondition],
+) -> Result<RunOutcome, DecodingError> {
+    let check = |system: &Chip8| conditions.iter().find(|condition| condition.is_met(system));
+
+    if let Some(&condition) = check(system) {
+        return Ok(RunOutcome {
+            reason: StopReason::Condition(condition),
+            frames: 0,
+        });
+    }
+    for frame in 1..=max_frames {
+        let mut met = None;
+        system.run_frame_until(cycles_per_frame, |system| {
+            met = check(system).copied();
+            met.is_some()
+        })?;
+
+        let reason = match met {
+            Some(condition) => StopReason::Condition(condition),
+            None if !system.running => StopReason::Halted,
+            None => continue,
+        };
+        return Ok(RunOutcome {
+            reason,
+            frames: frame,
+        });
+    }
+    Ok(RunOutcome {
+        reason: StopReason::FrameLimit,
+        frames: max_frames,
+    })
+}
+
+/// Formats the display as a plain (ASCII) PBM image, with 1 for lit pixels
+pub fn to_pbm(display: &Display) -> String {
+    let mut pbm = format!("P1\n{} {}\n", display.get_width(), display.get_height());
+    for y in 0..display.get_height() {
+        let row: Vec<&str> = (0..display.get_width())
+            .map(|x| if display.get_pixel(x, y) { "1" } else { "0" })
+            .collect();
+        let _ = writeln!(pbm, "{}", row.join(" "));
+    }
+    pbm
+}
+
+/// Parses a display dump, either a plain PBM image or the text drawn by the display's `Display` impl.
+///
+/// Text dumps may also use `#` for lit pixels and `.` for unlit ones, so golden files can be written by hand.
+pub fn parse_dump(text: &str) -> Option<Display> {
+    let text = text.trim_start();
+    if let Some(pbm) = text.strip_prefix("P1") {
+        // comments run from # to the end of the line
+        let mut tokens = pbm
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(str::split_whitespace);
+        let width: usize = tokens.next()?.parse().ok()?;
+        let height: usize = tokens.next()?.parse().ok()?;
+        // pixels don't need to be separated by whitespace
+        let mut values = tokens.flat_map(str::chars);
+        let mut display = new_display(width, height)?;
+        for y in 0..height {
+            for x in 0..width {
+                match values.next()? {
+                    '1' => display.set_pixel(x, y, true),
+                    '0' => {}
+                    _ => return None,
+                }
+            }
+        }
+        Some(display)
+    } else {
+        let rows: Vec<&str> = text.lines().map(str::trim_end).collect();
+        let rows = &rows[..rows.iter().rposition(|row| !row.is_empty())? + 1];
+        let width = rows[0].chars().count();
+        let mut display = new_display(width, rows.len())?;
+        for (y, row) in rows.iter().enumerate() {
+            if row.chars().count() != width {
+                return None;
+            }
+            for (x, pixel) in row.chars().enumerate() {
+                match pixel {
+                    '█' | '#' => display.set_pixel(x, y, true),
+                    '░' | '.' => {}
+                    _ => return None,
+                }
+            }
+        }
+        Some(display)
+    }
+}
+
+fn new_display(width: usize, height: usize) -> Option<Display> {
+    let valid = width > 0
+        && width <= crate::display::MAX_WIDTH
+        && width.is_multiple_of(8)
+        && height > 0
+        && height <= crate::display::MAX_WIDTH;
+    valid.then(|| Display::new(width, height))
+}
+
+/// Number of pixels that differ between two displays, or None if they aren't the same size
+pub fn pixel_difference(first: &Display, second: &Display) -> Option<u32> {
+    if (first.get_width(), first.get_height()) != (second.get_width(), second.get_height()) {
+        return None;
+    }
+    Some(
+        first
+            .rows()
+            .iter()
+            .zip(second.rows())
+            .map(|(first, second)| (first ^ second).count_ones())
+            .sum(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::Engine;
+
+    #[test]
+    fn parse_conditions() {
+        assert_eq!("halt".parse(), Ok(StopCondition::Halt));
+        assert_eq!("self-jump".parse(), Ok(StopCondition::SelfJump));
+        assert_eq!("pc=0x2A4".parse(), Ok(StopCondition::PcReached(0x2A4)));
+        assert_eq!(
+            "mem=0x3FF:1".parse(),
+            Ok(StopCondition::MemoryEquals {
+                address: 0x3FF,
+                value: 1
+            })
+        );
+        assert!("mem=0x3FF:0x100".parse::<StopCondition>().is_err());
+        assert!("forever".parse::<StopCondition>().is_err());
+
+        let condition = StopCondition::MemoryEquals {
+            address: 0xABC,
+            value: 0xD,
+        };
+        assert_eq!(condition.to_string().parse(), Ok(condition));
+    }
+
+    #[test]
+    fn stops_at_self_jump() {
+        let mut system = Chip8::new();
+        // V0 = 1, *0x300 = V0, loop forever
+        let rom = [0x60, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x06];
+        system.load_rom(&rom, 0x200).unwrap();
+
+        let outcome = run_until(&mut system, 10, 100, &[StopCondition::SelfJump]).unwrap();
+        assert_eq!(
+            outcome.reason,
+            StopReason::Condition(StopCondition::SelfJump)
+        );
+        assert_eq!(outcome.frames, 1);
+        assert_eq!(system.pc, 0x206);
+
+        let mut system = Chip8::new();
+        system.load_rom(&rom, 0x200).unwrap();
+        let condition = StopCondition::MemoryEquals {
+            address: 0x300,
+            value: 1,
+        };
+        let outcome = run_until(&mut system, 10, 100, &[condition]).unwrap();
+        assert_eq!(outcome.reason, StopReason::Condition(condition));
+
+        let outcome = run_until(&mut system, 10, 5, &[StopCondition::Halt]).unwrap();
+        assert_eq!(outcome.reason, StopReason::FrameLimit);
+    }
+
+    #[test]
+    fn runs_on_the_block_engine() {
+        #[rustfmt::skip]
+        let rom = [
+            0x70, 0x01, // 200: V0 += 1
+            0x30, 0x64, // 202: skip if V0 == 100
+            0x12, 0x00, // 204: jump to the start
+            0x12, 0x06, // 206: loop forever
+        ];
+        let run = |engine| {
+            let mut system = Chip8::new();
+            system.set_engine(engine);
+            system.load_rom(&rom, 0x200).unwrap();
+            let outcome = run_until(&mut system, 10, 100, &[StopCondition::SelfJump]).unwrap();
+            (outcome, system)
+        };
+        let (interpreted, _) = run(Engine::Interpreter);
+        let (compiled, system) = run(Engine::Blocks);
+        assert_eq!(compiled, interpreted);
+        assert_eq!(system.pc, 0x206);
+        assert!(!system.compiled_blocks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dump_round_trip() {
+        let mut display = Display::default();
+        display.draw_sprite(3, 4, 2, &[0xA5, 0x5A], false);
+
+        let from_pbm = parse_dump(&to_pbm(&display)).unwrap();
+        assert_eq!(pixel_difference(&display, &from_pbm), Some(0));
+        let from_text = parse_dump(&display.to_string()).unwrap();
+        assert_eq!(pixel_difference(&display, &from_text), Some(0));
+
+        let mut changed = display.clone();
+        changed.set_pixel(0, 0, true);
+        assert_eq!(pixel_difference(&display, &changed), Some(1));
+        assert_eq!(pixel_difference(&display, &Display::new(128, 64)), None);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::prelude::*;
+
 type Register = u8;
 type Address = u16;
 type OpCode = u16;
@@ -55,6 +57,24 @@ impl MathOperation {
     }
 }
 
+impl MathOperation {
+    /// The last nibble of the `0x8XYO` opcode for this operation
+    pub fn nibble(&self) -> u16 {
+        match self {
+            MathOperation::Assign => 0x0,
+            MathOperation::BitwiseOr => 0x1,
+            MathOperation::BitwiseAnd => 0x2,
+            MathOperation::BitwiseXor => 0x3,
+            MathOperation::Add => 0x4,
+            MathOperation::Subtract => 0x5,
+            MathOperation::BitshiftRight => 0x6,
+            MathOperation::Difference => 0x7,
+            MathOperation::BitshiftLeft => 0xE,
+            MathOperation::UnknownOperation(opcode) => opcode & 0x000F,
+        }
+    }
+}
+
 impl From<OpCode> for MathOperation {
     /// Find the type of instruction that a given opcode represents
     fn from(opcode: OpCode) -> Self {
@@ -239,10 +259,128 @@ pub enum Instruction {
     /// Doesn't modify I
     RegisterLoad(Register),
 
+    // XO-CHIP instructions
+    /// 0xF002
+    /// Load the 16 byte audio pattern buffer from memory starting at address I
+    LoadAudioPattern,
+
+    /// 0xFX3A
+    /// Set the audio pattern playback pitch to Vx
+    SetPitch(Register),
+
     /// TODO: Change out for using TryFrom instead of this crutch
     UndefinedOperation(u16),
 }
 
+/// What a skip instruction checks before skipping the next instruction
+///
+/// The engines that compile skips, [`blocks`](crate::blocks) and [`transpile`](crate::transpile),
+/// both start from this, so they agree on what each skip means.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SkipCondition {
+    /// Skips if Vx equals `value`, or if it doesn't when `equal` is false
+    Const {
+        register: Register,
+        value: u8,
+        equal: bool,
+    },
+    /// Skips if Vx equals Vy, or if they differ when `equal` is false
+    Registers {
+        x: Register,
+        y: Register,
+        equal: bool,
+    },
+    /// Skips if the key in Vx is pressed, or if it isn't when `pressed` is false
+    Key { register: Register, pressed: bool },
+}
+
+impl Instruction {
+    /// Whether this instruction conditionally skips the next instruction
+    pub fn is_skip(&self) -> bool {
+        self.skip_condition().is_some()
+    }
+
+    /// When this instruction skips the next one, or None if it isn't a skip
+    pub fn skip_condition(&self) -> Option<SkipCondition> {
+        Some(match *self {
+            Instruction::RegisterEqualToConst { register, value } => SkipCondition::Const {
+                register,
+                value,
+                equal: true,
+            },
+            Instruction::RegisterNotEqualToConst { register, value } => SkipCondition::Const {
+                register,
+                value,
+                equal: false,
+            },
+            Instruction::RegistersEqual(x, y) => SkipCondition::Registers { x, y, equal: true },
+            Instruction::RegistersNotEqual(x, y) => SkipCondition::Registers { x, y, equal: false },
+            Instruction::KeyPressed(register) => SkipCondition::Key {
+                register,
+                pressed: true,
+            },
+            Instruction::KeyNotPressed(register) => SkipCondition::Key {
+                register,
+                pressed: false,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Encodes the instruction back into its opcode
+    pub fn opcode(&self) -> OpCode {
+        let x = |register: &Register| (*register as u16 & 0xF) << 8;
+        let y = |register: &Register| (*register as u16 & 0xF) << 4;
+        match self {
+            Instruction::MachineCodeCall(address) => address & 0x0FFF,
+            Instruction::Halt => 0x0000,
+            Instruction::ClearDisplay => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::Goto { address } => 0x1000 | (address & 0x0FFF),
+            Instruction::Call { address } => 0x2000 | (address & 0x0FFF),
+            Instruction::RegisterEqualToConst { register, value } => {
+                0x3000 | x(register) | *value as u16
+            }
+            Instruction::RegisterNotEqualToConst { register, value } => {
+                0x4000 | x(register) | *value as u16
+            }
+            Instruction::RegistersEqual(first, second) => 0x5000 | x(first) | y(second),
+            Instruction::SetRegister { register, value } => 0x6000 | x(register) | *value as u16,
+            Instruction::AddConst { register, value } => 0x7000 | x(register) | *value as u16,
+            Instruction::Math {
+                operation: MathOperation::UnknownOperation(opcode),
+                ..
+            } => *opcode,
+            Instruction::Math {
+                source,
+                destination,
+                operation,
+            } => 0x8000 | x(destination) | y(source) | operation.nibble(),
+            Instruction::RegistersNotEqual(first, second) => 0x9000 | x(first) | y(second),
+            Instruction::SetPointer(address) => 0xA000 | (address & 0x0FFF),
+            Instruction::JumpRelative { offset } => 0xB000 | (offset & 0x0FFF),
+            Instruction::Random { register, mask } => 0xC000 | x(register) | *mask as u16,
+            Instruction::Draw { position, height } => {
+                0xD000 | x(&position.0) | y(&position.1) | (*height as u16 & 0xF)
+            }
+            Instruction::KeyPressed(register) => 0xE09E | x(register),
+            Instruction::KeyNotPressed(register) => 0xE0A1 | x(register),
+            Instruction::GetDelayTimer(register) => 0xF007 | x(register),
+            Instruction::WaitKeyPress(register) => 0xF00A | x(register),
+            Instruction::SetDelayTimer(register) => 0xF015 | x(register),
+            Instruction::SetSoundTimer(register) => 0xF018 | x(register),
+            Instruction::AddToPointer(register) => 0xF01E | x(register),
+            Instruction::SetPointerToLetter(register) => 0xF029 | x(register),
+            Instruction::SplitNumber(register) => 0xF033 | x(register),
+            Instruction::RegisterDump(register) => 0xF055 | x(register),
+            Instruction::RegisterLoad(register) => 0xF065 | x(register),
+            Instruction::LoadAudioPattern => 0xF002,
+            Instruction::SetPitch(register) => 0xF03A | x(register),
+            Instruction::UndefinedOperation(opcode) => *opcode,
+        }
+    }
+}
+
 /// Takes a value and returns a range of bytes from that value
 ///
 /// For reference, it shifts the value according to this table
@@ -415,6 +553,7 @@ impl From<u16> for Instruction {
                 let sub_instruction = (0x00FF & instruction) as u8;
                 let register = get_nibble(instruction, 1) as Register;
                 match sub_instruction {
+                    0x02 if register == 0 => Instruction::LoadAudioPattern,
                     0x07 => Instruction::GetDelayTimer(register),
                     0x0A => Instruction::WaitKeyPress(register),
                     0x15 => Instruction::SetDelayTimer(register),
@@ -424,6 +563,7 @@ impl From<u16> for Instruction {
                     0x33 => Instruction::SplitNumber(register),
                     0x55 => Instruction::RegisterDump(register),
                     0x65 => Instruction::RegisterLoad(register),
+                    0x3A => Instruction::SetPitch(register),
                     _ => Instruction::UndefinedOperation(instruction),
                 }
             }
@@ -432,8 +572,8 @@ impl From<u16> for Instruction {
     }
 }
 
-impl std::fmt::Display for Instruction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Instruction::MachineCodeCall(_) => write!(f, ""),
             Instruction::Halt => write!(f, "Halt VM"),
@@ -486,6 +626,8 @@ impl std::fmt::Display for Instruction {
             Instruction::RegisterLoad(end_reg) => {
                 write!(f, "load registers from v0 to v{end_reg:X} starting at I")
             }
+            Instruction::LoadAudioPattern => write!(f, "load audio pattern starting at I"),
+            Instruction::SetPitch(reg) => write!(f, "pitch = v{reg:X}"),
             Instruction::UndefinedOperation(opcode) => write!(f, "Unknown opcode {opcode:X}"),
         }
     }
@@ -533,6 +675,45 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decode_xo_chip_audio() {
+        assert_eq!(Instruction::from(0xF002), Instruction::LoadAudioPattern);
+        assert_eq!(Instruction::from(0xF53A), Instruction::SetPitch(5));
+        assert_eq!(
+            Instruction::from(0xF102),
+            Instruction::UndefinedOperation(0xF102),
+            "F002 doesn't take a register"
+        );
+    }
+
+    #[test]
+    fn skip_conditions() {
+        assert_eq!(
+            Instruction::from(0x4A05).skip_condition(),
+            Some(SkipCondition::Const {
+                register: 0xA,
+                value: 5,
+                equal: false
+            })
+        );
+        assert_eq!(
+            Instruction::from(0x5120).skip_condition(),
+            Some(SkipCondition::Registers {
+                x: 1,
+                y: 2,
+                equal: true
+            })
+        );
+        assert_eq!(
+            Instruction::from(0xE39E).skip_condition(),
+            Some(SkipCondition::Key {
+                register: 3,
+                pressed: true
+            })
+        );
+        assert_eq!(Instruction::from(0x1200).skip_condition(), None);
+    }
+
     /// Single digit hex for each value
     fn test_math_op(
         operation: u8,
@@ -643,4 +824,19 @@ mod tests {
         binary_assert_eq!(get_nibbles(bytes, 1, 3), 0xBCD);
         binary_assert_eq!(get_nibbles(bytes, 2, 2), 0xCD);
     }
+
+    #[test]
+    fn encode_round_trip() {
+        // the decoder ignores the last nibble of 5XY0 and 9XY0, so only check the instruction survives
+        for opcode in 0..=u16::MAX {
+            let instruction = Instruction::from(opcode);
+            assert_eq!(
+                Instruction::from(instruction.opcode()),
+                instruction,
+                "Opcode {opcode:04X} should encode back to the same instruction"
+            );
+        }
+        assert_eq!(Instruction::from(0x8AB6).opcode(), 0x8AB6);
+        assert_eq!(Instruction::from(0xF33A).opcode(), 0xF33A);
+    }
 }
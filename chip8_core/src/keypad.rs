@@ -1,34 +1,14 @@
-// use maplit::hashmap;
-// Maybe don't use this, instead commit to using bevy for everything
-//
-/*
-pub static DEFAULT_CONFIG: Lazy<KeyMap> = Lazy::new(|| {
-    use Key::*;
-    KeyMap {
-        map: hashmap! {
-            Key0 => vec!["0".to_string(), "`".to_string()],
-            Key1 => vec!["1".to_string()],
-            Key2 => vec!["2".to_string()],
-            Key3 => vec!["3".to_string()],
-            Key4 => vec!["4".to_string()],
-            Key5 => vec!["5".to_string()],
-            Key6 => vec!["6".to_string()],
-            Key7 => vec!["7".to_string()],
-            Key8 => vec!["8".to_string()],
-            Key9 => vec!["9".to_string()],
-            KeyA => vec!["q".to_string()],
-            KeyB => vec!["w".to_string()],
-            KeyC => vec!["e".to_string()],
-            KeyD => vec!["r".to_string()],
-            KeyE => vec!["t".to_string()],
-            KeyF => vec!["y".to_string()]
-        },
-        reverse: None,
-    }
-});
-*/
+use alloc::collections::BTreeMap;
+use core::str::FromStr;
+
+use crate::prelude::*;
 
 use enum_primitive_derive::Primitive;
+use num_traits::FromPrimitive;
+use thiserror::Error;
+
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Primitive)]
 #[repr(u8)]
@@ -51,29 +31,27 @@ pub enum Key {
     KeyF = 0xF,
 }
 
-// impl From<u8> for Key {
-//     fn from(value: u8) -> Self {
-//         use Key::*;
-//         match value {
-//             0x0 => Key0,
-//             0x1 => Key1,
-//             0x2 => Key2,
-//             0x3 => Key3,
-//             0x4 => Key4,
-//             0x5 => Key5,
-//             0x6 => Key6,
-//             0x7 => Key7,
-//             0x8 => Key8,
-//             0x9 => Key9,
-//             0xA => KeyA,
-//             0xB => KeyB,
-//             0xC => KeyC,
-//             0xD => KeyD,
-//             0xE => KeyE,
-//             0xF => KeyF,
-//         }
-//     }
-// }
+impl Key {
+    /// Every key, indexed by its value
+    pub const ALL: [Key; 16] = [
+        Key::Key0,
+        Key::Key1,
+        Key::Key2,
+        Key::Key3,
+        Key::Key4,
+        Key::Key5,
+        Key::Key6,
+        Key::Key7,
+        Key::Key8,
+        Key::Key9,
+        Key::KeyA,
+        Key::KeyB,
+        Key::KeyC,
+        Key::KeyD,
+        Key::KeyE,
+        Key::KeyF,
+    ];
+}
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum KeyState {
@@ -120,3 +98,429 @@ impl Keypad {
         old_state != state
     }
 }
+
+/// Progress of the FX0A key wait, driven by key press and release edges
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyWait {
+    #[default]
+    Idle,
+    /// Waiting for any key to go down, keys that were already held don't count
+    Press { register: usize },
+    /// A key went down, waiting for that key to come back up
+    Release { register: usize, key: Key },
+}
+
+impl KeyWait {
+    pub fn is_waiting(&self) -> bool {
+        *self != KeyWait::Idle
+    }
+
+    /// Advances the wait when a key goes down or up
+    /// # Returns
+    /// Returns the register and key to store in it once the wait is over
+    pub fn key_changed(
+        &mut self,
+        key: Key,
+        pressed: bool,
+        on_release: bool,
+    ) -> Option<(usize, Key)> {
+        match (*self, pressed) {
+            (KeyWait::Press { register }, true) if on_release => {
+                *self = KeyWait::Release { register, key };
+                None
+            }
+            (KeyWait::Press { register }, true) => {
+                *self = KeyWait::Idle;
+                Some((register, key))
+            }
+            (
+                KeyWait::Release {
+                    register,
+                    key: held,
+                },
+                false,
+            ) if held == key => {
+                *self = KeyWait::Idle;
+                Some((register, key))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Built in keyboard layouts for [`KeyMap`]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMapPreset {
+    /// The left side of a QWERTY keyboard, as shown in the README
+    ///
+    /// ```text
+    /// 1 2 3 4      1 2 3 C
+    /// Q W E R  ->  4 5 6 D
+    /// A S D F      7 8 9 E
+    /// Z X C V      A 0 B F
+    /// ```
+    Qwerty,
+    /// The same keys as [`KeyMapPreset::Qwerty`] on an AZERTY keyboard,
+    /// where the top row works with or without shift
+    ///
+    /// ```text
+    /// & é " '      1 2 3 C
+    /// A Z E R  ->  4 5 6 D
+    /// Q S D F      7 8 9 E
+    /// W X C V      A 0 B F
+    /// ```
+    Azerty,
+    /// The number pad, arranged so the keys are in the same places as on the CHIP-8 keypad
+    ///
+    /// ```text
+    /// 7 8 9 *          1 2 3 C
+    /// 4 5 6 -      ->  4 5 6 D
+    /// 1 2 3 +          7 8 9 E
+    /// / 0 . Enter      A 0 B F
+    /// ```
+    Numpad,
+    /// Each hex digit key presses the CHIP-8 key with the same label, like the COSMAC VIP's hex keypad
+    Vip,
+}
+
+impl KeyMapPreset {
+    pub const ALL: [KeyMapPreset; 4] = [
+        KeyMapPreset::Qwerty,
+        KeyMapPreset::Azerty,
+        KeyMapPreset::Numpad,
+        KeyMapPreset::Vip,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyMapPreset::Qwerty => "qwerty",
+            KeyMapPreset::Azerty => "azerty",
+            KeyMapPreset::Numpad => "numpad",
+            KeyMapPreset::Vip => "vip",
+        }
+    }
+
+    /// Host keys for each CHIP-8 key
+    #[rustfmt::skip]
+    fn bindings(self) -> Vec<(&'static str, Key)> {
+        use Key::*;
+        match self {
+            KeyMapPreset::Qwerty => vec![
+                ("1", Key1), ("2", Key2), ("3", Key3), ("4", KeyC),
+                ("q", Key4), ("w", Key5), ("e", Key6), ("r", KeyD),
+                ("a", Key7), ("s", Key8), ("d", Key9), ("f", KeyE),
+                ("z", KeyA), ("x", Key0), ("c", KeyB), ("v", KeyF),
+            ],
+            KeyMapPreset::Azerty => vec![
+                ("&", Key1), ("é", Key2), ("\"", Key3), ("'", KeyC),
+                ("1", Key1), ("2", Key2), ("3", Key3), ("4", KeyC),
+                ("a", Key4), ("z", Key5), ("e", Key6), ("r", KeyD),
+                ("q", Key7), ("s", Key8), ("d", Key9), ("f", KeyE),
+                ("w", KeyA), ("x", Key0), ("c", KeyB), ("v", KeyF),
+            ],
+            KeyMapPreset::Numpad => vec![
+                ("7", Key1), ("8", Key2), ("9", Key3), ("*", KeyC),
+                ("4", Key4), ("5", Key5), ("6", Key6), ("-", KeyD),
+                ("1", Key7), ("2", Key8), ("3", Key9), ("+", KeyE),
+                ("/", KeyA), ("0", Key0), (".", KeyB), ("enter", KeyF),
+            ],
+            KeyMapPreset::Vip => vec![
+                ("0", Key0), ("1", Key1), ("2", Key2), ("3", Key3),
+                ("4", Key4), ("5", Key5), ("6", Key6), ("7", Key7),
+                ("8", Key8), ("9", Key9), ("a", KeyA), ("b", KeyB),
+                ("c", KeyC), ("d", KeyD), ("e", KeyE), ("f", KeyF),
+            ],
+        }
+    }
+}
+
+impl FromStr for KeyMapPreset {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|preset| preset.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown keymap preset {name:?}"))
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Invalid keymap on line {line}: {reason}")]
+pub struct ParseKeyMapError {
+    pub line: usize,
+    pub reason: String,
+}
+
+/// Maps host keys to CHIP-8 keys, and back again for showing which host keys to press
+///
+/// Host keys are identified by the lowercase `KeyboardEvent.key` value a browser gives them,
+/// which is the character for printable keys, such as `q` or `&`, or a name such as `enter`.
+/// The space bar is called `space`, since whitespace separates keys in config files.
+/// Each CHIP-8 key can have any number of host keys, but a host key only presses one CHIP-8 key.
+///
+/// With the `serde` feature, a keymap is a table of CHIP-8 keys to their host keys, like the config file,
+/// and it can also be read from the name of a preset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(try_from = "KeyMapConfig", into = "KeyMapConfig")
+)]
+pub struct KeyMap {
+    keys: BTreeMap<String, Key>,
+    /// Host keys for each CHIP-8 key, in the order they were bound
+    hosts: [Vec<String>; 16],
+}
+
+impl KeyMap {
+    /// A keymap without any keys bound
+    pub fn empty() -> Self {
+        Self {
+            keys: BTreeMap::new(),
+            hosts: Default::default(),
+        }
+    }
+
+    fn normalize(host: &str) -> String {
+        match host {
+            " " => "space".to_owned(),
+            host => host.to_lowercase(),
+        }
+    }
+
+    /// Makes `host` press `key`, replacing whatever it pressed before
+    /// # Returns
+    /// Returns the key `host` was previously bound to
+    pub fn bind(&mut self, host: &str, key: Key) -> Option<Key> {
+        let previous = self.unbind(host);
+        let host = Self::normalize(host);
+        self.hosts[key as usize].push(host.clone());
+        self.keys.insert(host, key);
+        previous
+    }
+
+    /// Stops `host` from pressing a key
+    /// # Returns
+    /// Returns the key `host` was bound to
+    pub fn unbind(&mut self, host: &str) -> Option<Key> {
+        let host = Self::normalize(host);
+        let key = self.keys.remove(&host)?;
+        self.hosts[key as usize].retain(|bound| *bound != host);
+        Some(key)
+    }
+
+    /// The CHIP-8 key that `host` presses
+    pub fn get(&self, host: &str) -> Option<Key> {
+        self.keys.get(&Self::normalize(host)).copied()
+    }
+
+    /// Every host key that presses `key`
+    pub fn host_keys(&self, key: Key) -> &[String] {
+        &self.hosts[key as usize]
+    }
+
+    /// The host key to show for `key` in on-screen hints, the first one that was bound
+    pub fn hint(&self, key: Key) -> Option<&str> {
+        self.host_keys(key).first().map(String::as_str)
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMapPreset::Qwerty.into()
+    }
+}
+
+impl From<KeyMapPreset> for KeyMap {
+    fn from(preset: KeyMapPreset) -> Self {
+        let mut keymap = Self::empty();
+        for (host, key) in preset.bindings() {
+            keymap.bind(host, key);
+        }
+        keymap
+    }
+}
+
+/// Formats the keymap as a config file, with a line of host keys for each CHIP-8 key
+impl core::fmt::Display for KeyMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "# CHIP-8 key = host keys, separated by spaces")?;
+        for key in Key::ALL {
+            write!(f, "{:X} =", key as u8)?;
+            for host in self.host_keys(key) {
+                write!(f, " {host}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for KeyMap {
+    type Err = ParseKeyMapError;
+
+    /// Parses a config file written by the `Display` impl.
+    /// Blank lines and lines starting with `#` are ignored, and keys without a line are left unbound.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut keymap = Self::empty();
+        for (index, entry) in text.lines().enumerate() {
+            let error = |reason: String| ParseKeyMapError {
+                line: index + 1,
+                reason,
+            };
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let (key, hosts) = entry
+                .split_once('=')
+                .ok_or_else(|| error("expected <CHIP-8 key> = <host keys>".to_owned()))?;
+            let key = u8::from_str_radix(key.trim(), 16)
+                .ok()
+                .and_then(Key::from_u8)
+                .ok_or_else(|| error(format!("{:?} isn't a CHIP-8 key", key.trim())))?;
+            for host in hosts.split_whitespace() {
+                if let Some(previous) = keymap.bind(host, key) {
+                    return Err(error(format!(
+                        "{host:?} is already bound to {:X}",
+                        previous as u8
+                    )));
+                }
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+/// How a [`KeyMap`] is serialized
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum KeyMapConfig {
+    Preset(KeyMapPreset),
+    Keys(BTreeMap<String, Vec<String>>),
+}
+
+#[cfg(feature = "serde")]
+impl From<KeyMap> for KeyMapConfig {
+    fn from(keymap: KeyMap) -> Self {
+        let keys = Key::ALL
+            .into_iter()
+            .map(|key| (format!("{:X}", key as u8), keymap.host_keys(key).to_vec()))
+            .collect();
+        KeyMapConfig::Keys(keys)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<KeyMapConfig> for KeyMap {
+    type Error = String;
+
+    fn try_from(config: KeyMapConfig) -> Result<Self, Self::Error> {
+        let keys = match config {
+            KeyMapConfig::Preset(preset) => return Ok(preset.into()),
+            KeyMapConfig::Keys(keys) => keys,
+        };
+        let mut keymap = Self::empty();
+        for (key, hosts) in keys {
+            let key = u8::from_str_radix(&key, 16)
+                .ok()
+                .and_then(Key::from_u8)
+                .ok_or_else(|| format!("{key:?} isn't a CHIP-8 key"))?;
+            for host in hosts {
+                if let Some(previous) = keymap.bind(&host, key) {
+                    return Err(format!("{host:?} is already bound to {:X}", previous as u8));
+                }
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_wait_edges() {
+        let mut wait = KeyWait::Press { register: 3 };
+        assert_eq!(wait.key_changed(Key::Key5, false, true), None);
+        assert_eq!(wait.key_changed(Key::Key5, true, true), None);
+        assert_eq!(
+            wait,
+            KeyWait::Release {
+                register: 3,
+                key: Key::Key5
+            }
+        );
+        // other keys don't finish the wait
+        assert_eq!(wait.key_changed(Key::Key6, true, true), None);
+        assert_eq!(wait.key_changed(Key::Key6, false, true), None);
+        assert_eq!(
+            wait.key_changed(Key::Key5, false, true),
+            Some((3, Key::Key5))
+        );
+        assert!(!wait.is_waiting());
+
+        let mut wait = KeyWait::Press { register: 0 };
+        assert_eq!(
+            wait.key_changed(Key::KeyA, true, false),
+            Some((0, Key::KeyA))
+        );
+        assert_eq!(wait, KeyWait::Idle);
+    }
+
+    #[test]
+    fn presets_bind_every_key() {
+        for preset in KeyMapPreset::ALL {
+            let keymap = KeyMap::from(preset);
+            for key in Key::ALL {
+                assert!(
+                    keymap.hint(key).is_some(),
+                    "{preset:?} doesn't bind {key:?}"
+                );
+            }
+            assert_eq!(preset.name().parse(), Ok(preset));
+        }
+    }
+
+    #[test]
+    fn lookups() {
+        let mut keymap = KeyMap::from(KeyMapPreset::Azerty);
+        assert_eq!(keymap.get("Z"), Some(Key::Key5));
+        assert_eq!(keymap.get("é"), Some(Key::Key2));
+        assert_eq!(keymap.host_keys(Key::Key2), ["é", "2"]);
+        assert_eq!(keymap.hint(Key::KeyC), Some("'"));
+
+        assert_eq!(keymap.bind(" ", Key::Key5), None);
+        assert_eq!(keymap.get("space"), Some(Key::Key5));
+        assert_eq!(keymap.bind("z", Key::KeyA), Some(Key::Key5));
+        assert_eq!(keymap.host_keys(Key::Key5), ["space"]);
+        assert_eq!(keymap.unbind("z"), Some(Key::KeyA));
+        assert_eq!(keymap.get("z"), None);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut keymap = KeyMap::from(KeyMapPreset::Numpad);
+        keymap.bind("h", Key::Key0);
+        let config = keymap.to_string();
+        assert!(config.contains("\n0 = 0 h\n"));
+        assert_eq!(config.parse(), Ok(keymap));
+
+        let keymap: KeyMap = "# comment\n\n1 = 1 !\nf = v".parse().unwrap();
+        assert_eq!(keymap.get("!"), Some(Key::Key1));
+        assert_eq!(keymap.get("v"), Some(Key::KeyF));
+        assert_eq!(keymap.hint(Key::Key2), None);
+
+        let error = |text: &str| text.parse::<KeyMap>().unwrap_err().line;
+        assert_eq!(error("1 = 1\nG = g"), 2);
+        assert_eq!(error("1 = 1\n2 = 1"), 2);
+        assert_eq!(error("1 1"), 1);
+    }
+}
@@ -1,21 +1,63 @@
+//! The CHIP-8 virtual machine, along with tools for assembling, testing and presenting programs
+//!
+//! Everything here builds with only `alloc` when the default `std` feature is disabled,
+//! except for the modules and methods that need an operating system, which are marked as such.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+/// The parts of the std prelude that come from `alloc`, for modules that also build without std
+mod prelude {
+    pub use alloc::borrow::ToOwned;
+    pub use alloc::boxed::Box;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec::Vec;
+    pub use alloc::{format, vec};
+}
+
+pub mod asm;
+#[cfg(feature = "std")]
+pub mod audio;
+pub mod blocks;
+pub mod builder;
+#[cfg(feature = "capture")]
+pub mod capture;
+pub mod coverage;
+#[cfg(feature = "std")]
+mod debugger;
+pub mod decode_cache;
 pub mod display;
+pub mod effects;
 pub mod font;
+#[cfg(feature = "std")]
+pub mod frontend;
+pub mod handler;
+pub mod headless;
 pub mod instruction;
 pub mod keypad;
+pub mod movie;
+#[cfg(feature = "serde")]
+pub mod profile;
 pub mod quirks;
+pub mod render;
 pub mod time;
+pub mod transpile;
 
-use byteorder::ByteOrder;
+pub use builder::{BuildError, Chip8Builder};
+use handler::{DrawEvent, HookId, Hooks, MemoryWrite, ResolutionEvent};
 use instruction::Instruction;
-use keypad::{Key, Keypad};
-use rand::Rng;
-use time::Timers;
+use keypad::{Key, KeyWait, Keypad};
+use prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use time::{ClockSource, Timers};
 
 use thiserror::Error;
 
-use num_traits::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum DecodingError {
     #[error("Opcode ({opcode:X?}) doesn't match a known one")]
     InvalidOpcode { opcode: u16 },
@@ -25,14 +67,48 @@ pub enum DecodingError {
     InvalidState { operation: String, reason: String },
 }
 
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    #[error("The load address {address:#X} is outside of memory")]
+    InvalidAddress { address: u16 },
+    #[error("The ROM is {size} bytes, but only {available} bytes fit after {address:#X}")]
+    TooLarge {
+        size: usize,
+        available: usize,
+        address: u16,
+    },
+}
+
+/// The XO-CHIP pitch register value that plays the audio pattern at 4000 bits per second
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// Seeds from the OS when there is one, otherwise with a fixed seed until [`Chip8::seed_rng`] is called
+fn new_rng() -> StdRng {
+    #[cfg(feature = "std")]
+    return StdRng::from_entropy();
+    #[cfg(not(feature = "std"))]
+    return StdRng::seed_from_u64(0);
+}
+
 /// The VM state
+///
+/// This isn't `Send`, since neither hooks nor compiled blocks are.
 pub struct Chip8 {
     //rom: [u8; 0x1000],
     /// Programs are loaded starting at 200
     /// 0x000 to 0x1FF are reserved for CHIP-8 interpreter
     /// Last 352 bytes are reserved for "variables and display refresh"
     /// Thus, programs have 0x200 to 0xE8F
-    pub memory: [u8; 0x1000],
+    ///
+    /// It's 4 KiB unless configured otherwise with [`Chip8Builder::memory_size`].
+    pub memory: Vec<u8>,
+    /// Where the font starts in memory, which FX29 points into
+    pub font_address: u16,
+    /// The font glyphs, kept to reload them on a hard reset
+    font: [u8; font::FONT_SIZE],
+    /// The last ROM loaded, kept to restart it, see [`Chip8::soft_reset`]
+    rom: Vec<u8>,
+    load_address: u16,
     // 16 valid registers, V0 to VF
     pub registers: [u8; 16],
     /// The P register
@@ -41,81 +117,364 @@ pub struct Chip8 {
     /// Increment by 2 per instruction, as instructions are 2 bytes long.
     pub pc: u16,
     pub stack: Vec<u16>,
+    /// The most return addresses the stack holds, further calls are errors. It's unbounded when None
+    pub stack_depth: Option<usize>,
     pub timers: Timers,
+    /// Whether [`Chip8::run_next`] ticks the timers in real time
+    pub clock: ClockSource,
     pub display: display::Display,
     pub keypad: Keypad,
     pub running: bool,
-    pub key_wait_register: Option<usize>,
+    /// Progress of the FX0A instruction, which pauses execution until a key is pressed
+    pub key_wait: KeyWait,
     pub quirks: quirks::QuirkConfig,
+    /// The XO-CHIP audio pattern, played instead of the plain tone once loaded
+    pub audio_pattern: Option<[u8; 16]>,
+    /// The XO-CHIP audio pattern playback pitch
+    pub pitch: u8,
+    /// Per-address execution counts, only tracked when enabled
+    pub coverage: Option<coverage::Coverage>,
+    /// Decoded instructions, only kept when enabled, see [`Chip8::enable_decode_cache`]
+    pub decode_cache: Option<decode_cache::DecodeCache>,
+    /// Compiled code, only kept while the block engine is selected, see [`Chip8::set_engine`]
+    blocks: Option<blocks::BlockCache>,
+    /// Key presses and releases being recorded, see [`Chip8::start_recording`]
+    pub recording: Option<movie::Movie>,
+    /// Random number generator for the CXNN instruction, see [`Chip8::seed_rng`]
+    pub rng: StdRng,
+    /// Callbacks subscribed with the `on_*` methods
+    hooks: Hooks,
 }
 
 impl Default for Chip8 {
     fn default() -> Self {
-        Self {
-            memory: [0_u8; 0x1000],
-            registers: [0_u8; 16],
-            pointer: 0,
-            pc: 200,
-            stack: Default::default(),
-            timers: Default::default(),
-            display: Default::default(),
-            keypad: Default::default(),
-            running: true,
-            key_wait_register: None,
-            quirks: Default::default(),
-        }
+        Chip8Builder::new()
+            .build()
+            .expect("The default configuration is valid")
     }
 }
 
 impl Chip8 {
+    /// A VM with the font loaded, ready for a ROM at 0x200, see [`Chip8Builder`] to configure it
     pub fn new() -> Self {
-        let mut chip8 = Chip8 {
-            // rom: [0u8; 0x1000],
-            memory: [0u8; 0x1000],
-            registers: [0_u8; 16],
-            pointer: 0,
-            pc: 0x200,
-            stack: vec![0u16; 0],
-            timers: Timers::new(),
-            display: display::Display::default(),
-            keypad: Keypad::default(),
-            running: true,
-            key_wait_register: None,
-            quirks: Default::default(),
-        };
-        font::load_font(&mut chip8.memory);
-        chip8
+        Self::default()
     }
 
-    pub fn reset(&mut self) {
-        self.memory = [0u8; 0x1000];
+    /// Restarts the loaded ROM, like the reset button on a real machine
+    ///
+    /// The CPU, stack, display and timers are cleared and the ROM and font are written again,
+    /// undoing any changes the program made to its own code. The rest of memory is left as it was.
+    pub fn soft_reset(&mut self) {
+        let rom = core::mem::take(&mut self.rom);
+        self.load_rom(&rom, self.load_address)
+            .expect("The ROM fit when it was loaded");
+        self.rom = rom;
+        let font = self.font;
+        self.write_memory(self.font_address, &font)
+            .expect("The font fit when the VM was built");
         self.registers = Default::default();
         self.pointer = 0;
-        self.pc = 0x200;
+        self.stack.clear();
         self.timers = Default::default();
-        self.display = Default::default();
+        // back to low resolution, telling the frontend if it changed
+        self.set_resolution(64, 32);
+        self.display.clear();
+        self.key_wait = KeyWait::Idle;
+        self.running = true;
+        self.audio_pattern = None;
+        self.pitch = DEFAULT_PITCH;
+    }
+
+    /// Wipes memory and every other part of the VM, forgetting the ROM but reloading the font
+    ///
+    /// The configuration is kept, including the quirks, memory size, engine and hooks.
+    pub fn hard_reset(&mut self) {
+        self.memory.fill(0);
+        self.rom.clear();
         self.keypad = Default::default();
-        self.key_wait_register = None;
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.clear();
+        }
+        self.soft_reset();
+    }
+
+    /// The ROM last loaded with [`Chip8::load_rom`], empty after a hard reset
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// Where the ROM was loaded and where execution restarts after a reset
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    /// Copies a ROM into memory at `address` and starts executing from there
+    ///
+    /// The ROM is remembered so [`Chip8::soft_reset`] can restart it.
+    pub fn load_rom(&mut self, rom: &[u8], address: u16) -> Result<(), LoadError> {
+        self.write_memory(address, rom)?;
+        self.pc = address;
+        self.rom = rom.to_vec();
+        self.load_address = address;
+        Ok(())
+    }
+
+    /// Copies `bytes` into memory at `address`, such as to poke a value from a debugger
+    ///
+    /// Use this rather than writing to [`Chip8::memory`] directly while the decode cache is enabled,
+    /// otherwise the old instructions keep running.
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) -> Result<(), LoadError> {
+        let start = address as usize;
+        if start >= self.memory.len() {
+            return Err(LoadError::InvalidAddress { address });
+        }
+        let available = self.memory.len() - start;
+        if bytes.len() > available {
+            return Err(LoadError::TooLarge {
+                size: bytes.len(),
+                available,
+                address,
+            });
+        }
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        if let Some(cache) = &mut self.decode_cache {
+            cache.invalidate(start..start + bytes.len());
+        }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.invalidate(start..start + bytes.len());
+        }
+        Ok(())
+    }
+
+    /// Makes random numbers deterministic, so runs can be reproduced
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Starts caching decoded instructions, which speeds up loops
+    ///
+    /// The cache is kept up to date with writes from instructions, [`Chip8::load_rom`]
+    /// and [`Chip8::write_memory`], but not with writes straight to [`Chip8::memory`].
+    pub fn enable_decode_cache(&mut self) {
+        self.decode_cache = Some(decode_cache::DecodeCache::new(self.memory.len()));
+    }
+
+    /// Stops caching decoded instructions, going back to decoding each one as it runs
+    pub fn disable_decode_cache(&mut self) {
+        self.decode_cache = None;
+    }
+
+    /// Picks how [`Chip8::run_frame`] executes instructions, see [`blocks`] for the block engine
+    pub fn set_engine(&mut self, engine: blocks::Engine) {
+        if engine == self.engine() {
+            return;
+        }
+        self.blocks = match engine {
+            blocks::Engine::Interpreter => None,
+            blocks::Engine::Blocks => Some(blocks::BlockCache::new(self.memory.len())),
+        };
+    }
+
+    pub fn engine(&self) -> blocks::Engine {
+        match self.blocks {
+            Some(_) => blocks::Engine::Blocks,
+            None => blocks::Engine::Interpreter,
+        }
+    }
+
+    /// The code compiled by the block engine, if it's selected
+    pub fn compiled_blocks(&self) -> Option<&blocks::BlockCache> {
+        self.blocks.as_ref()
+    }
+
+    /// Starts tracking code coverage, discarding any previous results
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(coverage::Coverage::new());
+    }
+
+    /// Stops tracking code coverage and returns the results
+    pub fn take_coverage(&mut self) -> Option<coverage::Coverage> {
+        self.coverage.take()
+    }
+
+    /// Starts recording key presses and releases to a movie, discarding any previous recording
+    ///
+    /// The random number generator is reseeded so the movie can be replayed exactly,
+    /// with a random seed if none is given. The current quirks are saved with the movie.
+    pub fn start_recording(&mut self, seed: Option<u64>) {
+        let seed = seed.unwrap_or_else(|| self.rng.gen());
+        self.seed_rng(seed);
+        self.recording = Some(movie::Movie::new(seed, self.quirks.clone()));
+    }
+
+    /// Stops recording and returns the movie
+    pub fn take_recording(&mut self) -> Option<movie::Movie> {
+        self.recording.take()
+    }
+
+    /// The area of the display that changed since [`Chip8::reset_dirty`] was last called
+    pub fn dirty_region(&self) -> Option<display::DirtyRegion> {
+        self.display.dirty_region()
+    }
+
+    /// Marks the display as fully redrawn by the frontend
+    pub fn reset_dirty(&mut self) {
+        self.display.reset_dirty();
+    }
+
+    /// Replaces the display with a blank one of a different size
+    pub fn set_resolution(&mut self, width: usize, height: usize) {
+        if (width, height) == (self.display.get_width(), self.display.get_height()) {
+            return;
+        }
+        self.display = display::Display::new(width, height);
+        self.hooks
+            .resolution_change
+            .emit(&ResolutionEvent { width, height });
+    }
+
+    /// Calls `callback` after every sprite is drawn
+    pub fn on_draw(&mut self, callback: impl FnMut(&DrawEvent) + 'static) -> HookId {
+        self.hooks.add(|hooks| &mut hooks.draw, Box::new(callback))
+    }
+
+    /// Calls `callback` when the program clears the screen
+    pub fn on_clear(&mut self, mut callback: impl FnMut() + 'static) -> HookId {
+        self.hooks
+            .add(|hooks| &mut hooks.clear, Box::new(move |_| callback()))
+    }
+
+    /// Calls `callback` when the sound timer is set while it's zero
+    pub fn on_sound_start(&mut self, mut callback: impl FnMut() + 'static) -> HookId {
+        self.hooks.add(
+            |hooks| &mut hooks.sound_start,
+            Box::new(move |_| callback()),
+        )
+    }
+
+    /// Calls `callback` when the sound timer reaches zero
+    pub fn on_sound_stop(&mut self, mut callback: impl FnMut() + 'static) -> HookId {
+        self.hooks
+            .add(|hooks| &mut hooks.sound_stop, Box::new(move |_| callback()))
+    }
+
+    /// Calls `callback` with the destination register when FX0A starts waiting for a key
+    pub fn on_key_wait(&mut self, callback: impl FnMut(&usize) + 'static) -> HookId {
+        self.hooks
+            .add(|hooks| &mut hooks.key_wait, Box::new(callback))
+    }
+
+    /// Calls `callback` when an instruction stops the VM, whether it halted or hit an error
+    pub fn on_halt(&mut self, mut callback: impl FnMut() + 'static) -> HookId {
+        self.hooks
+            .add(|hooks| &mut hooks.halt, Box::new(move |_| callback()))
+    }
+
+    /// Calls `callback` when an instruction fails, before the error is returned
+    pub fn on_error(&mut self, callback: impl FnMut(&DecodingError) + 'static) -> HookId {
+        self.hooks.add(|hooks| &mut hooks.error, Box::new(callback))
+    }
+
+    /// Calls `callback` when [`Chip8::set_resolution`] changes the display size
+    pub fn on_resolution_change(
+        &mut self,
+        callback: impl FnMut(&ResolutionEvent) + 'static,
+    ) -> HookId {
+        self.hooks
+            .add(|hooks| &mut hooks.resolution_change, Box::new(callback))
+    }
+
+    /// Calls `callback` when an instruction writes to any address in `range`
+    pub fn on_memory_write(
+        &mut self,
+        range: core::ops::Range<usize>,
+        mut callback: impl FnMut(&MemoryWrite) + 'static,
+    ) -> HookId {
+        let filtered = move |write: &MemoryWrite| {
+            let written = write.range();
+            if written.start < range.end && range.start < written.end {
+                callback(write);
+            }
+        };
+        self.hooks
+            .add(|hooks| &mut hooks.memory_write, Box::new(filtered))
+    }
+
+    /// Unsubscribes a callback added with one of the `on_*` methods
+    /// # Returns
+    /// Returns false if the callback was already removed
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.hooks.remove(id)
+    }
+
+    /// Invalidates cached and compiled code and calls the memory write hooks with the bytes just written
+    fn memory_written(&mut self, address: u16, len: usize) {
+        let range = address as usize..address as usize + len;
+        if let Some(cache) = &mut self.decode_cache {
+            cache.invalidate(range.clone());
+        }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.self_modified(range);
+        }
+        if self.hooks.memory_write.is_empty() {
+            return;
+        }
+        let start = address as usize;
+        let bytes = self.memory[start..start + len].to_vec();
+        self.hooks
+            .memory_write
+            .emit(&MemoryWrite { address, bytes });
+    }
+
+    fn sound_changed(&mut self, was_on: bool) {
+        match (was_on, self.timers.is_sound_on()) {
+            (false, true) => self.hooks.sound_start.emit(&()),
+            (true, false) => self.hooks.sound_stop.emit(&()),
+            _ => {}
+        }
+    }
+
+    /// Whether a call would go over [`Chip8::stack_depth`]
+    pub fn is_stack_full(&self) -> bool {
+        self.stack_depth
+            .is_some_and(|depth| self.stack.len() >= depth)
     }
 
     pub fn is_key_waiting(&self) -> bool {
-        self.key_wait_register.is_some()
+        self.key_wait.is_waiting()
     }
 
     pub fn press_key(&mut self, key: Key) {
-        self.keypad.set_key(key, keypad::KeyState::Pressed);
-        if let Some(wait_register) = self.key_wait_register {
-            let key_val = key
-                .to_u8()
-                .expect("This shouldn't fail, it's an enum with a u8 value");
-            log::info!("Key wait done with key: {:X}", key_val);
-            self.registers[wait_register] = key_val;
-            self.key_wait_register = None;
-        }
+        self.set_key(key, true);
     }
 
     pub fn release_key(&mut self, key: Key) {
-        self.keypad.set_key(key, keypad::KeyState::NotPressed);
+        self.set_key(key, false);
+    }
+
+    /// Updates the keypad, passing the press or release on to the key wait if the key changed
+    fn set_key(&mut self, key: Key, pressed: bool) {
+        if let Some(movie) = &mut self.recording {
+            movie.record(key, pressed);
+        }
+        let state = if pressed {
+            keypad::KeyState::Pressed
+        } else {
+            keypad::KeyState::NotPressed
+        };
+        if !self.keypad.set_key(key, state) {
+            return;
+        }
+        let done = self
+            .key_wait
+            .key_changed(key, pressed, self.quirks.key_wait_release);
+        if let Some((register, key)) = done {
+            log::info!("Key wait done with key: {:X}", key as u8);
+            self.registers[register] = key as u8;
+        }
     }
 
     fn registers_to_string(&self) -> String {
@@ -134,7 +493,10 @@ impl Chip8 {
         match instruction {
             Instruction::MachineCodeCall(opcode) => unimplemented!("Machine Code {:X}", opcode),
             Instruction::Halt => self.running = false,
-            Instruction::ClearDisplay => self.display.clear(),
+            Instruction::ClearDisplay => {
+                self.display.clear();
+                self.hooks.clear.emit(&());
+            }
             Instruction::Return => {
                 if self.stack.is_empty() {
                     self.running = false;
@@ -151,6 +513,13 @@ impl Chip8 {
             }
             Instruction::Goto { address } => self.pc = address,
             Instruction::Call { address } => {
+                if self.is_stack_full() {
+                    self.running = false;
+                    return Err(DecodingError::InvalidState {
+                        operation: "Call".to_owned(),
+                        reason: "the call stack is full".to_owned(),
+                    });
+                }
                 self.stack.push(self.pc);
                 self.pc = address;
             }
@@ -204,20 +573,29 @@ impl Chip8 {
                 self.pc = offset + self.registers[register] as u16;
             }
             Instruction::Random { register, mask } => {
-                let rand: u8 = rand::thread_rng().gen();
+                let rand: u8 = self.rng.gen();
                 self.registers[register as usize] = rand & mask
             }
             Instruction::Draw { position, height } => {
                 let mem_start = self.pointer as usize;
-                // 8 bytes per row
-                let mem_end = self.pointer as usize + (8 * height as usize);
-                self.display.draw_sprite(
-                    self.registers[position.0 as usize],
-                    self.registers[position.1 as usize],
+                // 1 byte per row
+                let mem_end = (mem_start + height as usize).min(self.memory.len());
+                let x = self.registers[position.0 as usize];
+                let y = self.registers[position.1 as usize];
+                let collision = self.display.draw_sprite(
+                    x,
+                    y,
                     height,
                     &self.memory[mem_start..mem_end],
                     self.quirks.partial_wrap,
                 );
+                self.set_carry(collision);
+                self.hooks.draw.emit(&DrawEvent {
+                    x,
+                    y,
+                    height,
+                    collision,
+                });
             }
             Instruction::KeyPressed(register) => {
                 let key = self.registers[register as usize];
@@ -229,7 +607,7 @@ impl Chip8 {
             }
             Instruction::KeyNotPressed(register) => {
                 let key = self.registers[register as usize];
-                println!("Is key {:x} (from register {}) pressed?", key, register);
+                log::trace!("Is key {:x} (from register {}) pressed?", key, register);
                 if !self.keypad.is_key_pressed(
                     Key::from_u8(key).expect("Register contains value not in keypad range (0-15)"),
                 ) {
@@ -241,8 +619,10 @@ impl Chip8 {
             }
             Instruction::WaitKeyPress(register) => {
                 log::info!("Waiting on register {:X}", register);
-                self.key_wait_register = Some(register as usize);
-                // probably should have a callback for this
+                self.key_wait = KeyWait::Press {
+                    register: register as usize,
+                };
+                self.hooks.key_wait.emit(&(register as usize));
             }
             Instruction::SetDelayTimer(register) => {
                 self.timers.delay = self.registers[register as usize].into();
@@ -254,7 +634,8 @@ impl Chip8 {
                 self.pointer += self.registers[register as usize] as u16;
             }
             Instruction::SetPointerToLetter(register) => {
-                self.pointer = font::get_letter_address(self.registers[register as usize])
+                self.pointer =
+                    self.font_address + font::get_letter_address(self.registers[register as usize])
             }
             Instruction::SplitNumber(register) => {
                 let value = self.registers[register as usize];
@@ -267,13 +648,15 @@ impl Chip8 {
                 self.memory[self.pointer as usize] = digits[0];
                 self.memory[self.pointer as usize + 1] = digits[1];
                 self.memory[self.pointer as usize + 2] = digits[2];
+                self.memory_written(self.pointer, 3);
             }
             Instruction::RegisterDump(register) => {
                 for i in 0..=register as usize {
                     self.memory[self.pointer as usize + i] = self.registers[i]
                 }
+                self.memory_written(self.pointer, register as usize + 1);
                 if self.quirks.save_load_set_pointer {
-                    self.pointer += (register as u16) + 1;
+                    self.pointer = self.pointer.wrapping_add(register as u16 + 1);
                 }
             }
             Instruction::RegisterLoad(register) => {
@@ -281,9 +664,19 @@ impl Chip8 {
                     self.registers[i] = self.memory[self.pointer as usize + i];
                 }
                 if self.quirks.save_load_set_pointer {
-                    self.pointer += (register as u16) + 1;
+                    self.pointer = self.pointer.wrapping_add(register as u16 + 1);
                 }
             }
+            Instruction::LoadAudioPattern => {
+                // a pattern at the end of memory wraps around to the start, like addresses do on XO-CHIP
+                let start = self.pointer as usize;
+                let pattern =
+                    core::array::from_fn(|i| self.memory[(start + i) % self.memory.len()]);
+                self.audio_pattern = Some(pattern);
+            }
+            Instruction::SetPitch(register) => {
+                self.pitch = self.registers[register as usize];
+            }
             Instruction::UndefinedOperation(opcode) => {
                 return Err(DecodingError::InvalidOpcode { opcode });
             }
@@ -395,117 +788,147 @@ impl Chip8 {
 
     /// Gets a u16 from the two u8s at index and the following item
     pub fn get_u16(&self, index: usize) -> u16 {
-        byteorder::BE::read_u16(&self.memory[index..=(index + 1)])
+        u16::from_be_bytes([self.memory[index], self.memory[index + 1]])
     }
 
-    fn get_instruction_at_pc(&self) -> Instruction {
+    fn get_instruction_at_pc(&mut self) -> Instruction {
+        if let Some(cache) = &mut self.decode_cache {
+            return cache.get(&self.memory, self.pc);
+        }
         let instruction_data: u16 = self.get_u16(self.pc as usize);
         // println!("Instruction: {:#x}", instruction_data);
         instruction_data.into()
     }
 
+    /// Runs the instruction at the program counter, after ticking the timers for the real time since the last call
+    /// if [`Chip8::clock`] is the wall clock
+    #[cfg(feature = "std")]
     pub fn run_next(&mut self) -> Result<(), DecodingError> {
-        self.timers.do_ticks();
-        if !self.is_key_waiting() {
-            let instruction = self.get_instruction_at_pc();
-            self.next_instruction();
-            self.handle_instruction(instruction)?;
+        if self.clock == ClockSource::WallClock {
+            let sound_was_on = self.timers.is_sound_on();
+            self.timers.do_ticks();
+            self.sound_changed(sound_was_on);
         }
+        self.step()?;
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), DecodingError> {
-        let mut skip_debug = false;
-        while self.running {
-            self.run_next()?;
-            println!();
-            println!("{}", self.display);
-            println!("Instruction: {:X?}", self.get_instruction_at_pc());
-
-            if self.is_key_waiting() {
-                loop {
-                    print!("Enter key: ");
-                    let mut key = String::new();
-                    std::io::stdin().read_line(&mut key).unwrap();
-                    if let Ok(val) = u8::from_str_radix(key.trim(), 16) {
-                        if val <= 0xf {
-                            self.registers[self.key_wait_register.unwrap()] = val;
-                            self.key_wait_register = None;
-                            break;
-                        }
-                    }
-                    print!("Error parsing, is this a single hex character?\nTry again: ");
-                }
-            }
+    /// Executes the instruction at the program counter without ticking the timers
+    /// # Returns
+    /// Returns the executed instruction, or None if the VM is waiting for a key press
+    pub fn step(&mut self) -> Result<Option<Instruction>, DecodingError> {
+        if self.is_key_waiting() {
+            return Ok(None);
+        }
+        let address = self.pc;
+        let instruction = self.get_instruction_at_pc();
+        let was_running = self.running;
+        let sound_was_on = self.timers.is_sound_on();
+        self.next_instruction();
+        let result = self.handle_instruction(instruction);
+        if let Err(error) = &result {
+            self.hooks.error.emit(error);
+        }
+        if was_running && !self.running {
+            self.hooks.halt.emit(&());
+        }
+        result?;
+        self.sound_changed(sound_was_on);
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(address, instruction, self.pc);
+        }
+        Ok(Some(instruction))
+    }
 
-            let mut user_input = String::new();
-            loop {
-                if skip_debug {
-                    break;
-                }
-                std::io::stdin()
-                    .read_line(&mut user_input)
-                    .expect("Error reading from stdin");
-
-                if user_input.starts_with("instruction") {
-                    let suffix = user_input.split_once("instruction").unwrap().1.trim();
-                    let mut address = self.pc;
-                    if suffix.is_empty() {
-                        // print current pc instruction
-                        address = self.pc;
-                    } else if suffix.starts_with('-') {
-                        let offset =
-                            u16::from_str_radix(suffix.split_once('-').unwrap().1, 16).unwrap();
-                        address -= 2 * offset;
-                    } else if suffix.starts_with('+') {
-                        let offset =
-                            u16::from_str_radix(suffix.split_once('-').unwrap().1, 16).unwrap();
-                        address += 2 * offset;
-                    }
-                    user_input = format!("memory {:#x}", address);
-                }
+    /// Runs a single 60 Hz frame independently of wall clock time
+    ///
+    /// Executes up to `cycles` instructions, then ticks the timers once.
+    /// The frame ends early if the VM halts, or after a draw when the `display_wait` quirk is set.
+    /// This uses the block engine if it's selected, unless code coverage is being tracked.
+    pub fn run_frame(&mut self, cycles: usize) -> Result<(), DecodingError> {
+        self.run_frame_until(cycles, |_| false)?;
+        Ok(())
+    }
 
-                if user_input.starts_with("registers") {
-                    println!("Registers: \n{}", self.registers_to_string())
-                } else if user_input.starts_with("pc") {
-                    println!("Program counter: {:#6X}", self.pc);
-                } else if user_input.starts_with("memory 0x") {
-                    let hex_str = user_input.split("0x").nth(1).unwrap().trim();
-                    let address = usize::from_str_radix(hex_str, 16).unwrap();
-                    let mem = &self.memory[address..address + 2];
-
-                    println!(
-                        "Memory at address: {:#X}: {:#X}",
-                        address,
-                        byteorder::BigEndian::read_u16(mem)
-                    );
-                } else if user_input.starts_with("set 0x") {
-                    let raw_input = user_input.split_once("0x").unwrap().1;
-                    let (address_str, val_str) = raw_input.split_once("0x").unwrap();
-                    let address =
-                        usize::from_str_radix(address_str.trim(), 16).expect("Invalid usize hex");
-                    let val = u8::from_str_radix(val_str.trim(), 16).expect("Invalid u8 hex");
-                    self.memory[address] = val;
-                    println!("Set address {:X} to {:X}", address, val);
-                } else if user_input.starts_with("skip") {
-                    skip_debug = true;
-                    println!("Skipping debug until halt");
-                    break;
-                } else if user_input.starts_with("pointer") {
-                    println!("Pointer: {:X}", self.pointer);
-                } else {
-                    break;
+    /// Runs a single 60 Hz frame like [`Chip8::run_frame`], with compiled code where there is some
+    ///
+    /// Before each instruction, `native` gets the number of instructions left in the frame.
+    /// It either runs compiled code for the program counter without going over that, returning how
+    /// many instructions it ran, or returns 0 to have the interpreter run the next instruction.
+    /// Compiled code isn't tracked by code coverage.
+    pub fn run_frame_with(
+        &mut self,
+        cycles: usize,
+        native: impl FnMut(&mut Chip8, usize) -> usize,
+    ) -> Result<(), DecodingError> {
+        self.run_frame_checked(cycles, native, |_| false)?;
+        Ok(())
+    }
+
+    /// Runs a single 60 Hz frame like [`Chip8::run_frame`], checking `stop` after every instruction
+    ///
+    /// With the block engine, `stop` is checked after every block instead,
+    /// so it can miss states that only last until the end of a block.
+    /// # Returns
+    /// Returns true if `stop` ended the frame early, in which case the timers aren't ticked
+    pub fn run_frame_until(
+        &mut self,
+        cycles: usize,
+        stop: impl FnMut(&Chip8) -> bool,
+    ) -> Result<bool, DecodingError> {
+        if self.blocks.is_some() && self.coverage.is_none() {
+            self.run_frame_checked(cycles, Chip8::run_block, stop)
+        } else {
+            self.run_frame_checked(cycles, |_, _| 0, stop)
+        }
+    }
+
+    fn run_frame_checked(
+        &mut self,
+        cycles: usize,
+        mut native: impl FnMut(&mut Chip8, usize) -> usize,
+        mut stop: impl FnMut(&Chip8) -> bool,
+    ) -> Result<bool, DecodingError> {
+        let mut remaining = cycles;
+        while remaining > 0 && self.running && !self.is_key_waiting() {
+            let ran = native(self, remaining);
+            if ran > 0 {
+                remaining -= ran;
+                if stop(self) {
+                    return Ok(true);
                 }
-                user_input.clear();
+                continue;
+            }
+            let instruction = self.step()?;
+            remaining -= 1;
+            if stop(self) {
+                return Ok(true);
+            }
+            if self.quirks.display_wait && matches!(instruction, Some(Instruction::Draw { .. })) {
+                break;
             }
         }
-        println!("Halted");
-        Ok(())
+        self.end_frame(cycles);
+        Ok(false)
+    }
+
+    /// Ticks the timers once the instructions for a frame have run
+    fn end_frame(&mut self, cycles: usize) {
+        let sound_was_on = self.timers.is_sound_on();
+        if self.quirks.key_wait_beep && matches!(self.key_wait, KeyWait::Release { .. }) {
+            // keep the tone on through the tick, it stops a frame after the key is released
+            self.timers.sound = self.timers.sound.max(2);
+        }
+        self.timers.tick();
+        self.sound_changed(sound_was_on);
+        if let Some(movie) = &mut self.recording {
+            movie.end_frame(cycles);
+        }
     }
 }
 
-impl std::fmt::Display for Chip8 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Chip8 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         writeln!(
             f,
             "Chip8 internals:
@@ -520,10 +943,11 @@ impl std::fmt::Display for Chip8 {
 
 #[cfg(test)]
 mod tests {
-    // TODO: Fix math tests, since I fixed subtle bugs in implementation
     // Should I move these tests to the integration tests?
     // use super::*;
 
+    // run_next needs std
+    #[cfg(feature = "std")]
     mod math_operations {
         use super::super::*;
 
@@ -535,13 +959,12 @@ mod tests {
         }
 
         fn init_vm(opcode: u16) -> Chip8 {
-            let mut vm = Chip8::new();
-            // program counter starts at 0, so this'll be the first instruction
-            let opcode_bytes = opcode.to_be_bytes();
-            vm.memory[0] = opcode_bytes[0];
-            vm.memory[1] = opcode_bytes[1];
-
-            vm
+            // the program counter starts at the ROM, so this'll be the first instruction
+            Chip8Builder::new()
+                .clock(ClockSource::Frames)
+                .rom(&opcode.to_be_bytes())
+                .build()
+                .expect("A single instruction fits in memory")
         }
 
         #[test]
@@ -657,7 +1080,8 @@ mod tests {
             vm.run_next().expect("Decoding error on test instruction");
 
             assert_eq!(vm.registers[1], 54_u8.wrapping_sub(64));
-            assert!(vm.get_carry())
+            // VF is cleared when the subtraction borrows
+            assert!(!vm.get_carry())
         }
 
         #[test]
@@ -665,7 +1089,8 @@ mod tests {
             // Test right bitshift: Store least signifigant bit in VF, then shift V1 to the right 1
             let mut vm = init_vm(0x8126);
 
-            // source register is ignored
+            // source register is ignored with the SUPER-CHIP shift
+            vm.quirks.alt_shift = true;
             vm.registers[1] = 0b1011;
 
             vm.run_next().expect("Decoding error on test instruction");
@@ -688,7 +1113,8 @@ mod tests {
             // Test left bitshift: Store most signifigant bit in VF, then shift V1 to the left 1
             let mut vm = init_vm(0x812E);
 
-            // source register is ignored
+            // source register is ignored with the SUPER-CHIP shift
+            vm.quirks.alt_shift = true;
             vm.registers[1] = 0b1101_1011;
 
             vm.run_next().expect("Decoding error on test instruction");
@@ -724,4 +1150,172 @@ mod tests {
         #[test]
         fn unknown_operation() {}
     }
+
+    mod loading {
+        use super::super::*;
+
+        #[test]
+        fn load_rom() {
+            let mut vm = Chip8::new();
+            vm.load_rom(&[0x12, 0x34], 0x300).unwrap();
+            assert_eq!(vm.pc, 0x300);
+            assert_eq!(vm.get_u16(0x300), 0x1234);
+
+            assert_eq!(
+                vm.load_rom(&[0; 0x10], 0xFF8),
+                Err(LoadError::TooLarge {
+                    size: 0x10,
+                    available: 8,
+                    address: 0xFF8
+                })
+            );
+            assert_eq!(
+                vm.load_rom(&[], 0x1000),
+                Err(LoadError::InvalidAddress { address: 0x1000 })
+            );
+        }
+
+        #[test]
+        fn seeded_random() {
+            let run = || {
+                let mut vm = Chip8::new();
+                vm.seed_rng(1234);
+                vm.load_rom(&[0xC0, 0xFF, 0xC1, 0xFF], 0x200).unwrap();
+                vm.step().unwrap();
+                vm.step().unwrap();
+                (vm.registers[0], vm.registers[1])
+            };
+            assert_eq!(run(), run());
+        }
+    }
+
+    mod key_wait {
+        use super::super::*;
+
+        fn waiting_vm(preset: quirks::QuirkPresets) -> Chip8 {
+            let mut vm = Chip8::new();
+            vm.quirks.use_preset(preset);
+            // wait for a key into V3, then loop
+            vm.load_rom(&[0xF3, 0x0A, 0x12, 0x02], 0x200).unwrap();
+            vm.step().unwrap();
+            vm
+        }
+
+        #[test]
+        fn finishes_on_release() {
+            let mut vm = waiting_vm(quirks::QuirkPresets::Chip8);
+            vm.press_key(Key::Key5);
+            vm.run_frame(10).unwrap();
+            assert!(vm.is_key_waiting());
+            assert!(
+                vm.timers.is_sound_on(),
+                "The VIP beeps while the key is held"
+            );
+
+            vm.release_key(Key::Key5);
+            assert!(!vm.is_key_waiting());
+            assert_eq!(vm.registers[3], 5);
+            vm.run_frame(10).unwrap();
+            assert!(!vm.timers.is_sound_on());
+        }
+
+        #[test]
+        fn beep_calls_the_sound_hooks() {
+            use std::cell::Cell;
+            use std::rc::Rc;
+
+            let mut vm = waiting_vm(quirks::QuirkPresets::Chip8);
+            let (starts, stops) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+            vm.on_sound_start({
+                let starts = starts.clone();
+                move || starts.set(starts.get() + 1)
+            });
+            vm.on_sound_stop({
+                let stops = stops.clone();
+                move || stops.set(stops.get() + 1)
+            });
+
+            vm.press_key(Key::Key5);
+            vm.run_frame(10).unwrap();
+            vm.run_frame(10).unwrap();
+            assert_eq!((starts.get(), stops.get()), (1, 0));
+
+            vm.release_key(Key::Key5);
+            vm.run_frame(10).unwrap();
+            assert_eq!((starts.get(), stops.get()), (1, 1));
+        }
+
+        #[test]
+        fn finishes_on_press() {
+            let mut vm = waiting_vm(quirks::QuirkPresets::SuperChip);
+            vm.press_key(Key::KeyA);
+            assert!(!vm.is_key_waiting());
+            assert_eq!(vm.registers[3], 0xA);
+        }
+
+        #[test]
+        fn ignores_held_keys() {
+            let mut vm = Chip8::new();
+            vm.load_rom(&[0xF3, 0x0A], 0x200).unwrap();
+            vm.press_key(Key::Key1);
+            vm.step().unwrap();
+            vm.press_key(Key::Key1);
+            vm.release_key(Key::Key1);
+            assert!(vm.is_key_waiting(), "Keys held before the wait don't count");
+            vm.press_key(Key::Key2);
+            vm.release_key(Key::Key2);
+            assert_eq!(vm.registers[3], 2);
+        }
+    }
+
+    mod reset {
+        use super::super::*;
+
+        #[rustfmt::skip]
+        const ROM: [u8; 12] = [
+            0xA2, 0x00, // 200: I = 0x200
+            0x60, 0xAB, // 202: V0 = 0xAB
+            0xF0, 0x55, // 204: store V0 over the first instruction
+            0xA3, 0x00, // 206: I = 0x300
+            0xF0, 0x55, // 208: store V0 at 0x300, outside the ROM
+            0x22, 0x0C, // 20A: call 0x20C, which halts on the empty memory after the ROM
+        ];
+
+        fn run_rom() -> Chip8 {
+            let mut vm = Chip8::new();
+            vm.load_rom(&ROM, 0x200).unwrap();
+            vm.memory[0x300] = 0xFF;
+            vm.memory[0x10] = 0;
+            for _ in 0..7 {
+                vm.step().unwrap();
+            }
+            assert!(!vm.running);
+            vm
+        }
+
+        #[test]
+        fn soft_reset_restarts_the_rom() {
+            let mut vm = run_rom();
+            vm.soft_reset();
+            assert!(vm.running);
+            assert_eq!((vm.pc, vm.pointer, vm.stack.len()), (0x200, 0, 0));
+            assert_eq!(vm.memory[0x200..0x20C], ROM);
+            assert_eq!(vm.memory[0x10], font::letters::LETTER_3[1]);
+            assert_eq!(vm.memory[0x300], 0xAB, "Memory outside the ROM is kept");
+
+            vm.step().unwrap();
+            assert_eq!(vm.pointer, 0x200);
+        }
+
+        #[test]
+        fn hard_reset_wipes_everything() {
+            let mut vm = run_rom();
+            vm.hard_reset();
+            assert!(vm.rom().is_empty());
+            assert_eq!(vm.pc, vm.load_address());
+            assert_eq!(vm.memory[..5], font::letters::LETTER_0);
+            assert_eq!(vm.memory[0x300], 0);
+            assert!(vm.memory[font::FONT_SIZE..].iter().all(|&byte| byte == 0));
+        }
+    }
 }
@@ -0,0 +1,361 @@
+//! Recording key presses to movie files that replay deterministically
+//!
+//! A movie stores the random seed, the quirks and the number of instructions per frame,
+//! along with every key press and release tagged with the frame it happened before, in frame order.
+//! Replaying it against the same ROM reproduces every frame exactly, which makes movies
+//! useful for attaching to bug reports and for tool-assisted regression runs.
+//!
+//! Movies are saved as text:
+//!
+//! ```text
+//! chip8-movie 1
+//! seed 42
+//! cycles-per-frame 10
+//! frames 600
+//! quirks flag_reset save_load_set_pointer display_wait key_wait_release key_wait_beep
+//! 12 press 5
+//! 20 release 5
+//! ```
+
+use core::str::FromStr;
+
+use crate::prelude::*;
+
+use num_traits::FromPrimitive;
+use thiserror::Error;
+
+use crate::keypad::Key;
+use crate::quirks::QuirkConfig;
+use crate::{Chip8, DecodingError};
+
+const HEADER: &str = "chip8-movie 1";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Invalid movie on line {line}: {reason}")]
+pub struct ParseMovieError {
+    pub line: usize,
+    pub reason: String,
+}
+
+/// A key press or release
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    /// Number of frames that had run when the key changed
+    pub frame: u64,
+    pub key: Key,
+    pub pressed: bool,
+}
+
+/// Recorded input, along with everything else needed to replay it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub seed: u64,
+    pub quirks: QuirkConfig,
+    pub cycles_per_frame: usize,
+    /// Number of frames recorded
+    pub frames: u64,
+    pub events: Vec<InputEvent>,
+}
+
+impl Movie {
+    pub fn new(seed: u64, quirks: QuirkConfig) -> Self {
+        Self {
+            seed,
+            quirks,
+            cycles_per_frame: 0,
+            frames: 0,
+            events: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, key: Key, pressed: bool) {
+        self.events.push(InputEvent {
+            frame: self.frames,
+            key,
+            pressed,
+        });
+    }
+
+    pub(crate) fn end_frame(&mut self, cycles: usize) {
+        if self.frames > 0 && cycles != self.cycles_per_frame {
+            log::warn!(
+                "Frame {} ran {cycles} cycles instead of {}, the movie won't replay exactly",
+                self.frames,
+                self.cycles_per_frame
+            );
+        }
+        self.cycles_per_frame = cycles;
+        self.frames += 1;
+    }
+
+    /// Applies the seed and quirks to `system`, which should have just loaded the movie's ROM
+    pub fn start_playback(&self, system: &mut Chip8) -> Playback<'_> {
+        system.seed_rng(self.seed);
+        system.quirks = self.quirks.clone();
+        Playback {
+            movie: self,
+            frame: 0,
+            next_event: 0,
+        }
+    }
+}
+
+fn quirk_flags(quirks: &mut QuirkConfig) -> [(&'static str, &mut bool); 8] {
+    [
+        ("flag_reset", &mut quirks.flag_reset),
+        ("save_load_set_pointer", &mut quirks.save_load_set_pointer),
+        ("display_wait", &mut quirks.display_wait),
+        ("partial_wrap", &mut quirks.partial_wrap),
+        ("key_wait_release", &mut quirks.key_wait_release),
+        ("key_wait_beep", &mut quirks.key_wait_beep),
+        ("alt_shift", &mut quirks.alt_shift),
+        ("alt_rel_jump", &mut quirks.alt_rel_jump),
+    ]
+}
+
+impl core::fmt::Display for Movie {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "cycles-per-frame {}", self.cycles_per_frame)?;
+        writeln!(f, "frames {}", self.frames)?;
+        write!(f, "quirks")?;
+        let mut quirks = self.quirks.clone();
+        for (name, enabled) in quirk_flags(&mut quirks) {
+            if *enabled {
+                write!(f, " {name}")?;
+            }
+        }
+        writeln!(f)?;
+        for event in &self.events {
+            let action = if event.pressed { "press" } else { "release" };
+            writeln!(f, "{} {action} {:X}", event.frame, event.key as u8)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Movie {
+    type Err = ParseMovieError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        // missing fields are reported on the line after the end of the file
+        let end = text.lines().count() + 1;
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+        let error = |line: usize, reason: &str| ParseMovieError {
+            line,
+            reason: reason.to_owned(),
+        };
+
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            Some((line, _)) => return Err(error(line, "not a version 1 movie")),
+            None => return Err(error(1, "the file is empty")),
+        }
+        let mut field = |name: &str| {
+            let (line, text) = lines
+                .next()
+                .ok_or_else(|| error(end, &format!("missing {name}")))?;
+            text.strip_prefix(name)
+                .filter(|value| value.is_empty() || value.starts_with(' '))
+                .map(|value| (line, value.trim().to_owned()))
+                .ok_or_else(|| error(line, &format!("expected {name}")))
+        };
+        let number = |(line, value): (usize, String)| {
+            value
+                .parse::<u64>()
+                .map_err(|err| error(line, &err.to_string()))
+        };
+
+        let seed = number(field("seed")?)?;
+        let cycles_per_frame = number(field("cycles-per-frame")?)? as usize;
+        let frames = number(field("frames")?)?;
+
+        let (line, names) = field("quirks")?;
+        let mut quirks = QuirkConfig::default();
+        let mut flags = quirk_flags(&mut quirks);
+        for (_, enabled) in flags.iter_mut() {
+            **enabled = false;
+        }
+        for name in names.split_whitespace() {
+            let (_, enabled) = flags
+                .iter_mut()
+                .find(|(flag, _)| *flag == name)
+                .ok_or_else(|| error(line, &format!("unknown quirk {name:?}")))?;
+            **enabled = true;
+        }
+
+        let mut events = Vec::new();
+        for (line, text) in lines {
+            let parts: Vec<&str> = text.split_whitespace().collect();
+            let [frame, action, key] = parts[..] else {
+                return Err(error(line, "expected <frame> press|release <key>"));
+            };
+            let frame = frame
+                .parse()
+                .map_err(|_| error(line, "invalid frame number"))?;
+            // playback stops at the first event for a later frame, so they have to be in order
+            if let Some(previous) = events.last().map(|event: &InputEvent| event.frame) {
+                if frame < previous {
+                    return Err(error(
+                        line,
+                        &format!("frame {frame} comes after frame {previous}"),
+                    ));
+                }
+            }
+            let pressed = match action {
+                "press" => true,
+                "release" => false,
+                _ => return Err(error(line, "expected press or release")),
+            };
+            let key = u8::from_str_radix(key, 16)
+                .ok()
+                .and_then(Key::from_u8)
+                .ok_or_else(|| error(line, "keys are a single hex digit"))?;
+            events.push(InputEvent {
+                frame,
+                key,
+                pressed,
+            });
+        }
+
+        Ok(Movie {
+            seed,
+            quirks,
+            cycles_per_frame,
+            frames,
+            events,
+        })
+    }
+}
+
+/// Replays a movie one frame at a time, see [`Movie::start_playback`]
+#[derive(Debug)]
+pub struct Playback<'a> {
+    movie: &'a Movie,
+    frame: u64,
+    next_event: usize,
+}
+
+impl Playback<'_> {
+    /// Presses and releases the keys for the next frame, for frontends that run frames themselves
+    pub fn apply_inputs(&mut self, system: &mut Chip8) {
+        while let Some(event) = self.movie.events.get(self.next_event) {
+            if event.frame > self.frame {
+                break;
+            }
+            if event.pressed {
+                system.press_key(event.key);
+            } else {
+                system.release_key(event.key);
+            }
+            self.next_event += 1;
+        }
+        self.frame += 1;
+    }
+
+    /// Applies the inputs for the next frame and runs it
+    /// # Returns
+    /// Returns false once every recorded frame has run
+    pub fn run_frame(&mut self, system: &mut Chip8) -> Result<bool, DecodingError> {
+        if self.is_finished() {
+            return Ok(false);
+        }
+        self.apply_inputs(system);
+        system.run_frame(self.movie.cycles_per_frame)?;
+        Ok(true)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames
+    }
+
+    pub fn movie(&self) -> &Movie {
+        self.movie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Waits for a key, draws a random sprite where the key says, then starts again
+    const ROM: [u8; 12] = [
+        0xF0, 0x0A, // wait for a key into V0
+        0xC1, 0xFF, // V1 = random
+        0xF1, 0x29, // I = font sprite for V1
+        0xD0, 0x05, // draw at (V0, V0)
+        0x12, 0x00, // loop
+        0x00, 0x00,
+    ];
+
+    fn system() -> Chip8 {
+        let mut system = Chip8::new();
+        system.load_rom(&ROM, 0x200).unwrap();
+        system
+    }
+
+    #[test]
+    fn replays_exactly() {
+        let mut recorded = system();
+        recorded.start_recording(None);
+        for frame in 0..100 {
+            if frame % 7 == 0 {
+                recorded.press_key(Key::from_u8(frame % 16).unwrap());
+            }
+            if frame % 7 == 3 {
+                recorded.release_key(Key::from_u8((frame - 3) % 16).unwrap());
+            }
+            recorded.run_frame(10).unwrap();
+        }
+        let movie = recorded.take_recording().unwrap();
+        assert_eq!(movie.frames, 100);
+        assert_eq!(movie.cycles_per_frame, 10);
+        assert_eq!(movie.events.len(), 29);
+
+        let mut replayed = system();
+        let mut playback = movie.start_playback(&mut replayed);
+        while playback.run_frame(&mut replayed).unwrap() {}
+        assert_eq!(replayed.display.rows(), recorded.display.rows());
+        assert_eq!(replayed.registers, recorded.registers);
+        assert_eq!(replayed.pc, recorded.pc);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut movie = Movie::new(1234, QuirkConfig::default());
+        movie.cycles_per_frame = 15;
+        movie.frames = 3;
+        movie.record(Key::KeyA, true);
+        movie.frames = 5;
+        movie.record(Key::KeyA, false);
+
+        let text = movie.to_string();
+        assert!(text.contains(
+            "quirks flag_reset save_load_set_pointer display_wait key_wait_release key_wait_beep\n"
+        ));
+        assert!(text.ends_with("3 press A\n5 release A\n"));
+        assert_eq!(text.parse(), Ok(movie));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let valid = "chip8-movie 1\nseed 1\ncycles-per-frame 10\nframes 5\nquirks\n";
+        assert!(valid.parse::<Movie>().is_ok());
+
+        let error = |text: &str| text.parse::<Movie>().unwrap_err().line;
+        assert_eq!(error("chip8-movie 2\n"), 1);
+        assert_eq!(error(&valid.replace("seed 1", "seed x")), 2);
+        assert_eq!(error(&valid.replace("quirks", "quirks turbo")), 5);
+        assert_eq!(error(&format!("{valid}3 press G\n")), 6);
+        assert_eq!(error(&format!("{valid}3 hold 1\n")), 6);
+        assert_eq!(
+            error(&format!("{valid}3 press 1\n3 release 1\n2 press 2\n")),
+            8
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Settings for running a particular ROM, which can be saved next to it and shared between frontends
+//!
+//! A profile deserializes from any serde format, such as this TOML:
+//! ```toml
+//! platform = "super-chip"
+//! cycles_per_frame = 30
+//!
+//! keymap = "azerty"
+//! persistence = { effect = "phosphor", decay = 0.3 }
+//!
+//! [quirks]
+//! alt_rel_jump = false
+//! ```
+//! Everything is optional, and left out settings keep their defaults.
+//! The keymap, palette and persistence effect are up to each frontend to apply.
+
+use serde::{Deserialize, Serialize};
+
+use crate::blocks::Engine;
+use crate::effects::PersistenceMode;
+use crate::keypad::KeyMap;
+use crate::quirks::{QuirkOverrides, QuirkPresets};
+use crate::render::Palette;
+use crate::{Chip8, Chip8Builder};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// The platform to take the quirks and memory size from
+    pub platform: Option<QuirkPresets>,
+    /// Quirks that differ from the platform's
+    pub quirks: QuirkOverrides,
+    /// Instructions per 60 Hz frame
+    pub cycles_per_frame: Option<usize>,
+    pub engine: Option<Engine>,
+    pub seed: Option<u64>,
+    pub load_address: Option<u16>,
+    pub memory_size: Option<usize>,
+    pub font_address: Option<u16>,
+    pub stack_depth: Option<usize>,
+    /// A keymap preset's name, or the host keys for each CHIP-8 key
+    pub keymap: Option<KeyMap>,
+    pub palette: Option<Palette>,
+    pub persistence: Option<PersistenceMode>,
+}
+
+impl Profile {
+    /// The settings from `self`, with the ones it leaves out taken from `fallback`
+    ///
+    /// The quirks are combined one by one, so a profile can change a single quirk of another's platform.
+    pub fn or(self, fallback: Profile) -> Profile {
+        Profile {
+            platform: self.platform.or(fallback.platform),
+            quirks: self.quirks.or(fallback.quirks),
+            cycles_per_frame: self.cycles_per_frame.or(fallback.cycles_per_frame),
+            engine: self.engine.or(fallback.engine),
+            seed: self.seed.or(fallback.seed),
+            load_address: self.load_address.or(fallback.load_address),
+            memory_size: self.memory_size.or(fallback.memory_size),
+            font_address: self.font_address.or(fallback.font_address),
+            stack_depth: self.stack_depth.or(fallback.stack_depth),
+            keymap: self.keymap.or(fallback.keymap),
+            palette: self.palette.or(fallback.palette),
+            persistence: self.persistence.or(fallback.persistence),
+        }
+    }
+
+    /// Applies the settings to a builder, on top of what it's already set to
+    pub fn configure(&self, mut builder: Chip8Builder) -> Chip8Builder {
+        if let Some(platform) = self.platform {
+            builder = builder.platform(platform);
+        }
+        self.quirks.apply(&mut builder.quirks);
+        builder.engine = self.engine.unwrap_or(builder.engine);
+        builder.seed = self.seed.or(builder.seed);
+        builder.load_address = self.load_address.unwrap_or(builder.load_address);
+        builder.memory_size = self.memory_size.unwrap_or(builder.memory_size);
+        builder.font_address = self.font_address.unwrap_or(builder.font_address);
+        builder.stack_depth = self.stack_depth.or(builder.stack_depth);
+        builder
+    }
+
+    /// Applies the settings that can change on a VM that's already running
+    ///
+    /// The memory size, font address and load address only take effect through [`Profile::configure`].
+    pub fn apply(&self, system: &mut Chip8) {
+        if let Some(platform) = self.platform {
+            system.quirks.use_preset(platform);
+        }
+        self.quirks.apply(&mut system.quirks);
+        if let Some(engine) = self.engine {
+            system.set_engine(engine);
+        }
+        if let Some(seed) = self.seed {
+            system.seed_rng(seed);
+        }
+        system.stack_depth = self.stack_depth.or(system.stack_depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_quirks_one_by_one() {
+        let game = Profile {
+            platform: Some(QuirkPresets::SuperChip),
+            quirks: QuirkOverrides {
+                alt_rel_jump: Some(false),
+                ..Default::default()
+            },
+            cycles_per_frame: Some(30),
+            ..Default::default()
+        };
+        let flags = Profile {
+            quirks: QuirkOverrides {
+                display_wait: Some(true),
+                ..Default::default()
+            },
+            seed: Some(1),
+            ..Default::default()
+        };
+        let profile = flags.or(game);
+        assert_eq!(profile.platform, Some(QuirkPresets::SuperChip));
+        assert_eq!(profile.cycles_per_frame, Some(30));
+
+        let system = profile.configure(Chip8Builder::new()).build().unwrap();
+        assert!(system.quirks.alt_shift);
+        assert!(!system.quirks.alt_rel_jump);
+        assert!(system.quirks.display_wait);
+
+        let mut running = Chip8::new();
+        profile.apply(&mut running);
+        assert_eq!(running.quirks, system.quirks);
+    }
+
+    #[test]
+    fn configures_memory() {
+        let profile = Profile {
+            platform: Some(QuirkPresets::XoChip),
+            memory_size: Some(0x2000),
+            load_address: Some(0x300),
+            stack_depth: Some(12),
+            ..Default::default()
+        };
+        let system = profile.configure(Chip8Builder::new()).build().unwrap();
+        assert_eq!(system.memory.len(), 0x2000);
+        assert_eq!(system.pc, 0x300);
+        assert_eq!(system.stack_depth, Some(12));
+        assert!(system.quirks.partial_wrap);
+    }
+}
@@ -2,6 +2,8 @@
 use wasm_bindgen::prelude::wasm_bindgen;
 
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct QuirkConfig {
     // Chip8 quirks
@@ -17,6 +19,12 @@ pub struct QuirkConfig {
     /// Wrap sprite even when only partially over the edge
     pub partial_wrap: bool,
 
+    /// FX0A finishes when the pressed key is released, like the COSMAC VIP, rather than as soon as it's pressed
+    pub key_wait_release: bool,
+
+    /// Sound the tone while FX0A waits for the pressed key to be released, like the COSMAC VIP
+    pub key_wait_beep: bool,
+
     // CHIP-48 and SUPER-CHIP quirks
     /// Bitshift instructions operate purely on vX rather than storing vX into vY and then shifting it
     pub alt_shift: bool,
@@ -40,6 +48,8 @@ impl QuirkConfig {
                 self.save_load_set_pointer = true;
                 self.display_wait = true;
                 self.partial_wrap = false;
+                self.key_wait_release = true;
+                self.key_wait_beep = true;
                 self.alt_shift = false;
                 self.alt_rel_jump = false;
             }
@@ -48,6 +58,8 @@ impl QuirkConfig {
                 self.save_load_set_pointer = false;
                 self.display_wait = false;
                 self.partial_wrap = false;
+                self.key_wait_release = false;
+                self.key_wait_beep = false;
                 self.alt_shift = true;
                 self.alt_rel_jump = true;
             }
@@ -55,7 +67,11 @@ impl QuirkConfig {
                 self.flag_reset = false;
                 self.save_load_set_pointer = true;
                 self.display_wait = false;
-                self.partial_wrap = false;
+                // XO-CHIP sprites wrap around the screen instead of being clipped
+                self.partial_wrap = true;
+                // Octo finishes key waits on release too, but stays quiet
+                self.key_wait_release = true;
+                self.key_wait_beep = false;
                 self.alt_shift = false;
                 self.alt_rel_jump = false;
             }
@@ -71,6 +87,8 @@ impl Default for QuirkConfig {
             save_load_set_pointer: false,
             display_wait: false,
             partial_wrap: false,
+            key_wait_release: false,
+            key_wait_beep: false,
             alt_shift: false,
             alt_rel_jump: false,
         };
@@ -80,9 +98,65 @@ impl Default for QuirkConfig {
 }
 
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
-#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum QuirkPresets {
     Chip8,
     SuperChip,
     XoChip,
 }
+
+/// Quirks to change, leaving the ones that are None as they are
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuirkOverrides {
+    pub flag_reset: Option<bool>,
+    pub save_load_set_pointer: Option<bool>,
+    pub display_wait: Option<bool>,
+    pub partial_wrap: Option<bool>,
+    pub key_wait_release: Option<bool>,
+    pub key_wait_beep: Option<bool>,
+    pub alt_shift: Option<bool>,
+    pub alt_rel_jump: Option<bool>,
+}
+
+impl QuirkOverrides {
+    pub fn apply(&self, quirks: &mut QuirkConfig) {
+        let overrides = [
+            (self.flag_reset, &mut quirks.flag_reset),
+            (
+                self.save_load_set_pointer,
+                &mut quirks.save_load_set_pointer,
+            ),
+            (self.display_wait, &mut quirks.display_wait),
+            (self.partial_wrap, &mut quirks.partial_wrap),
+            (self.key_wait_release, &mut quirks.key_wait_release),
+            (self.key_wait_beep, &mut quirks.key_wait_beep),
+            (self.alt_shift, &mut quirks.alt_shift),
+            (self.alt_rel_jump, &mut quirks.alt_rel_jump),
+        ];
+        for (value, quirk) in overrides {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+    }
+
+    /// The overrides from `self`, with the ones it leaves out taken from `fallback`
+    pub fn or(self, fallback: QuirkOverrides) -> QuirkOverrides {
+        QuirkOverrides {
+            flag_reset: self.flag_reset.or(fallback.flag_reset),
+            save_load_set_pointer: self
+                .save_load_set_pointer
+                .or(fallback.save_load_set_pointer),
+            display_wait: self.display_wait.or(fallback.display_wait),
+            partial_wrap: self.partial_wrap.or(fallback.partial_wrap),
+            key_wait_release: self.key_wait_release.or(fallback.key_wait_release),
+            key_wait_beep: self.key_wait_beep.or(fallback.key_wait_beep),
+            alt_shift: self.alt_shift.or(fallback.alt_shift),
+            alt_rel_jump: self.alt_rel_jump.or(fallback.alt_rel_jump),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Converts the display into an RGBA8 image that frontends can draw directly
+
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::prelude::*;
+
+use crate::display::Display;
+
+/// An RGBA8 color
+pub type Color = [u8; 4];
+
+/// Built in color schemes
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PalettePreset {
+    /// White on black
+    Classic,
+    /// The greens of an old handheld LCD
+    LcdGreen,
+    /// The default colors of the Octo IDE
+    Octo,
+    /// Bright, easily distinguished colors
+    HighContrast,
+}
+
+/// The colors used for each combination of display planes.
+///
+/// The color index is built from one bit per plane, so a single plane CHIP-8 display only uses
+/// the first two colors, and the two XO-CHIP planes use all four.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Palette {
+    pub colors: [Color; 4],
+}
+
+impl Palette {
+    /// A palette for a single plane display
+    pub fn two_color(off: Color, on: Color) -> Self {
+        Self {
+            colors: [off, on, on, on],
+        }
+    }
+
+    pub fn preset(preset: PalettePreset) -> Self {
+        let colors = match preset {
+            PalettePreset::Classic => [
+                [0x00, 0x00, 0x00, 0xFF],
+                [0xFF, 0xFF, 0xFF, 0xFF],
+                [0xAA, 0xAA, 0xAA, 0xFF],
+                [0x55, 0x55, 0x55, 0xFF],
+            ],
+            PalettePreset::LcdGreen => [
+                [0x9B, 0xBC, 0x0F, 0xFF],
+                [0x0F, 0x38, 0x0F, 0xFF],
+                [0x30, 0x62, 0x30, 0xFF],
+                [0x8B, 0xAC, 0x0F, 0xFF],
+            ],
+            PalettePreset::Octo => [
+                [0x99, 0x66, 0x00, 0xFF],
+                [0xFF, 0xCC, 0x00, 0xFF],
+                [0xFF, 0x66, 0x00, 0xFF],
+                [0x66, 0x22, 0x00, 0xFF],
+            ],
+            PalettePreset::HighContrast => [
+                [0x00, 0x00, 0x00, 0xFF],
+                [0xFF, 0xFF, 0xFF, 0xFF],
+                [0xFF, 0xFF, 0x00, 0xFF],
+                [0x00, 0xFF, 0xFF, 0xFF],
+            ],
+        };
+        Self { colors }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::preset(PalettePreset::Classic)
+    }
+}
+
+/// Renders displays into an RGBA8 buffer, scaling each CHIP-8 pixel up to a `scale` by `scale` square
+#[derive(Clone, Debug)]
+pub struct Renderer {
+    pub palette: Palette,
+    scale: usize,
+    width: usize,
+    height: usize,
+    buffer: Vec<u8>,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new(Palette::default(), 1)
+    }
+}
+
+impl Renderer {
+    pub fn new(palette: Palette, scale: usize) -> Self {
+        Self {
+            palette,
+            scale: scale.max(1),
+            width: 0,
+            height: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: usize) {
+        self.scale = scale.max(1);
+    }
+
+    /// Width in pixels of the last rendered image
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height in pixels of the last rendered image
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The last rendered image, 4 bytes per pixel, row by row
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Renders a single plane display
+    pub fn render(&mut self, display: &Display) -> &[u8] {
+        self.render_planes(&[display])
+    }
+
+    /// Renders up to 2 display planes on top of each other.
+    /// Plane `n` contributes bit `n` of the palette index, all planes must be the same size.
+    pub fn render_planes(&mut self, planes: &[&Display]) -> &[u8] {
+        let Some(first) = planes.first() else {
+            return &self.buffer;
+        };
+        let (width, height) = (first.get_width(), first.get_height());
+        self.resize(width, height);
+
+        let scale = self.scale;
+        let row_bytes = self.width * 4;
+        for y in 0..height {
+            let image_row = y * scale * row_bytes;
+            for x in 0..width {
+                let index = planes
+                    .iter()
+                    .enumerate()
+                    .fold(0, |index, (plane, display)| {
+                        index | ((display.get_pixel(x, y) as usize) << plane)
+                    });
+                let color = self.palette.colors[index & 0b11];
+                let start = image_row + x * scale * 4;
+                for pixel in self.buffer[start..start + scale * 4].chunks_exact_mut(4) {
+                    pixel.copy_from_slice(&color);
+                }
+            }
+            // the rest of the rows for this CHIP-8 pixel are copies of the first
+            for copy in 1..scale {
+                let copy_start = image_row + copy * row_bytes;
+                self.buffer
+                    .copy_within(image_row..image_row + row_bytes, copy_start);
+            }
+        }
+        &self.buffer
+    }
+
+    /// Renders per pixel brightness values, such as those produced by [`crate::effects::Persistence`],
+    /// blending between the background and foreground colors of the palette
+    pub fn render_intensity(&mut self, intensity: &[f32], width: usize, height: usize) -> &[u8] {
+        self.resize(width, height);
+
+        let [off, on, ..] = self.palette.colors;
+        let scale = self.scale;
+        let row_bytes = self.width * 4;
+        for y in 0..height {
+            let image_row = y * scale * row_bytes;
+            for x in 0..width {
+                let amount = intensity[y * width + x].clamp(0.0, 1.0);
+                let mut color = [0; 4];
+                for channel in 0..4 {
+                    let (off, on) = (off[channel] as f32, on[channel] as f32);
+                    // rounds to nearest, since f32::round needs std and the value is never negative
+                    color[channel] = (off + (on - off) * amount + 0.5) as u8;
+                }
+                let start = image_row + x * scale * 4;
+                for pixel in self.buffer[start..start + scale * 4].chunks_exact_mut(4) {
+                    pixel.copy_from_slice(&color);
+                }
+            }
+            for copy in 1..scale {
+                let copy_start = image_row + copy * row_bytes;
+                self.buffer
+                    .copy_within(image_row..image_row + row_bytes, copy_start);
+            }
+        }
+        &self.buffer
+    }
+
+    /// Resizes the buffer to fit a display of this size at the current scale
+    fn resize(&mut self, display_width: usize, display_height: usize) {
+        self.width = display_width * self.scale;
+        self.height = display_height * self.scale;
+        self.buffer.resize(self.width * self.height * 4, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_scaled() {
+        let mut display = Display::default();
+        display.draw_sprite(1, 0, 1, &[0x80], false);
+
+        let palette = Palette::two_color([0, 0, 0, 255], [1, 2, 3, 255]);
+        let mut renderer = Renderer::new(palette, 3);
+        let buffer = renderer.render(&display).to_vec();
+
+        assert_eq!((renderer.width(), renderer.height()), (64 * 3, 32 * 3));
+        assert_eq!(buffer.len(), 64 * 3 * 32 * 3 * 4);
+        let pixel = |x: usize, y: usize| &buffer[(y * 64 * 3 + x) * 4..][..4];
+        assert_eq!(pixel(2, 0), [0, 0, 0, 255]);
+        assert_eq!(pixel(3, 0), [1, 2, 3, 255]);
+        assert_eq!(pixel(5, 2), [1, 2, 3, 255]);
+        assert_eq!(pixel(6, 2), [0, 0, 0, 255]);
+        assert_eq!(pixel(3, 3), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn render_intensity() {
+        let palette = Palette::two_color([0, 0, 0, 255], [200, 100, 50, 255]);
+        let mut renderer = Renderer::new(palette, 1);
+        let buffer = renderer.render_intensity(&[0.0, 0.5, 1.0], 3, 1);
+        assert_eq!(buffer, [0, 0, 0, 255, 100, 50, 25, 255, 200, 100, 50, 255]);
+    }
+
+    #[test]
+    fn render_planes() {
+        let mut first = Display::default();
+        let mut second = Display::default();
+        first.draw_sprite(0, 0, 1, &[0b1010_0000], false);
+        second.draw_sprite(0, 0, 1, &[0b0110_0000], false);
+
+        let palette = Palette::preset(PalettePreset::Octo);
+        let mut renderer = Renderer::new(palette, 1);
+        let buffer = renderer.render_planes(&[&first, &second]);
+
+        let pixel = |x: usize| &buffer[x * 4..][..4];
+        assert_eq!(pixel(0), palette.colors[1], "First plane only");
+        assert_eq!(pixel(1), palette.colors[2], "Second plane only");
+        assert_eq!(pixel(2), palette.colors[3], "Both planes");
+        assert_eq!(pixel(3), palette.colors[0], "Neither plane");
+    }
+}
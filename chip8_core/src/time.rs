@@ -1,24 +1,47 @@
+#[cfg(feature = "std")]
 use instant::{Duration, Instant};
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::wasm_bindgen;
 
+/// What ticks the timers besides the end of each frame
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClockSource {
+    /// Only [`Chip8::run_frame`](crate::Chip8::run_frame) and friends tick the timers
+    #[cfg_attr(not(feature = "std"), default)]
+    Frames,
+    /// [`Chip8::run_next`](crate::Chip8::run_next) ticks them for the real time since it was last called
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "std", default)]
+    WallClock,
+}
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub struct Timers {
     pub delay: usize,
     pub sound: usize,
+    #[cfg(feature = "std")]
     prev_tick: Instant,
+    #[cfg(feature = "std")]
     remainder: Duration,
+    #[cfg(feature = "std")]
     rate: usize,
 }
 
+#[cfg_attr(not(feature = "std"), allow(clippy::derivable_impls))]
 impl Default for Timers {
     fn default() -> Self {
         Timers {
             delay: 0,
             sound: 0,
+            #[cfg(feature = "std")]
             prev_tick: Instant::now(),
+            #[cfg(feature = "std")]
             remainder: Duration::default(),
+            #[cfg(feature = "std")]
             rate: 60,
         }
     }
@@ -34,6 +57,7 @@ impl Timers {
     }
 
     /// Do n ticks where n = millis * 60
+    #[cfg(feature = "std")]
     pub fn do_ticks(&mut self) {
         // println!("Previous tick: {:?}", self.prev_tick);
         let now = Instant::now();
@@ -46,7 +70,7 @@ impl Timers {
         let tick_count = tick_count.trunc() as u32;
         // println!("Tick Count: {}", tick_count);
         for _ in 0..tick_count {
-            self.single_tick();
+            self.tick();
 
             // short circuit if both timers are 0
             // since then we don't need to tick really
@@ -58,7 +82,8 @@ impl Timers {
         self.prev_tick = now;
     }
 
-    fn single_tick(&mut self) {
+    /// Decrements both timers once, as happens at the start of every 60 Hz frame
+    pub fn tick(&mut self) {
         if self.delay > 0 {
             self.delay -= 1
         }
@@ -0,0 +1,538 @@
+//! Ahead of time translation of ROMs into Rust source
+//!
+//! [`recover_control_flow`] follows jumps, calls and skips from the entry point to find the
+//! instructions a ROM can reach, which leaves out sprites and other data. [`to_rust`] turns each
+//! basic block of those into a function that runs on a [`Chip8`](crate::Chip8), with a
+//! `run_frame` that calls them through [`Chip8::run_frame_with`](crate::Chip8::run_frame_with).
+//!
+//! Only instructions that change registers, the pointer or the program counter are translated.
+//! The interpreter still runs the rest, such as drawing and timers, along with computed jumps
+//! (`BNNN`) and any block whose bytes the program has overwritten.
+//! The generated code compiles for any target chip8_core does, including `wasm32-unknown-unknown`.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::fmt::Write;
+
+use crate::asm;
+use crate::instruction::{Instruction, MathOperation, SkipCondition};
+use crate::prelude::*;
+use crate::quirks::QuirkConfig;
+
+/// The instructions a ROM can reach, and where its basic blocks start
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ControlFlow {
+    /// Every reachable instruction, by address
+    pub instructions: BTreeMap<u16, Instruction>,
+    /// Addresses control can arrive at other than by falling through,
+    /// such as jump targets, return addresses, and both sides of a skip
+    pub leaders: BTreeSet<u16>,
+    /// Addresses of `BNNN` jumps, whose targets aren't known until they run
+    pub computed_jumps: BTreeSet<u16>,
+}
+
+impl ControlFlow {
+    pub fn is_reachable(&self, address: u16) -> bool {
+        self.instructions.contains_key(&address)
+    }
+}
+
+/// Finds the instructions reachable from the start of a ROM loaded at `load_address`
+///
+/// Jumps out of the ROM, such as into the font, aren't followed.
+pub fn recover_control_flow(rom: &[u8], load_address: u16) -> ControlFlow {
+    let start = load_address as usize;
+    let end = start + rom.len();
+    let decode = |address: u16| {
+        let index = (address as usize).checked_sub(start)?;
+        let bytes = rom.get(index..index + 2)?;
+        Some(Instruction::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+    };
+
+    let mut flow = ControlFlow::default();
+    flow.leaders.insert(load_address);
+    let mut pending = vec![load_address];
+    while let Some(address) = pending.pop() {
+        if flow.is_reachable(address) {
+            continue;
+        }
+        let Some(instruction) = decode(address) else {
+            continue;
+        };
+        flow.instructions.insert(address, instruction);
+        let next = address + 2;
+        let (successors, leaders): (&[u16], &[u16]) = match instruction {
+            Instruction::Goto { address: target } => (&[target], &[target]),
+            // the return lands after the call
+            Instruction::Call { address: target } => (&[target, next], &[target, next]),
+            _ if instruction.is_skip() => (&[next, next + 2], &[next, next + 2]),
+            Instruction::Return | Instruction::Halt | Instruction::MachineCodeCall(_) => (&[], &[]),
+            Instruction::JumpRelative { .. } => {
+                flow.computed_jumps.insert(address);
+                (&[], &[])
+            }
+            // the interpreter hands control back after these
+            _ if !is_native(&instruction) => (&[next], &[next]),
+            _ => (&[next], &[]),
+        };
+        flow.leaders.extend(
+            leaders
+                .iter()
+                .filter(|&&leader| (start..end).contains(&(leader as usize))),
+        );
+        pending.extend(successors);
+    }
+    flow
+}
+
+/// Whether an instruction is translated rather than left to the interpreter
+fn is_native(instruction: &Instruction) -> bool {
+    instruction.is_skip()
+        || matches!(
+            instruction,
+            Instruction::Goto { .. }
+                | Instruction::Call { .. }
+                | Instruction::Return
+                | Instruction::SetRegister { .. }
+                | Instruction::AddConst { .. }
+                | Instruction::SetPointer(_)
+                | Instruction::AddToPointer(_)
+                | Instruction::SetPointerToLetter(_)
+                | Instruction::GetDelayTimer(_)
+                | Instruction::RegisterLoad(_)
+        )
+        || matches!(
+            instruction,
+            Instruction::Math { operation, .. } if !matches!(operation, MathOperation::UnknownOperation(_))
+        )
+}
+
+/// A basic block ready to be written out
+struct Block {
+    start: u16,
+    /// Address after the last instruction in the block
+    end: u16,
+    /// Instructions the block runs when it doesn't leave early
+    len: usize,
+    body: String,
+}
+
+/// Translates a ROM loaded at `load_address` into a Rust module that runs it with `quirks`
+///
+/// `name` is only used in the module documentation. The module has a `new_vm` function to set up
+/// a VM with the ROM loaded, and a `run_frame` to use in place of [`Chip8::run_frame`](crate::Chip8::run_frame).
+pub fn to_rust(rom: &[u8], load_address: u16, quirks: &QuirkConfig, name: &str) -> String {
+    let flow = recover_control_flow(rom, load_address);
+    let blocks: Vec<Block> = flow
+        .leaders
+        .iter()
+        .filter_map(|&leader| translate_block(&flow, leader, quirks))
+        .collect();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "//! Transpiled from `{name}` with `chip8_emu transpile`, regenerate it rather than editing it"
+    );
+    out += "//!
+//! Each basic block the ROM can reach is a function, which `run_frame` calls in place of the
+//! interpreter. The interpreter still runs everything else, including blocks the program overwrites.
+
+";
+    let uses_key = blocks.iter().any(|block| block.body.contains("Key::"));
+    let uses_font = blocks.iter().any(|block| block.body.contains("font::"));
+    if uses_key {
+        out += "use chip8_core::keypad::Key;\n";
+    }
+    out += "use chip8_core::quirks::QuirkConfig;\n";
+    out += match uses_font {
+        true => "use chip8_core::{font, Chip8, DecodingError};\n",
+        false => "use chip8_core::{Chip8, DecodingError};\n",
+    };
+    let _ = writeln!(out, "\npub const LOAD_ADDRESS: u16 = {load_address:#05X};");
+    out += "\npub const ROM: &[u8] = &[";
+    for (index, byte) in rom.iter().enumerate() {
+        out += if index % 12 == 0 { "\n    " } else { " " };
+        let _ = write!(out, "{byte:#04X},");
+    }
+    out += "\n];\n";
+
+    let QuirkConfig {
+        flag_reset,
+        save_load_set_pointer,
+        display_wait,
+        partial_wrap,
+        key_wait_release,
+        key_wait_beep,
+        alt_shift,
+        alt_rel_jump,
+    } = quirks;
+    let _ = write!(
+        out,
+        "
+/// The quirks the ROM was transpiled for, which the translated blocks assume
+pub const QUIRKS: QuirkConfig = QuirkConfig {{
+    flag_reset: {flag_reset},
+    save_load_set_pointer: {save_load_set_pointer},
+    display_wait: {display_wait},
+    partial_wrap: {partial_wrap},
+    key_wait_release: {key_wait_release},
+    key_wait_beep: {key_wait_beep},
+    alt_shift: {alt_shift},
+    alt_rel_jump: {alt_rel_jump},
+}};
+
+/// A VM with the ROM loaded and the quirks set
+pub fn new_vm() -> Chip8 {{
+    let mut vm = Chip8::new();
+    vm.quirks = QUIRKS;
+    vm.load_rom(ROM, LOAD_ADDRESS)
+        .expect(\"The ROM fit in memory when it was transpiled\");
+    vm
+}}
+
+/// Runs a frame like [`Chip8::run_frame`], using the translated blocks wherever they apply
+pub fn run_frame(vm: &mut Chip8, cycles: usize) -> Result<(), DecodingError> {{
+    vm.run_frame_with(cycles, run_native)
+}}
+
+type BlockFn = fn(&mut Chip8) -> usize;
+
+/// Runs the block at the program counter if it fits in `budget` and hasn't been overwritten,
+/// and the VM still has the quirks it was translated for
+/// # Returns
+/// Returns how many instructions ran, or 0 to have the interpreter run the next one
+fn run_native(vm: &mut Chip8, budget: usize) -> usize {{
+    let (start, end, len, block): (usize, usize, usize, BlockFn) = match vm.pc {{
+"
+    );
+    for block in &blocks {
+        let _ = writeln!(
+            out,
+            "        {start:#05X} => ({start:#05X}, {end:#05X}, {len}, block_{start:03x}),",
+            start = block.start,
+            end = block.end,
+            len = block.len,
+        );
+    }
+    let _ = write!(
+        out,
+        "        _ => return 0,
+    }};
+    let rom = start - LOAD_ADDRESS as usize..end - LOAD_ADDRESS as usize;
+    if len > budget || vm.quirks != QUIRKS || vm.memory[start..end] != ROM[rom] {{
+        return 0;
+    }}
+    block(vm)
+}}
+"
+    );
+    for block in &blocks {
+        let _ = write!(
+            out,
+            "
+fn block_{start:03x}(vm: &mut Chip8) -> usize {{
+{body}}}
+",
+            start = block.start,
+            body = block.body,
+        );
+    }
+    out
+}
+
+/// Translates the instructions from `start` up to the next jump or skip, untranslated instruction
+/// or leader, or returns None if the first instruction is left to the interpreter
+fn translate_block(flow: &ControlFlow, start: u16, quirks: &QuirkConfig) -> Option<Block> {
+    let mut body = String::new();
+    let mut len = 0;
+    let mut address = start;
+    loop {
+        let instruction = match flow.instructions.get(&address) {
+            Some(instruction) if is_native(instruction) => *instruction,
+            _ => break,
+        };
+        if address != start && flow.leaders.contains(&address) {
+            break;
+        }
+        let _ = writeln!(
+            body,
+            "    // {address:03X}: {}",
+            asm::mnemonic(&instruction)
+        );
+        len += 1;
+        let next = address + 2;
+        if let Some(condition) = condition(&instruction) {
+            let jump = flow.instructions.get(&next);
+            if let Some(Instruction::Goto { address: target }) = jump {
+                // fuse the skip with the jump it skips over
+                let _ = writeln!(body, "    // {next:03X}: {}", asm::mnemonic(jump.unwrap()));
+                let _ = write!(
+                    body,
+                    "    if {condition} {{
+        vm.pc = {skip:#05X};
+        return {len};
+    }}
+    vm.pc = {target:#05X};
+    {taken}
+",
+                    skip = next + 2,
+                    taken = len + 1,
+                );
+                return Some(Block {
+                    start,
+                    end: next + 2,
+                    len: len + 1,
+                    body,
+                });
+            }
+            let _ = write!(
+                body,
+                "    vm.pc = if {condition} {{ {skip:#05X} }} else {{ {next:#05X} }};
+    {len}
+",
+                skip = next + 2,
+            );
+            return Some(Block {
+                start,
+                end: next,
+                len,
+                body,
+            });
+        }
+        match instruction {
+            Instruction::Goto { address: target } => {
+                let _ = write!(body, "    vm.pc = {target:#05X};\n    {len}\n");
+                return Some(Block {
+                    start,
+                    end: next,
+                    len,
+                    body,
+                });
+            }
+            Instruction::Call { address: target } => {
+                // a full stack is an error, which the interpreter reports
+                let _ = write!(
+                    body,
+                    "    if vm.is_stack_full() {{
+        vm.pc = {address:#05X};
+        return {ran};
+    }}
+    vm.stack.push({next:#05X});
+    vm.pc = {target:#05X};
+    {len}
+",
+                    ran = len - 1,
+                );
+                return Some(Block {
+                    start,
+                    end: next,
+                    len,
+                    body,
+                });
+            }
+            Instruction::Return => {
+                // an empty stack is an error, which the interpreter reports
+                let _ = write!(
+                    body,
+                    "    match vm.stack.pop() {{
+        Some(address) => {{
+            vm.pc = address;
+            {len}
+        }}
+        None => {{
+            vm.pc = {address:#05X};
+            {ran}
+        }}
+    }}
+",
+                    ran = len - 1,
+                );
+                return Some(Block {
+                    start,
+                    end: next,
+                    len,
+                    body,
+                });
+            }
+            _ => body += &statement(&instruction, quirks),
+        }
+        address = next;
+    }
+    if len == 0 {
+        return None;
+    }
+    let _ = write!(body, "    vm.pc = {address:#05X};\n    {len}\n");
+    Some(Block {
+        start,
+        end: address,
+        len,
+        body,
+    })
+}
+
+/// Rust for an instruction that doesn't change the program counter
+fn statement(instruction: &Instruction, quirks: &QuirkConfig) -> String {
+    let v = |register: u8| format!("vm.registers[{register:#X}]");
+    match *instruction {
+        Instruction::SetRegister { register, value } => {
+            format!("    {} = {value:#04X};\n", v(register))
+        }
+        Instruction::AddConst { register, value } => {
+            let x = v(register);
+            format!("    {x} = {x}.wrapping_add({value:#04X});\n")
+        }
+        Instruction::SetPointer(address) => format!("    vm.pointer = {address:#05X};\n"),
+        Instruction::AddToPointer(register) => {
+            format!("    vm.pointer += {} as u16;\n", v(register))
+        }
+        Instruction::SetPointerToLetter(register) => {
+            format!(
+                "    vm.pointer = vm.font_address + font::get_letter_address({});\n",
+                v(register)
+            )
+        }
+        Instruction::GetDelayTimer(register) => {
+            format!("    {} = vm.timers.delay as u8;\n", v(register))
+        }
+        Instruction::RegisterLoad(register) => {
+            let mut out = format!(
+                "    for i in 0..={register:#X} {{
+        vm.registers[i] = vm.memory[vm.pointer as usize + i];
+    }}
+"
+            );
+            if quirks.save_load_set_pointer {
+                let _ = writeln!(out, "    vm.pointer += {};", register as u16 + 1);
+            }
+            out
+        }
+        Instruction::Math {
+            source,
+            destination,
+            operation,
+        } => math(v(source), v(destination), operation, quirks),
+        _ => unreachable!("{instruction:?} isn't translated"),
+    }
+}
+
+/// Rust for an 8XYN instruction, matching what the interpreter does under `quirks`
+fn math(y: String, x: String, operation: MathOperation, quirks: &QuirkConfig) -> String {
+    let flag = "vm.registers[0xF]";
+    let bitwise = |operator: &str| {
+        let mut out = format!("    {x} {operator}= {y};\n");
+        if quirks.flag_reset {
+            let _ = writeln!(out, "    {flag} = 0;");
+        }
+        out
+    };
+    // the result is written before the flag, so the flag wins when X is F
+    let with_flag = |operands: String, result: &str, carry: &str| {
+        format!(
+            "    {operands};
+    let result = {result};
+    {x} = result;
+    {flag} = ({carry}) as u8;
+"
+        )
+    };
+    let both = || format!("let (x, y) = ({x}, {y})");
+    let shifted = || format!("let value = {}", if quirks.alt_shift { &x } else { &y });
+    match operation {
+        MathOperation::Assign => format!("    {x} = {y};\n"),
+        MathOperation::BitwiseOr => bitwise("|"),
+        MathOperation::BitwiseAnd => bitwise("&"),
+        MathOperation::BitwiseXor => bitwise("^"),
+        MathOperation::Add => with_flag(both(), "x.wrapping_add(y)", "x > result"),
+        MathOperation::Subtract => with_flag(both(), "x.wrapping_sub(y)", "x > result"),
+        MathOperation::Difference => with_flag(both(), "y.wrapping_sub(x)", "y > result"),
+        MathOperation::BitshiftRight => {
+            with_flag(shifted(), "value >> 1", "value & 0b0000_0001 != 0")
+        }
+        MathOperation::BitshiftLeft => {
+            with_flag(shifted(), "value << 1", "value & 0b1000_0000 != 0")
+        }
+        MathOperation::UnknownOperation(_) => unreachable!("Unknown math isn't translated"),
+    }
+}
+
+/// The Rust condition under which a skip instruction skips
+fn condition(instruction: &Instruction) -> Option<String> {
+    let v = |register: u8| format!("vm.registers[{register:#X}]");
+    let operator = |equal: bool| if equal { "==" } else { "!=" };
+    Some(match instruction.skip_condition()? {
+        SkipCondition::Const {
+            register,
+            value,
+            equal,
+        } => format!("{} {} {value:#04X}", v(register), operator(equal)),
+        SkipCondition::Registers { x, y, equal } => {
+            format!("{} {} {}", v(x), operator(equal), v(y))
+        }
+        SkipCondition::Key { register, pressed } => format!(
+            "{}vm.keypad.is_key_pressed(Key::ALL[{} as usize])",
+            if pressed { "" } else { "!" },
+            v(register)
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_control_flow() {
+        #[rustfmt::skip]
+        let rom = [
+            0x22, 0x08, // 200: call 0x208
+            0x3A, 0x00, // 202: skip if VA == 0
+            0xB2, 0x00, // 204: jump to 0x200 + V0
+            0x12, 0x0C, // 206: jump past the data
+            0x00, 0xEE, // 208: return
+            0xFF, 0xFF, // 20A: data
+            0x00, 0x00, // 20C: halt
+        ];
+        let flow = recover_control_flow(&rom, 0x200);
+        assert_eq!(
+            flow.instructions.keys().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204, 0x206, 0x208, 0x20C]
+        );
+        assert!(!flow.is_reachable(0x20A));
+        assert_eq!(
+            flow.leaders.iter().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204, 0x206, 0x208, 0x20C]
+        );
+        assert_eq!(
+            flow.computed_jumps.iter().copied().collect::<Vec<_>>(),
+            [0x204]
+        );
+    }
+
+    #[test]
+    fn fuses_skips_with_jumps() {
+        #[rustfmt::skip]
+        let rom = [
+            0x70, 0x01, // 200: V0 += 1
+            0x30, 0x10, // 202: skip if V0 == 0x10
+            0x12, 0x00, // 204: jump to 0x200
+            0x00, 0x00, // 206: halt
+        ];
+        let source = to_rust(&rom, 0x200, &QuirkConfig::default(), "loop.ch8");
+        assert!(source.contains(
+            "fn block_200(vm: &mut Chip8) -> usize {
+    // 200: ADD V0, 0x01
+    vm.registers[0x0] = vm.registers[0x0].wrapping_add(0x01);
+    // 202: SE V0, 0x10
+    // 204: JP 0x200
+    if vm.registers[0x0] == 0x10 {
+        vm.pc = 0x206;
+        return 2;
+    }
+    vm.pc = 0x200;
+    3
+}"
+        ));
+        // the halt is left to the interpreter
+        assert!(!source.contains("fn block_206"));
+    }
+}
@@ -0,0 +1,313 @@
+//! Conformance tests against Timendus' CHIP-8 test suite
+//!
+//! Each test in the suite is picked from its menu with simulated key presses under every quirk preset,
+//! then each labelled area of its result screen is compared against a known good framebuffer.
+//! This uses the combined suite ROM that `chip8_wasm` ships, since the `roms/chip8-test-suite`
+//! submodule isn't always checked out.
+//!
+//! Run with `cargo test -p chip8_core --test conformance -- --nocapture` to see the full matrix.
+
+use chip8_core::blocks::Engine;
+use chip8_core::display::Display;
+use chip8_core::headless::{self, StopCondition};
+use chip8_core::keypad::Key;
+use chip8_core::quirks::QuirkPresets;
+use chip8_core::Chip8;
+
+const SUITE: &[u8] = include_bytes!("../../chip8_wasm/chip8-test-suite.ch8");
+
+const PRESETS: [(QuirkPresets, &str); 3] = [
+    (QuirkPresets::Chip8, "CHIP-8"),
+    (QuirkPresets::SuperChip, "SUPER-CHIP"),
+    (QuirkPresets::XoChip, "XO-CHIP"),
+];
+
+const CYCLES_PER_FRAME: usize = 30;
+/// Frames to wait for a test to finish
+const MAX_FRAMES: usize = 3000;
+/// Frames to wait before pressing a key, since the menus poll the keypad rather than waiting for a key
+const SETTLE_FRAMES: usize = 60;
+/// Frames to hold a key down for, so the suite sees it with both key wait and key checks
+const HOLD_FRAMES: usize = 4;
+
+/// A labelled area of a result screen, which is where the suite draws the result of one check
+struct Cell {
+    label: &'static str,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+const fn cell(label: &'static str, x: usize, y: usize, width: usize) -> Cell {
+    Cell {
+        label,
+        x,
+        y,
+        width,
+        height: 5,
+    }
+}
+
+struct SuiteTest {
+    name: &'static str,
+    /// Key to pick the test from the menu
+    menu_key: Key,
+    /// Whether the test asks which platform to check for after being picked
+    asks_platform: bool,
+    /// Keys to press once the test has started, such as to answer the test's own prompts
+    keys: &'static [Key],
+    cells: &'static [Cell],
+}
+
+const TESTS: [SuiteTest; 5] = [
+    SuiteTest {
+        name: "ibm",
+        menu_key: Key::Key1,
+        asks_platform: false,
+        keys: &[],
+        cells: &[cell("IBM logo", 0, 0, 64)],
+    },
+    SuiteTest {
+        name: "corax",
+        menu_key: Key::Key2,
+        asks_platform: false,
+        keys: &[],
+        cells: &[
+            cell("3XNN", 0, 0, 23),
+            cell("00EE", 23, 0, 23),
+            cell("8XY5", 46, 0, 18),
+            cell("4XNN", 0, 5, 23),
+            cell("8XY0", 23, 5, 23),
+            cell("8XYE", 46, 5, 18),
+            cell("5XY0", 0, 10, 23),
+            cell("8XY1", 23, 10, 23),
+            cell("8XY6", 46, 10, 18),
+            cell("7XNN", 0, 15, 23),
+            cell("8XY2", 23, 15, 23),
+            cell("FX55", 46, 15, 18),
+            cell("9XY0", 0, 20, 23),
+            cell("8XY3", 23, 20, 23),
+            cell("FX33", 46, 20, 18),
+            cell("2NNN", 0, 25, 23),
+            cell("8XY4", 23, 25, 23),
+            cell("1NNN", 46, 25, 18),
+        ],
+    },
+    SuiteTest {
+        name: "flags",
+        menu_key: Key::Key3,
+        asks_platform: false,
+        keys: &[],
+        cells: &[
+            cell("8XY1 VF", 22, 0, 22),
+            cell("8XY2 VF", 44, 0, 20),
+            cell("8XY3 VF", 0, 5, 22),
+            cell("8XY4 VF", 22, 5, 22),
+            cell("8XY5 VF", 44, 5, 20),
+            cell("8XY6 VF", 0, 10, 22),
+            cell("8XY7 VF", 22, 10, 22),
+            cell("8XYE VF", 44, 10, 20),
+            cell("8XY4 carry", 22, 16, 22),
+            cell("8XY5 carry", 44, 16, 20),
+            cell("8XY6 carry", 0, 21, 22),
+            cell("8XY7 carry", 22, 21, 22),
+            cell("8XYE carry", 44, 21, 20),
+            cell("FX1E", 22, 27, 42),
+        ],
+    },
+    SuiteTest {
+        name: "quirks",
+        menu_key: Key::Key4,
+        asks_platform: true,
+        keys: &[],
+        cells: &[
+            cell("VF reset quirk", 0, 0, 64),
+            cell("Memory quirk", 0, 5, 64),
+            cell("Display wait quirk", 0, 10, 64),
+            cell("Clipping quirk", 0, 15, 64),
+            cell("Shifting quirk", 0, 20, 64),
+            cell("Jumping quirk", 0, 25, 64),
+        ],
+    },
+    SuiteTest {
+        name: "keypad",
+        menu_key: Key::Key5,
+        asks_platform: false,
+        // picks the FX0A test from the keypad menu, then answers it, since the other two
+        // keypad tests just show which keys are down and never finish
+        keys: &[Key::Key3, Key::KeyA],
+        cells: &[cell("FX0A check", 0, 9, 64), cell("FX0A result", 0, 17, 64)],
+    },
+];
+
+fn run_frames(system: &mut Chip8, frames: usize) {
+    for _ in 0..frames {
+        system.run_frame(CYCLES_PER_FRAME).unwrap();
+    }
+}
+
+fn press(system: &mut Chip8, key: Key) {
+    run_frames(system, SETTLE_FRAMES);
+    system.press_key(key);
+    run_frames(system, HOLD_FRAMES);
+    system.release_key(key);
+}
+
+/// Runs frames until the test stops, either waiting for a key or looping forever
+fn run_until_finished(system: &mut Chip8) {
+    for _ in 0..MAX_FRAMES {
+        if system.is_key_waiting() || StopCondition::SelfJump.is_met(system) {
+            return;
+        }
+        system.run_frame(CYCLES_PER_FRAME).unwrap();
+    }
+    panic!("The test didn't finish within {MAX_FRAMES} frames");
+}
+
+/// Runs a test, with `setup` picking how the VM runs instructions
+fn run_test(test: &SuiteTest, preset: QuirkPresets, setup: fn(&mut Chip8)) -> Chip8 {
+    let mut system = Chip8::new();
+    setup(&mut system);
+    system.quirks.use_preset(preset);
+    system.seed_rng(0);
+    system.load_rom(SUITE, 0x200).unwrap();
+
+    // any key gets past the splash screen
+    press(&mut system, Key::Key1);
+    press(&mut system, test.menu_key);
+    if test.asks_platform {
+        let platform = match preset {
+            QuirkPresets::Chip8 => Key::Key1,
+            QuirkPresets::SuperChip => Key::Key2,
+            QuirkPresets::XoChip => Key::Key3,
+        };
+        press(&mut system, platform);
+    }
+    for &key in test.keys {
+        press(&mut system, key);
+    }
+    run_until_finished(&mut system);
+    system
+}
+
+/// The expected result screen, which only depends on the preset if the test checks quirks,
+/// or relies on one like the keypad test does
+fn expected(test: &SuiteTest, preset: QuirkPresets) -> Display {
+    let dump = match (test.name, preset) {
+        ("ibm", _) => include_str!("conformance/ibm.txt"),
+        ("corax", _) => include_str!("conformance/corax.txt"),
+        ("flags", _) => include_str!("conformance/flags.txt"),
+        ("quirks", QuirkPresets::Chip8) => include_str!("conformance/quirks-chip8.txt"),
+        ("quirks", QuirkPresets::SuperChip) => include_str!("conformance/quirks-superchip.txt"),
+        ("quirks", QuirkPresets::XoChip) => include_str!("conformance/quirks-xochip.txt"),
+        // the SUPER-CHIP preset's FX0A doesn't wait for the key to be released
+        ("keypad", QuirkPresets::SuperChip) => include_str!("conformance/keypad-superchip.txt"),
+        ("keypad", _) => include_str!("conformance/keypad.txt"),
+        _ => unreachable!("No expected screen for {}", test.name),
+    };
+    headless::parse_dump(dump).expect("Invalid expected screen")
+}
+
+fn cell_matches(cell: &Cell, actual: &Display, expected: &Display) -> bool {
+    (cell.y..cell.y + cell.height).all(|y| {
+        (cell.x..cell.x + cell.width).all(|x| actual.get_pixel(x, y) == expected.get_pixel(x, y))
+    })
+}
+
+/// Formats a line of the results matrix, lining up the columns with the preset names
+fn matrix_row<'a>(
+    label: &str,
+    label_width: usize,
+    columns: impl Iterator<Item = &'a str>,
+) -> String {
+    let mut row = format!("{label:label_width$}");
+    for column in columns {
+        row += &format!("  {column:10}");
+    }
+    row.trim_end().to_owned()
+}
+
+#[test]
+fn test_suite() {
+    let label_width = TESTS
+        .iter()
+        .flat_map(|test| test.cells)
+        .map(|cell| cell.label.len())
+        .max()
+        .unwrap_or_default();
+    let mut matrix = vec![matrix_row(
+        "",
+        label_width,
+        PRESETS.iter().map(|&(_, name)| name),
+    )];
+
+    let mut failures = 0;
+    for test in &TESTS {
+        let results: Vec<(Display, Display)> = PRESETS
+            .iter()
+            .map(|&(preset, _)| {
+                (
+                    run_test(test, preset, |_| {}).display,
+                    expected(test, preset),
+                )
+            })
+            .collect();
+
+        for cell in test.cells {
+            let passed: Vec<bool> = results
+                .iter()
+                .map(|(actual, expected)| cell_matches(cell, actual, expected))
+                .collect();
+            failures += passed.iter().filter(|&&passed| !passed).count();
+            matrix.push(matrix_row(
+                cell.label,
+                label_width,
+                passed
+                    .iter()
+                    .map(|&passed| if passed { "pass" } else { "FAIL" }),
+            ));
+        }
+    }
+
+    let matrix = matrix.join("\n");
+    println!("{matrix}");
+    assert_eq!(failures, 0, "Failed {failures} checks:\n{matrix}");
+}
+
+/// Runs every test under every preset both ways, checking that the VMs end up the same
+fn assert_same_as_interpreter(setup: fn(&mut Chip8)) -> Vec<Chip8> {
+    let mut systems = Vec::new();
+    for test in &TESTS {
+        for &(preset, name) in &PRESETS {
+            let plain = run_test(test, preset, |_| {});
+            let other = run_test(test, preset, setup);
+            let context = format!("{} under {name}", test.name);
+            assert_eq!(other.pc, plain.pc, "{context}");
+            assert_eq!(other.registers, plain.registers, "{context}");
+            assert_eq!(other.pointer, plain.pointer, "{context}");
+            assert_eq!(other.stack, plain.stack, "{context}");
+            assert_eq!(other.memory, plain.memory, "{context}");
+            assert!(other.display == plain.display, "{context}");
+            systems.push(other);
+        }
+    }
+    systems
+}
+
+/// Differential test of the decode cache against decoding every instruction as it runs
+#[test]
+fn decode_cache_matches_interpreter() {
+    for system in assert_same_as_interpreter(Chip8::enable_decode_cache) {
+        let cache = system.decode_cache.as_ref().unwrap();
+        assert!(cache.hits() > cache.misses());
+    }
+}
+
+/// Differential test of the block engine against the interpreter
+#[test]
+fn block_engine_matches_interpreter() {
+    for system in assert_same_as_interpreter(|system| system.set_engine(Engine::Blocks)) {
+        assert!(!system.compiled_blocks().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! Checks the transpiler against a checked in translation of a demo ROM,
+//! then runs that translation alongside the interpreter
+//!
+//! Regenerate the translation after changing the transpiler with
+//! `UPDATE_GOLDEN=1 cargo test -p chip8_core --test transpile`.
+
+use chip8_core::asm::assemble;
+use chip8_core::keypad::Key;
+use chip8_core::quirks::{QuirkConfig, QuirkPresets};
+use chip8_core::transpile::{recover_control_flow, to_rust};
+use chip8_core::Chip8;
+
+#[rustfmt::skip]
+#[path = "transpile/demo.rs"]
+mod demo;
+
+const GOLDEN: &str = "tests/transpile/demo.rs";
+
+fn demo_rom() -> Vec<u8> {
+    assemble(include_str!("transpile/demo.asm"), "demo.asm", 0x200)
+        .expect("The demo assembles")
+        .bytes
+}
+
+fn quirks() -> QuirkConfig {
+    let mut quirks = QuirkConfig::default();
+    quirks.use_preset(QuirkPresets::Chip8);
+    quirks
+}
+
+#[test]
+fn matches_golden() {
+    let source = to_rust(&demo_rom(), 0x200, &quirks(), "demo.asm");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(GOLDEN, &source).unwrap();
+        return;
+    }
+    assert!(
+        source == include_str!("transpile/demo.rs"),
+        "{GOLDEN} is out of date, regenerate it with UPDATE_GOLDEN=1"
+    );
+}
+
+#[test]
+fn skips_data() {
+    let rom = demo_rom();
+    let flow = recover_control_flow(&rom, 0x200);
+    // the digits at the end are never executed
+    let last = *flow.instructions.keys().last().unwrap();
+    assert!((last as usize) < 0x200 + rom.len() - 3);
+    assert_eq!(flow.computed_jumps.len(), 1);
+}
+
+#[test]
+fn runs_like_the_interpreter() {
+    for cycles in [1, 7, 30] {
+        let mut native = demo::new_vm();
+        let mut plain = Chip8::new();
+        plain.quirks = quirks();
+        plain.load_rom(&demo_rom(), 0x200).unwrap();
+        native.seed_rng(0);
+        plain.seed_rng(0);
+
+        for frame in 0..600 {
+            // hold down a different key every so often
+            if frame % 50 == 10 {
+                let key = Key::ALL[frame / 50 % 16];
+                native.press_key(key);
+                plain.press_key(key);
+            } else if frame % 50 == 30 {
+                let key = Key::ALL[frame / 50 % 16];
+                native.release_key(key);
+                plain.release_key(key);
+            }
+            demo::run_frame(&mut native, cycles).unwrap();
+            plain.run_frame(cycles).unwrap();
+
+            let context = format!("frame {frame} at {cycles} instructions per frame");
+            assert_eq!(native.pc, plain.pc, "{context}");
+            assert_eq!(native.registers, plain.registers, "{context}");
+            assert_eq!(native.pointer, plain.pointer, "{context}");
+            assert_eq!(native.stack, plain.stack, "{context}");
+            assert_eq!(native.memory, plain.memory, "{context}");
+            assert_eq!(native.timers.delay, plain.timers.delay, "{context}");
+            assert!(native.display == plain.display, "{context}");
+        }
+    }
+}
+
+#[test]
+fn falls_back_when_the_quirks_change() {
+    let mut quirks = quirks();
+    quirks.use_preset(QuirkPresets::SuperChip);
+    assert_ne!(quirks, demo::QUIRKS);
+
+    let mut native = demo::new_vm();
+    native.quirks = quirks.clone();
+    let mut plain = Chip8::new();
+    plain.quirks = quirks;
+    plain.load_rom(&demo_rom(), 0x200).unwrap();
+    native.seed_rng(0);
+    plain.seed_rng(0);
+
+    for frame in 0..300 {
+        if frame % 50 == 10 {
+            native.press_key(Key::ALL[5]);
+            plain.press_key(Key::ALL[5]);
+        } else if frame % 50 == 30 {
+            native.release_key(Key::ALL[5]);
+            plain.release_key(Key::ALL[5]);
+        }
+        demo::run_frame(&mut native, 15).unwrap();
+        plain.run_frame(15).unwrap();
+
+        assert_eq!(native.pc, plain.pc, "frame {frame}");
+        assert_eq!(native.registers, plain.registers, "frame {frame}");
+        assert_eq!(native.memory, plain.memory, "frame {frame}");
+        assert!(native.display == plain.display, "frame {frame}");
+    }
+}
@@ -0,0 +1,61 @@
+; Exercises everything the transpiler translates, for tests/transpile.rs
+start:
+    CLS
+    LD V1, 1
+main:
+    ADD V0, 1
+    ADD V0, 2
+    LD V2, V0
+    ADD V2, V1
+    SUB V2, V1
+    SUBN V3, V2
+    SHR V4, V2
+    SHL V4, V4
+    OR V3, V4
+    AND V3, V0
+    XOR V4, V3
+    CALL draw
+    LD V6, V0
+    LD V7, 0x0F
+    AND V6, V7
+    SKNP V6
+    ADD V5, 1
+    SNE V0, 0x30
+    JP patch
+    JP main
+
+; turns the first instruction of the delay loop into ADD VA, 5
+patch:
+    LD VE, V0
+    LD V0, 0x7A
+    LD V1, 0x05
+    LD I, delay
+    LD [I], V1
+    LD V0, VE
+    LD V1, 1
+    CALL delay
+    LD DT, V0
+    LD I, digits
+    LD B, V5
+    LD V2, [I]
+    LD F, V2
+    ADD I, V1
+    LD VD, DT
+    LD V0, 0
+    JP V0, table
+table:
+    JP main
+
+delay:
+    ADD VB, 1
+    SE VB, 0x40
+    JP delay
+    RET
+
+draw:
+    LD F, V3
+    DRW V0, V1, 5
+    RET
+
+digits:
+    DB 0, 0, 0
@@ -0,0 +1,289 @@
+//! Transpiled from `demo.asm` with `chip8_emu transpile`, regenerate it rather than editing it
+//!
+//! Each basic block the ROM can reach is a function, which `run_frame` calls in place of the
+//! interpreter. The interpreter still runs everything else, including blocks the program overwrites.
+
+use chip8_core::keypad::Key;
+use chip8_core::quirks::QuirkConfig;
+use chip8_core::{font, Chip8, DecodingError};
+
+pub const LOAD_ADDRESS: u16 = 0x200;
+
+pub const ROM: &[u8] = &[
+    0x00, 0xE0, 0x61, 0x01, 0x70, 0x01, 0x70, 0x02, 0x82, 0x00, 0x82, 0x14,
+    0x82, 0x15, 0x83, 0x27, 0x84, 0x26, 0x84, 0x4E, 0x83, 0x41, 0x83, 0x02,
+    0x84, 0x33, 0x22, 0x58, 0x86, 0x00, 0x67, 0x0F, 0x86, 0x72, 0xE6, 0xA1,
+    0x75, 0x01, 0x40, 0x30, 0x12, 0x2C, 0x12, 0x04, 0x8E, 0x00, 0x60, 0x7A,
+    0x61, 0x05, 0xA2, 0x50, 0xF1, 0x55, 0x80, 0xE0, 0x61, 0x01, 0x22, 0x50,
+    0xF0, 0x15, 0xA2, 0x5E, 0xF5, 0x33, 0xF2, 0x65, 0xF2, 0x29, 0xF1, 0x1E,
+    0xFD, 0x07, 0x60, 0x00, 0xB2, 0x4E, 0x12, 0x04, 0x7B, 0x01, 0x3B, 0x40,
+    0x12, 0x50, 0x00, 0xEE, 0xF3, 0x29, 0xD0, 0x15, 0x00, 0xEE, 0x00, 0x00,
+    0x00,
+];
+
+/// The quirks the ROM was transpiled for, which the translated blocks assume
+pub const QUIRKS: QuirkConfig = QuirkConfig {
+    flag_reset: true,
+    save_load_set_pointer: true,
+    display_wait: true,
+    partial_wrap: false,
+    key_wait_release: true,
+    key_wait_beep: true,
+    alt_shift: false,
+    alt_rel_jump: false,
+};
+
+/// A VM with the ROM loaded and the quirks set
+pub fn new_vm() -> Chip8 {
+    let mut vm = Chip8::new();
+    vm.quirks = QUIRKS;
+    vm.load_rom(ROM, LOAD_ADDRESS)
+        .expect("The ROM fit in memory when it was transpiled");
+    vm
+}
+
+/// Runs a frame like [`Chip8::run_frame`], using the translated blocks wherever they apply
+pub fn run_frame(vm: &mut Chip8, cycles: usize) -> Result<(), DecodingError> {
+    vm.run_frame_with(cycles, run_native)
+}
+
+type BlockFn = fn(&mut Chip8) -> usize;
+
+/// Runs the block at the program counter if it fits in `budget` and hasn't been overwritten,
+/// and the VM still has the quirks it was translated for
+/// # Returns
+/// Returns how many instructions ran, or 0 to have the interpreter run the next one
+fn run_native(vm: &mut Chip8, budget: usize) -> usize {
+    let (start, end, len, block): (usize, usize, usize, BlockFn) = match vm.pc {
+        0x202 => (0x202, 0x204, 1, block_202),
+        0x204 => (0x204, 0x21C, 12, block_204),
+        0x21C => (0x21C, 0x224, 4, block_21c),
+        0x224 => (0x224, 0x226, 1, block_224),
+        0x226 => (0x226, 0x22A, 2, block_226),
+        0x228 => (0x228, 0x22A, 1, block_228),
+        0x22A => (0x22A, 0x22C, 1, block_22a),
+        0x22C => (0x22C, 0x234, 4, block_22c),
+        0x236 => (0x236, 0x23C, 3, block_236),
+        0x23E => (0x23E, 0x240, 1, block_23e),
+        0x242 => (0x242, 0x24C, 5, block_242),
+        0x250 => (0x250, 0x256, 3, block_250),
+        0x254 => (0x254, 0x256, 1, block_254),
+        0x256 => (0x256, 0x258, 1, block_256),
+        0x258 => (0x258, 0x25A, 1, block_258),
+        0x25C => (0x25C, 0x25E, 1, block_25c),
+        _ => return 0,
+    };
+    let rom = start - LOAD_ADDRESS as usize..end - LOAD_ADDRESS as usize;
+    if len > budget || vm.quirks != QUIRKS || vm.memory[start..end] != ROM[rom] {
+        return 0;
+    }
+    block(vm)
+}
+
+fn block_202(vm: &mut Chip8) -> usize {
+    // 202: LD V1, 0x01
+    vm.registers[0x1] = 0x01;
+    vm.pc = 0x204;
+    1
+}
+
+fn block_204(vm: &mut Chip8) -> usize {
+    // 204: ADD V0, 0x01
+    vm.registers[0x0] = vm.registers[0x0].wrapping_add(0x01);
+    // 206: ADD V0, 0x02
+    vm.registers[0x0] = vm.registers[0x0].wrapping_add(0x02);
+    // 208: LD V2, V0
+    vm.registers[0x2] = vm.registers[0x0];
+    // 20A: ADD V2, V1
+    let (x, y) = (vm.registers[0x2], vm.registers[0x1]);
+    let result = x.wrapping_add(y);
+    vm.registers[0x2] = result;
+    vm.registers[0xF] = (x > result) as u8;
+    // 20C: SUB V2, V1
+    let (x, y) = (vm.registers[0x2], vm.registers[0x1]);
+    let result = x.wrapping_sub(y);
+    vm.registers[0x2] = result;
+    vm.registers[0xF] = (x > result) as u8;
+    // 20E: SUBN V3, V2
+    let (x, y) = (vm.registers[0x3], vm.registers[0x2]);
+    let result = y.wrapping_sub(x);
+    vm.registers[0x3] = result;
+    vm.registers[0xF] = (y > result) as u8;
+    // 210: SHR V4, V2
+    let value = vm.registers[0x2];
+    let result = value >> 1;
+    vm.registers[0x4] = result;
+    vm.registers[0xF] = (value & 0b0000_0001 != 0) as u8;
+    // 212: SHL V4, V4
+    let value = vm.registers[0x4];
+    let result = value << 1;
+    vm.registers[0x4] = result;
+    vm.registers[0xF] = (value & 0b1000_0000 != 0) as u8;
+    // 214: OR V3, V4
+    vm.registers[0x3] |= vm.registers[0x4];
+    vm.registers[0xF] = 0;
+    // 216: AND V3, V0
+    vm.registers[0x3] &= vm.registers[0x0];
+    vm.registers[0xF] = 0;
+    // 218: XOR V4, V3
+    vm.registers[0x4] ^= vm.registers[0x3];
+    vm.registers[0xF] = 0;
+    // 21A: CALL 0x258
+    if vm.is_stack_full() {
+        vm.pc = 0x21A;
+        return 11;
+    }
+    vm.stack.push(0x21C);
+    vm.pc = 0x258;
+    12
+}
+
+fn block_21c(vm: &mut Chip8) -> usize {
+    // 21C: LD V6, V0
+    vm.registers[0x6] = vm.registers[0x0];
+    // 21E: LD V7, 0x0F
+    vm.registers[0x7] = 0x0F;
+    // 220: AND V6, V7
+    vm.registers[0x6] &= vm.registers[0x7];
+    vm.registers[0xF] = 0;
+    // 222: SKNP V6
+    vm.pc = if !vm.keypad.is_key_pressed(Key::ALL[vm.registers[0x6] as usize]) { 0x226 } else { 0x224 };
+    4
+}
+
+fn block_224(vm: &mut Chip8) -> usize {
+    // 224: ADD V5, 0x01
+    vm.registers[0x5] = vm.registers[0x5].wrapping_add(0x01);
+    vm.pc = 0x226;
+    1
+}
+
+fn block_226(vm: &mut Chip8) -> usize {
+    // 226: SNE V0, 0x30
+    // 228: JP 0x22C
+    if vm.registers[0x0] != 0x30 {
+        vm.pc = 0x22A;
+        return 1;
+    }
+    vm.pc = 0x22C;
+    2
+}
+
+fn block_228(vm: &mut Chip8) -> usize {
+    // 228: JP 0x22C
+    vm.pc = 0x22C;
+    1
+}
+
+fn block_22a(vm: &mut Chip8) -> usize {
+    // 22A: JP 0x204
+    vm.pc = 0x204;
+    1
+}
+
+fn block_22c(vm: &mut Chip8) -> usize {
+    // 22C: LD VE, V0
+    vm.registers[0xE] = vm.registers[0x0];
+    // 22E: LD V0, 0x7A
+    vm.registers[0x0] = 0x7A;
+    // 230: LD V1, 0x05
+    vm.registers[0x1] = 0x05;
+    // 232: LD I, 0x250
+    vm.pointer = 0x250;
+    vm.pc = 0x234;
+    4
+}
+
+fn block_236(vm: &mut Chip8) -> usize {
+    // 236: LD V0, VE
+    vm.registers[0x0] = vm.registers[0xE];
+    // 238: LD V1, 0x01
+    vm.registers[0x1] = 0x01;
+    // 23A: CALL 0x250
+    if vm.is_stack_full() {
+        vm.pc = 0x23A;
+        return 2;
+    }
+    vm.stack.push(0x23C);
+    vm.pc = 0x250;
+    3
+}
+
+fn block_23e(vm: &mut Chip8) -> usize {
+    // 23E: LD I, 0x25E
+    vm.pointer = 0x25E;
+    vm.pc = 0x240;
+    1
+}
+
+fn block_242(vm: &mut Chip8) -> usize {
+    // 242: LD V2, [I]
+    for i in 0..=0x2 {
+        vm.registers[i] = vm.memory[vm.pointer as usize + i];
+    }
+    vm.pointer += 3;
+    // 244: LD F, V2
+    vm.pointer = vm.font_address + font::get_letter_address(vm.registers[0x2]);
+    // 246: ADD I, V1
+    vm.pointer += vm.registers[0x1] as u16;
+    // 248: LD VD, DT
+    vm.registers[0xD] = vm.timers.delay as u8;
+    // 24A: LD V0, 0x00
+    vm.registers[0x0] = 0x00;
+    vm.pc = 0x24C;
+    5
+}
+
+fn block_250(vm: &mut Chip8) -> usize {
+    // 250: ADD VB, 0x01
+    vm.registers[0xB] = vm.registers[0xB].wrapping_add(0x01);
+    // 252: SE VB, 0x40
+    // 254: JP 0x250
+    if vm.registers[0xB] == 0x40 {
+        vm.pc = 0x256;
+        return 2;
+    }
+    vm.pc = 0x250;
+    3
+}
+
+fn block_254(vm: &mut Chip8) -> usize {
+    // 254: JP 0x250
+    vm.pc = 0x250;
+    1
+}
+
+fn block_256(vm: &mut Chip8) -> usize {
+    // 256: RET
+    match vm.stack.pop() {
+        Some(address) => {
+            vm.pc = address;
+            1
+        }
+        None => {
+            vm.pc = 0x256;
+            0
+        }
+    }
+}
+
+fn block_258(vm: &mut Chip8) -> usize {
+    // 258: LD F, V3
+    vm.pointer = vm.font_address + font::get_letter_address(vm.registers[0x3]);
+    vm.pc = 0x25A;
+    1
+}
+
+fn block_25c(vm: &mut Chip8) -> usize {
+    // 25C: RET
+    match vm.stack.pop() {
+        Some(address) => {
+            vm.pc = address;
+            1
+        }
+        None => {
+            vm.pc = 0x25C;
+            0
+        }
+    }
+}
@@ -1,9 +1,23 @@
 pub mod utils;
 
 use std::convert::TryInto;
+use std::io;
 use std::ops::{Deref, DerefMut};
-
+use std::time::Duration;
+
+use chip8_core::audio::AudioGenerator;
+use chip8_core::blocks::Engine;
+use chip8_core::capture::{self, Recorder, RecordingFormat};
+use chip8_core::display::DirtyRegion;
+use chip8_core::effects::{BlendMode, Persistence, PersistenceMode};
+use chip8_core::frontend::{
+    AudioSink, Input, InputSource, RunStatus, Runner, Sound, TimeSource, VideoSink,
+};
+use chip8_core::handler::HookId;
+use chip8_core::keypad::{Key, KeyMap, KeyMapPreset};
+use chip8_core::profile::Profile;
 use chip8_core::quirks::QuirkConfig;
+use chip8_core::render::{Palette, PalettePreset, Renderer};
 use chip8_core::time::Timers;
 pub use chip8_core::Chip8;
 use wasm_bindgen::prelude::*;
@@ -21,21 +35,86 @@ extern "C" {
     fn alert(s: &str);
 }
 
+/// Advances the persistence effect after every frame, the page draws the image itself with `render_rgba`
 #[derive(Default)]
+struct Effects {
+    persistence: Persistence,
+    /// Whether the effect changed the image since `reset_dirty` was last called
+    changed: bool,
+}
+
+impl VideoSink for Effects {
+    fn present(&mut self, system: &Chip8, _: &RunStatus) -> io::Result<()> {
+        if self.persistence.is_enabled() {
+            self.persistence.update(&system.display);
+            self.changed |= self.persistence.changed();
+        }
+        Ok(())
+    }
+}
+
+/// The page plays the tone, checking `sound_on` in its audio callback
+struct Silent;
+
+impl AudioSink for Silent {
+    fn update(&mut self, _: &Chip8, _: Sound) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keys reach the VM directly through `key_down` and `key_up`
+struct NoInput;
+
+impl InputSource for NoInput {
+    fn poll(&mut self) -> io::Result<Option<Input>> {
+        Ok(None)
+    }
+}
+
+/// The page's clock, since `std::time::Instant` isn't available in the browser
+struct BrowserClock;
+
+impl TimeSource for BrowserClock {
+    fn now(&mut self) -> Duration {
+        Duration::from_secs_f64(js_sys::Date::now() / 1000.0)
+    }
+
+    /// The page decides when frames run, so nothing waits on this
+    fn sleep_until(&mut self, _: Duration) {}
+}
+
 #[wasm_bindgen(js_name = "Chip8")]
-pub struct WasmChip8(Chip8);
+pub struct WasmChip8 {
+    chip8: Chip8,
+    runner: Runner<'static, Effects, Silent, NoInput, BrowserClock>,
+    renderer: Renderer,
+    recorder: Option<Recorder>,
+    keymap: KeyMap,
+}
+
+impl Default for WasmChip8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Deref for WasmChip8 {
     type Target = Chip8;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.chip8
     }
 }
 
 impl DerefMut for WasmChip8 {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.chip8
+    }
+}
+
+impl WasmChip8 {
+    fn effects(&mut self) -> &mut Effects {
+        self.runner.video_mut()
     }
 }
 
@@ -43,27 +122,196 @@ impl DerefMut for WasmChip8 {
 impl WasmChip8 {
     #[wasm_bindgen(constructor)]
     pub fn new() -> WasmChip8 {
-        WasmChip8(Chip8::new())
+        WasmChip8 {
+            chip8: Chip8::new(),
+            runner: Runner::with_time(Effects::default(), Silent, NoInput, BrowserClock, 10),
+            renderer: Renderer::default(),
+            recorder: None,
+            keymap: KeyMap::default(),
+        }
     }
 
+    /// Restarts the loaded ROM
     pub fn reset(&mut self) {
-        self.0.reset();
+        self.chip8.soft_reset();
+    }
+
+    /// Wipes the VM, including the ROM, keeping the settings
+    pub fn hard_reset(&mut self) {
+        self.chip8.hard_reset();
+    }
+
+    /// Picks between the interpreter and the faster block engine
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.chip8.set_engine(engine);
     }
 
     pub fn load_default(&mut self) {
-        self.memory[0x200..0x200 + DEFAULT_ROM.len()].copy_from_slice(DEFAULT_ROM);
+        self.chip8
+            .load_rom(DEFAULT_ROM, 0x200)
+            .expect("The default ROM fits in memory");
     }
 
     pub fn load_rom(&mut self, rom: &[u8]) {
-        if rom.len() >= self.memory.len() - 0x200 {
-            log::warn!("Rom too large, there may be errors");
+        if let Err(err) = self.chip8.load_rom(rom, 0x200) {
+            log::warn!("{err}");
+        }
+    }
+
+    /// Runs a 60 Hz frame of up to `cycles` instructions, and advances the persistence effect
+    pub fn run_frame(&mut self, cycles: usize) {
+        self.runner.set_cycles_per_frame(cycles);
+        self.runner
+            .step_frame(&mut self.chip8)
+            .expect("The web frontend doesn't do IO");
+    }
+
+    /// The display packed 8 pixels per byte, leftmost pixel in the most significant bit
+    pub fn packed_display(&self) -> Vec<u8> {
+        self.display.to_packed_bytes()
+    }
+
+    /// Renders the display into the RGBA image buffer and returns a pointer to it.
+    ///
+    /// The buffer is `rgba_width() * rgba_height() * 4` bytes long, and lives in wasm memory,
+    /// so it can be wrapped in an `ImageData` without copying.
+    /// The pointer is invalidated if the scale or display size changes.
+    /// A persistence effect shows the state as of the last `run_frame`.
+    pub fn render_rgba(&mut self) -> *const u8 {
+        let persistence = &self.runner.video().persistence;
+        if persistence.is_enabled() && !persistence.intensity().is_empty() {
+            self.renderer
+                .render_intensity(
+                    persistence.intensity(),
+                    persistence.width(),
+                    persistence.height(),
+                )
+                .as_ptr()
+        } else {
+            self.renderer.render(&self.chip8.display).as_ptr()
+        }
+    }
+
+    /// Makes pixels fade out over several frames, `decay` is the fraction of brightness lost per frame
+    pub fn set_phosphor(&mut self, decay: f32) {
+        self.effects()
+            .persistence
+            .set_mode(PersistenceMode::Phosphor { decay });
+        self.effects().changed = true;
+    }
+
+    /// Combines the last `frames` frames, either lighting a pixel if it was on in any of them,
+    /// or averaging its brightness
+    pub fn set_frame_blend(&mut self, frames: usize, average: bool) {
+        let mode = if average {
+            BlendMode::Average
+        } else {
+            BlendMode::Or
+        };
+        self.effects()
+            .persistence
+            .set_mode(PersistenceMode::Blend { frames, mode });
+        self.effects().changed = true;
+    }
+
+    pub fn disable_persistence(&mut self) {
+        self.effects().persistence.set_mode(PersistenceMode::None);
+        self.effects().changed = true;
+    }
+
+    pub fn rgba_width(&self) -> usize {
+        self.renderer.width()
+    }
+
+    pub fn rgba_height(&self) -> usize {
+        self.renderer.height()
+    }
+
+    /// Sets how many image pixels wide each CHIP-8 pixel is
+    pub fn set_scale(&mut self, scale: usize) {
+        self.renderer.set_scale(scale);
+    }
+
+    pub fn set_palette_preset(&mut self, preset: PalettePreset) {
+        self.renderer.palette = Palette::preset(preset);
+    }
+
+    /// Sets one palette entry. 0 is the background and 1 the foreground,
+    /// 2 and 3 are used by the second XO-CHIP plane.
+    pub fn set_palette_color(&mut self, index: usize, red: u8, green: u8, blue: u8) {
+        if let Some(color) = self.renderer.palette.colors.get_mut(index) {
+            *color = [red, green, blue, 0xFF];
+        }
+    }
+
+    /// The current display as a PNG file, using the renderer's palette and scale
+    pub fn screenshot_png(&self) -> Result<Vec<u8>, JsError> {
+        Ok(capture::screenshot_png(
+            &self.display,
+            &self.renderer.palette,
+            self.renderer.scale(),
+        )?)
+    }
+
+    /// Starts recording the display, discarding any unfinished recording
+    pub fn start_recording(&mut self) {
+        self.recorder = Some(Recorder::new(self.renderer.palette, self.renderer.scale()));
+    }
+
+    /// Adds the current display to the recording, call this once per 60 Hz frame
+    pub fn record_frame(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.capture(&self.chip8.display);
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Stops recording and returns the encoded GIF, or APNG if `apng` is true
+    pub fn finish_recording(&mut self, apng: bool) -> Result<Vec<u8>, JsError> {
+        let recorder = self
+            .recorder
+            .take()
+            .ok_or_else(|| JsError::new("Not recording"))?;
+        let format = if apng {
+            RecordingFormat::Apng
         } else {
-            self.memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+            RecordingFormat::Gif
+        };
+        Ok(recorder.encode(format)?)
+    }
+
+    /// Increases every time the display changes
+    pub fn display_frame(&self) -> u32 {
+        self.display.frame()
+    }
+
+    /// The area of the display that changed since `reset_dirty` was last called, if any.
+    ///
+    /// While a persistence effect is changing pixels, this is the whole display.
+    pub fn dirty_region(&self) -> Option<DirtyRegion> {
+        if self.runner.video().changed {
+            return Some(DirtyRegion {
+                x: 0,
+                y: 0,
+                width: self.display.get_width(),
+                height: self.display.get_height(),
+            });
         }
+        self.chip8.dirty_region()
+    }
+
+    /// The rows of the display that changed since `reset_dirty` was last called
+    pub fn dirty_rows(&self) -> Vec<u32> {
+        self.display.dirty_rows().map(|row| row as u32).collect()
     }
 
-    pub fn get_display_pointer(&self) -> *const bool {
-        self.display.pixels.as_ptr()
+    pub fn reset_dirty(&mut self) {
+        self.chip8.reset_dirty();
+        self.effects().changed = false;
     }
 
     pub fn get_ram_pointer(&self) -> *const u8 {
@@ -96,6 +344,78 @@ impl WasmChip8 {
         self.release_key(key.try_into().expect("Already checked range"));
     }
 
+    /// Presses the CHIP-8 key for a host key, identified by its lowercase `KeyboardEvent.key`
+    /// # Returns
+    /// Returns false if the host key isn't mapped, so the event can be left to the page
+    pub fn host_key_down(&mut self, host: &str) -> bool {
+        match self.keymap.get(host) {
+            Some(key) => {
+                self.chip8.press_key(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Releases the CHIP-8 key for a host key, see `host_key_down`
+    pub fn host_key_up(&mut self, host: &str) -> bool {
+        match self.keymap.get(host) {
+            Some(key) => {
+                self.chip8.release_key(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn use_keymap_preset(&mut self, preset: KeyMapPreset) {
+        self.keymap = preset.into();
+    }
+
+    /// Replaces the keymap with one parsed from a config file
+    pub fn load_keymap(&mut self, config: &str) -> Result<(), JsError> {
+        self.keymap = config.parse()?;
+        Ok(())
+    }
+
+    /// Applies a TOML or JSON profile, the same files the command line takes with `--profile`
+    ///
+    /// Returns the profile's instructions per frame, if it has them, for the frontend to run at.
+    /// The keymap, palette and persistence effect replace the current ones.
+    /// The memory layout settings are ignored, since the VM is already built.
+    pub fn load_profile(&mut self, text: &str) -> Result<Option<usize>, JsError> {
+        // a JSON profile is an object, which can't start a TOML file
+        let profile: Profile = if text.trim_start().starts_with('{') {
+            serde_json::from_str(text)?
+        } else {
+            toml::from_str(text)?
+        };
+        profile.apply(&mut self.chip8);
+        if let Some(keymap) = profile.keymap {
+            self.keymap = keymap;
+        }
+        if let Some(palette) = profile.palette {
+            self.renderer.palette = palette;
+        }
+        if let Some(mode) = profile.persistence {
+            let effects = self.effects();
+            effects.persistence.set_mode(mode);
+            effects.changed = true;
+        }
+        Ok(profile.cycles_per_frame)
+    }
+
+    /// The keymap as a config file, for saving
+    pub fn keymap_config(&self) -> String {
+        self.keymap.to_string()
+    }
+
+    /// The host key to show on the on-screen keypad for a CHIP-8 key
+    pub fn key_hint(&self, key: u8) -> Option<String> {
+        let key = Key::ALL.get(key as usize)?;
+        self.keymap.hint(*key).map(str::to_owned)
+    }
+
     pub fn exec_instruction(&mut self, opcode: u16) {
         let instruction = opcode.into();
         log::debug!("Executing {:?}", instruction);
@@ -115,7 +435,63 @@ impl WasmChip8 {
     }
 
     pub fn get_wait_register(&self) -> String {
-        format!("{:X?}", self.key_wait_register)
+        format!("{:X?}", self.key_wait)
+    }
+
+    /// Calls `callback` whenever `event` happens, until it's passed to `remove_listener`
+    ///
+    /// The events, and the arguments their callbacks get, are `draw` (x, y, collision), `clear`,
+    /// `sound_start`, `sound_stop`, `key_wait` (register), `halt`, `error` (message)
+    /// and `resolution_change` (width, height).
+    pub fn add_listener(
+        &mut self,
+        event: &str,
+        callback: js_sys::Function,
+    ) -> Result<HookId, JsError> {
+        let call = move |args: &[JsValue]| {
+            let args: js_sys::Array = args.iter().collect();
+            if let Err(err) = callback.apply(&JsValue::NULL, &args) {
+                log::error!("Event listener failed: {err:?}");
+            }
+        };
+        let id = match event {
+            "draw" => self
+                .on_draw(move |draw| call(&[draw.x.into(), draw.y.into(), draw.collision.into()])),
+            "clear" => self.on_clear(move || call(&[])),
+            "sound_start" => self.on_sound_start(move || call(&[])),
+            "sound_stop" => self.on_sound_stop(move || call(&[])),
+            "key_wait" => self.on_key_wait(move |register| call(&[(*register as u32).into()])),
+            "halt" => self.on_halt(move || call(&[])),
+            "error" => self.on_error(move |err| call(&[err.to_string().into()])),
+            "resolution_change" => self.on_resolution_change(move |resolution| {
+                call(&[
+                    (resolution.width as u32).into(),
+                    (resolution.height as u32).into(),
+                ])
+            }),
+            _ => return Err(JsError::new(&format!("Unknown event {event:?}"))),
+        };
+        Ok(id)
+    }
+
+    /// Calls `callback` with the address and bytes whenever the program writes to memory in `start..end`
+    pub fn add_memory_listener(
+        &mut self,
+        start: usize,
+        end: usize,
+        callback: js_sys::Function,
+    ) -> HookId {
+        self.on_memory_write(start..end, move |write| {
+            let bytes = js_sys::Uint8Array::from(write.bytes.as_slice());
+            if let Err(err) = callback.call2(&JsValue::NULL, &write.address.into(), &bytes) {
+                log::error!("Memory listener failed: {err:?}");
+            }
+        })
+    }
+
+    /// Removes a listener added with `add_listener` or `add_memory_listener`
+    pub fn remove_listener(&mut self, id: HookId) -> bool {
+        self.remove_hook(id)
     }
 
     pub fn change_quirk(&mut self, quirk_name: &str, new_val: bool) {
@@ -124,6 +500,8 @@ impl WasmChip8 {
             "save_load_set_pointer" => self.quirks.save_load_set_pointer = new_val,
             "display_wait" => self.quirks.display_wait = new_val,
             "partial_wrap" => self.quirks.partial_wrap = new_val,
+            "key_wait_release" => self.quirks.key_wait_release = new_val,
+            "key_wait_beep" => self.quirks.key_wait_beep = new_val,
             "alt_shift" => self.quirks.alt_shift = new_val,
             "alt_rel_jump" => self.quirks.alt_rel_jump = new_val,
             _ => log::error!("Invalid quirk name queried {}", quirk_name),
@@ -136,7 +514,7 @@ impl WasmChip8 {
     }
 
     pub fn current_instruction(&self) -> u16 {
-        self.0.get_u16(self.pc as usize)
+        self.chip8.get_u16(self.pc as usize)
     }
 
     pub fn get_instruction(&self, index: usize) -> u16 {
@@ -158,6 +536,17 @@ impl WasmChip8 {
         self.timers.clone()
     }
 
+    /// Whether the sound timer is active, feed this to an `AudioGenerator` each audio callback
+    #[wasm_bindgen(getter)]
+    pub fn sound_on(&self) -> bool {
+        self.timers.is_sound_on()
+    }
+
+    /// Fills `output` with the current sound, including any XO-CHIP audio pattern
+    pub fn render_audio(&self, generator: &mut AudioGenerator, output: &mut [f32]) {
+        generator.render_chip8(&self.chip8, output);
+    }
+
     #[wasm_bindgen(getter)]
     pub fn running(&self) -> bool {
         self.running
@@ -102,6 +102,8 @@ export function matchPreset(config: QuirkConfig): QuirkPresets | undefined {
         config.save_load_set_pointer &&
         config.display_wait &&
         !config.partial_wrap &&
+        config.key_wait_release &&
+        config.key_wait_beep &&
         !config.alt_shift &&
         !config.alt_rel_jump
     ) return QuirkPresets.Chip8;
@@ -111,6 +113,8 @@ export function matchPreset(config: QuirkConfig): QuirkPresets | undefined {
         !config.save_load_set_pointer &&
         !config.display_wait &&
         !config.partial_wrap &&
+        !config.key_wait_release &&
+        !config.key_wait_beep &&
         config.alt_shift &&
         !config.alt_rel_jump
     ) return QuirkPresets.SuperChip;
@@ -120,6 +124,8 @@ export function matchPreset(config: QuirkConfig): QuirkPresets | undefined {
         config.save_load_set_pointer &&
         !config.display_wait &&
         !config.partial_wrap &&
+        config.key_wait_release &&
+        !config.key_wait_beep &&
         !config.alt_shift &&
         !config.alt_rel_jump
     ) return QuirkPresets.XoChip;
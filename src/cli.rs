@@ -0,0 +1,605 @@
+//! Command line arguments and errors
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chip8_core::asm::AssembleError;
+use chip8_core::blocks;
+use chip8_core::capture::CaptureError;
+use chip8_core::headless::StopCondition;
+use chip8_core::keypad::{Key, KeyMap, KeyMapPreset, ParseKeyMapError};
+use chip8_core::movie::{Movie, ParseMovieError};
+use chip8_core::profile::Profile;
+use chip8_core::quirks::{QuirkOverrides, QuirkPresets};
+use chip8_core::render::Palette;
+use chip8_core::{BuildError, Chip8, Chip8Builder, DecodingError};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use thiserror::Error;
+
+use crate::tui;
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("Couldn't read {}: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Couldn't write {}: {source}", .path.display())]
+    Write { path: PathBuf, source: io::Error },
+    #[error("Couldn't load {}: {source}", .path.display())]
+    Load { path: PathBuf, source: BuildError },
+    #[error("Error assembling {}: {source}", .path.display())]
+    Assemble {
+        path: PathBuf,
+        source: AssembleError,
+    },
+    #[error(transparent)]
+    Decoding(#[from] DecodingError),
+    #[error(transparent)]
+    Capture(#[from] CaptureError),
+    #[error("Invalid keymap {keymap:?}: {reason}")]
+    Keymap { keymap: String, reason: String },
+    #[error("Couldn't load {}: {source}", .path.display())]
+    InvalidKeymapFile {
+        path: PathBuf,
+        source: ParseKeyMapError,
+    },
+    #[error("Invalid profile {}: {reason}", .path.display())]
+    InvalidProfile { path: PathBuf, reason: String },
+    #[error("Terminal error: {0}")]
+    Terminal(io::Error),
+    #[error("{} isn't a valid text or PBM display dump", .path.display())]
+    InvalidDump { path: PathBuf },
+    #[error("Couldn't load {}: {source}", .path.display())]
+    InvalidMovie {
+        path: PathBuf,
+        source: ParseMovieError,
+    },
+}
+
+/// A CHIP-8 emulator
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// only one command is parsed, at startup, so the size of the run command doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run a ROM in the terminal, or without any display for a fixed number of frames
+    Run {
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[command(flatten)]
+        headless: HeadlessArgs,
+        #[command(flatten)]
+        keymap: KeymapArgs,
+        /// Record key presses to a movie file that replays the run exactly
+        #[arg(long, conflicts_with = "movie")]
+        record_movie: Option<PathBuf>,
+        /// Replay a movie, which also sets the seed, quirks and instructions per frame it was recorded with
+        #[arg(long)]
+        movie: Option<PathBuf>,
+    },
+    /// Write a keymap config file, to edit and use with --keymap-file
+    Keymap {
+        #[command(flatten)]
+        keymap: KeymapArgs,
+        /// Write the config to a file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Run a ROM in the line based debugger
+    Debug {
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Print every instruction as it is executed
+    Trace {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Number of 60 Hz frames to run for
+        #[arg(long, default_value_t = 60)]
+        frames: usize,
+        /// Also print the registers after each instruction
+        #[arg(long)]
+        registers: bool,
+    },
+    /// Disassemble a ROM into source the `asm` command accepts
+    Disasm {
+        rom: PathBuf,
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_addr: u16,
+        /// Write the source to a file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Also write a source map of the output, requires --output
+        #[arg(long, requires = "output")]
+        source_map: Option<PathBuf>,
+    },
+    /// Assemble source into a ROM
+    Asm {
+        source: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_addr: u16,
+        /// Also write a source map, for coverage reports
+        #[arg(long)]
+        source_map: Option<PathBuf>,
+    },
+    /// Translate a ROM into a Rust module that runs it with chip8_core, for standalone builds
+    Transpile {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Write the module to a file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Show information about a ROM
+    Info {
+        rom: PathBuf,
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        load_addr: u16,
+    },
+    /// Run a test ROM without a display, then print the screen and code coverage
+    Test {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Number of 60 Hz frames to run for
+        #[arg(long, default_value_t = 300)]
+        frames: usize,
+        /// Write a disassembly annotated with execution counts
+        #[arg(long)]
+        annotate: Option<PathBuf>,
+        /// Write an LCOV coverage report, requires --source-map
+        #[arg(long, requires = "source_map")]
+        lcov: Option<PathBuf>,
+        /// Source map written by the `asm` command
+        #[arg(long)]
+        source_map: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl From<Preset> for QuirkPresets {
+    fn from(preset: Preset) -> Self {
+        match preset {
+            Preset::Chip8 => QuirkPresets::Chip8,
+            Preset::SuperChip => QuirkPresets::SuperChip,
+            Preset::XoChip => QuirkPresets::XoChip,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Engine {
+    Interpreter,
+    Blocks,
+}
+
+impl From<Engine> for blocks::Engine {
+    fn from(engine: Engine) -> Self {
+        match engine {
+            Engine::Interpreter => blocks::Engine::Interpreter,
+            Engine::Blocks => blocks::Engine::Blocks,
+        }
+    }
+}
+
+/// Options for setting up the VM and loading a ROM
+#[derive(Args, Debug)]
+pub struct MachineArgs {
+    pub rom: PathBuf,
+    /// TOML or JSON file with settings for the ROM, which the other options override
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+    /// Quirk preset, individual quirk flags are applied on top of it [default: chip8]
+    #[arg(long, value_enum)]
+    pub preset: Option<Preset>,
+    /// Reset VF on AND, OR and XOR
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub flag_reset: Option<bool>,
+    /// Move I past the registers on FX55 and FX65
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub save_load_set_pointer: Option<bool>,
+    /// Wait for the next frame after drawing
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub display_wait: Option<bool>,
+    /// Wrap sprites that are partially off screen
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub partial_wrap: Option<bool>,
+    /// Finish FX0A when the key is released rather than pressed
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub key_wait_release: Option<bool>,
+    /// Beep while FX0A waits for the key to be released
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub key_wait_beep: Option<bool>,
+    /// Shift VX in place, ignoring VY
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub alt_shift: Option<bool>,
+    /// BXNN jumps to XNN + VX
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub alt_rel_jump: Option<bool>,
+    /// Instructions per 60 Hz frame [default: 10]
+    #[arg(long, conflicts_with = "hz")]
+    pub cpf: Option<usize>,
+    /// Instructions per second, rounded down to a whole number per frame
+    #[arg(long)]
+    pub hz: Option<usize>,
+    /// Seed for the random number generator, for reproducible runs
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Address to load the ROM at [default: 0x200]
+    #[arg(long, value_parser = parse_address)]
+    pub load_addr: Option<u16>,
+    /// Cache decoded instructions, which is faster for ROMs that don't modify their own code much
+    #[arg(long)]
+    pub decode_cache: bool,
+    /// How to run instructions, `blocks` compiles straight-line code for speed [default: interpreter]
+    #[arg(long, value_enum)]
+    pub engine: Option<Engine>,
+}
+
+/// A VM set up by [`MachineArgs::load`]
+pub struct Machine {
+    pub system: Chip8,
+    pub rom: Vec<u8>,
+    pub cycles_per_frame: usize,
+    /// The profile's keymap, which the keymap options override
+    pub keymap: Option<KeyMap>,
+    pub palette: Palette,
+}
+
+impl MachineArgs {
+    /// The settings from the command line, falling back to the profile file if there is one
+    pub fn profile(&self) -> Result<Profile, CliError> {
+        let flags = Profile {
+            platform: self.preset.map(Into::into),
+            quirks: QuirkOverrides {
+                flag_reset: self.flag_reset,
+                save_load_set_pointer: self.save_load_set_pointer,
+                display_wait: self.display_wait,
+                partial_wrap: self.partial_wrap,
+                key_wait_release: self.key_wait_release,
+                key_wait_beep: self.key_wait_beep,
+                alt_shift: self.alt_shift,
+                alt_rel_jump: self.alt_rel_jump,
+            },
+            cycles_per_frame: self.hz.map(|hz| (hz / 60).max(1)).or(self.cpf),
+            engine: self.engine.map(Into::into),
+            seed: self.seed,
+            load_address: self.load_addr,
+            ..Default::default()
+        };
+        Ok(match &self.profile {
+            Some(path) => flags.or(read_profile(path)?),
+            None => flags,
+        })
+    }
+
+    /// Creates a VM with the ROM loaded and the profile, quirks and seed applied
+    pub fn load(&self) -> Result<Machine, CliError> {
+        let rom = read(&self.rom)?;
+        let profile = self.profile()?;
+        let mut system = profile
+            .configure(Chip8Builder::new())
+            .rom(&rom)
+            .build()
+            .map_err(|source| CliError::Load {
+                path: self.rom.clone(),
+                source,
+            })?;
+        if self.decode_cache {
+            system.enable_decode_cache();
+        }
+        Ok(Machine {
+            system,
+            rom,
+            cycles_per_frame: profile.cycles_per_frame.unwrap_or(10),
+            keymap: profile.keymap,
+            palette: profile.palette.unwrap_or_default(),
+        })
+    }
+}
+
+/// Reads a profile, as JSON if the file ends in `.json` and TOML otherwise
+pub fn read_profile(path: &Path) -> Result<Profile, CliError> {
+    let text = String::from_utf8_lossy(&read(path)?).into_owned();
+    let is_json = path
+        .extension()
+        .is_some_and(|extension| extension == "json");
+    let parsed = if is_json {
+        serde_json::from_str(&text).map_err(|err| err.to_string())
+    } else {
+        toml::from_str(&text).map_err(|err| err.to_string())
+    };
+    parsed.map_err(|reason| CliError::InvalidProfile {
+        path: path.to_owned(),
+        reason,
+    })
+}
+
+/// Options for choosing which host keys press the CHIP-8 keys
+#[derive(Args, Debug)]
+pub struct KeymapArgs {
+    /// Keyboard layout: qwerty (the default), azerty, numpad or vip,
+    /// or the host keys for CHIP-8 keys 0 to F in order, such as "x123qweasdzc4rfv"
+    #[arg(long, conflicts_with = "keymap_file")]
+    pub keymap: Option<String>,
+    /// Keymap config file, as written by the `keymap` command
+    #[arg(long)]
+    pub keymap_file: Option<PathBuf>,
+}
+
+impl KeymapArgs {
+    /// The keymap from the options, or `fallback` if neither is given
+    pub fn load(&self, fallback: Option<KeyMap>) -> Result<KeyMap, CliError> {
+        let from_profile = fallback.is_some();
+        let keymap = match (&self.keymap, &self.keymap_file) {
+            (Some(keymap), _) => parse_keymap(keymap)?,
+            (None, Some(path)) => {
+                String::from_utf8_lossy(&read(path)?)
+                    .parse()
+                    .map_err(|source| CliError::InvalidKeymapFile {
+                        path: path.clone(),
+                        source,
+                    })?
+            }
+            (None, None) => fallback.unwrap_or_default(),
+        };
+        if let Some(reserved) = tui::RESERVED_KEYS
+            .into_iter()
+            .find(|&reserved| keymap.get(reserved).is_some())
+        {
+            let source = self
+                .keymap_file
+                .as_ref()
+                .map(|path| path.display().to_string());
+            let profile = from_profile.then(|| "the profile's keymap".to_owned());
+            return Err(CliError::Keymap {
+                keymap: self
+                    .keymap
+                    .clone()
+                    .or(source)
+                    .or(profile)
+                    .unwrap_or_default(),
+                reason: format!("{reserved:?} is used by the emulator"),
+            });
+        }
+        Ok(keymap)
+    }
+}
+
+/// Options for running without the terminal display, used when `--frames` or `--until` is given
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("headless").multiple(true).args(["frames", "until"])))]
+pub struct HeadlessArgs {
+    /// Run for at most this many 60 Hz frames without a display, then exit
+    #[arg(long)]
+    pub frames: Option<usize>,
+    /// Stop early when a condition is met: halt, self-jump, pc=<address> or mem=<address>:<value>.
+    /// Can be given more than once, exits with status 4 if none are met.
+    #[arg(long)]
+    pub until: Vec<StopCondition>,
+    /// Write the final display as text, or PBM or PNG depending on the extension
+    #[arg(long, requires = "headless")]
+    pub dump: Option<PathBuf>,
+    /// Compare the final display to a text or PBM dump, exits with status 3 if they differ
+    #[arg(long, requires = "headless")]
+    pub golden: Option<PathBuf>,
+    /// Write the golden file from the final display instead of comparing against it
+    #[arg(long, requires = "golden")]
+    pub update_golden: bool,
+    /// Write the sound to a WAV file
+    #[arg(long, requires = "headless")]
+    pub wav: Option<PathBuf>,
+    /// Write the sound timer for every frame to a CSV file
+    #[arg(long, requires = "headless")]
+    pub sound_log: Option<PathBuf>,
+    /// Write a PNG of the final frame
+    #[arg(long, requires = "headless")]
+    pub screenshot: Option<PathBuf>,
+    /// Record every frame to a GIF, or an animated PNG for any other extension
+    #[arg(long, requires = "headless")]
+    pub record: Option<PathBuf>,
+    /// Image pixels per CHIP-8 pixel for screenshots and recordings
+    #[arg(long, default_value_t = 8)]
+    pub scale: usize,
+}
+
+impl HeadlessArgs {
+    pub fn is_headless(&self) -> bool {
+        self.frames.is_some() || !self.until.is_empty()
+    }
+}
+
+/// Parses an address in hex with a `0x` prefix, or decimal
+fn parse_address(text: &str) -> Result<u16, String> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|err| err.to_string())
+}
+
+/// Parses a keymap given as a preset name, or as the host keys for CHIP-8 keys 0 to F
+pub fn parse_keymap(keymap: &str) -> Result<KeyMap, CliError> {
+    if let Ok(preset) = keymap.parse::<KeyMapPreset>() {
+        return Ok(preset.into());
+    }
+    let error = |reason: &str| CliError::Keymap {
+        keymap: keymap.to_owned(),
+        reason: reason.to_owned(),
+    };
+
+    let host_keys: Vec<char> = keymap.chars().collect();
+    if host_keys.len() != 16 {
+        return Err(error("it needs to be a preset or exactly 16 keys"));
+    }
+    let mut mapping = KeyMap::empty();
+    for (host, key) in host_keys.iter().zip(Key::ALL) {
+        if mapping.bind(&host.to_string(), key).is_some() {
+            return Err(error(&format!("{host:?} is used more than once")));
+        }
+    }
+    Ok(mapping)
+}
+
+pub fn read_movie(path: &Path) -> Result<Movie, CliError> {
+    String::from_utf8_lossy(&read(path)?)
+        .parse()
+        .map_err(|source| CliError::InvalidMovie {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+pub fn read(path: &Path) -> Result<Vec<u8>, CliError> {
+    std::fs::read(path).map_err(|source| CliError::Read {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), CliError> {
+    std::fs::write(path, contents).map_err(|source| CliError::Write {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8_core::effects::{BlendMode, PersistenceMode};
+    use chip8_core::render::PalettePreset;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn quirk_flags() {
+        let cli = Cli::parse_from([
+            "chip8_emu",
+            "run",
+            "rom.ch8",
+            "--preset",
+            "super-chip",
+            "--display-wait",
+            "--alt-shift=false",
+            "--load-addr",
+            "0x300",
+        ]);
+        let Command::Run { machine, .. } = cli.command else {
+            panic!("Expected the run command");
+        };
+        assert_eq!(machine.preset, Some(Preset::SuperChip));
+        assert_eq!(machine.display_wait, Some(true));
+        assert_eq!(machine.alt_shift, Some(false));
+        assert_eq!(machine.flag_reset, None);
+        assert_eq!(machine.load_addr, Some(0x300));
+    }
+
+    #[test]
+    fn keymaps() {
+        let keymap = parse_keymap("x123qweasdzc4rfv").unwrap();
+        assert_eq!(keymap.get("x"), Some(Key::Key0));
+        assert_eq!(keymap.get("V"), Some(Key::KeyF));
+        assert_eq!(parse_keymap("AZERTY").unwrap(), KeyMapPreset::Azerty.into());
+        assert!(parse_keymap("x123").is_err());
+        assert!(parse_keymap("x123qweasdzc4rfx").is_err());
+
+        let keymap = |keymap: &str| KeymapArgs {
+            keymap: Some(keymap.to_owned()),
+            keymap_file: None,
+        };
+        assert!(keymap("vip").load(None).is_ok());
+        assert!(keymap("x123qweasdzc4rfp").load(None).is_err());
+
+        // the options take priority over the profile, which still can't use reserved keys
+        let profile = Some(KeyMapPreset::Numpad.into());
+        assert_eq!(
+            keymap("vip").load(profile.clone()).unwrap(),
+            KeyMapPreset::Vip.into()
+        );
+        let no_options = KeymapArgs {
+            keymap: None,
+            keymap_file: None,
+        };
+        assert_eq!(
+            no_options.load(profile).unwrap(),
+            KeyMapPreset::Numpad.into()
+        );
+        let mut reserved = KeyMap::default();
+        reserved.bind("p", Key::Key0);
+        assert!(no_options.load(Some(reserved)).is_err());
+    }
+
+    #[test]
+    fn profile_round_trip() {
+        let mut keymap = KeyMap::from(KeyMapPreset::Vip);
+        keymap.bind("space", Key::Key5);
+        let profile = Profile {
+            platform: Some(QuirkPresets::XoChip),
+            quirks: QuirkOverrides {
+                alt_shift: Some(true),
+                ..Default::default()
+            },
+            cycles_per_frame: Some(200),
+            engine: Some(blocks::Engine::Blocks),
+            seed: Some(7),
+            keymap: Some(keymap),
+            palette: Some(Palette::preset(PalettePreset::Octo)),
+            persistence: Some(PersistenceMode::Blend {
+                frames: 3,
+                mode: BlendMode::Average,
+            }),
+            ..Default::default()
+        };
+
+        let toml = toml::to_string(&profile).unwrap();
+        assert_eq!(toml::from_str::<Profile>(&toml).unwrap(), profile);
+        let json = serde_json::to_string(&profile).unwrap();
+        assert_eq!(serde_json::from_str::<Profile>(&json).unwrap(), profile);
+    }
+
+    #[test]
+    fn profile_frontend_settings() {
+        let profile: Profile = toml::from_str(
+            r#"
+            keymap = "azerty"
+            persistence = { effect = "phosphor", decay = 0.5 }
+            palette = { colors = [[0, 0, 0, 255], [0, 255, 0, 255], [0, 255, 0, 255], [0, 255, 0, 255]] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(profile.keymap, Some(KeyMapPreset::Azerty.into()));
+        assert_eq!(
+            profile.persistence,
+            Some(PersistenceMode::Phosphor { decay: 0.5 })
+        );
+        assert_eq!(
+            profile.palette,
+            Some(Palette::two_color([0, 0, 0, 255], [0, 255, 0, 255]))
+        );
+
+        let keymap: Profile = toml::from_str("[keymap]\n0 = [\"x\"]\nF = [\"v\", \"b\"]").unwrap();
+        let keymap = keymap.keymap.unwrap();
+        assert_eq!(keymap.get("b"), Some(Key::KeyF));
+        assert_eq!(keymap.host_keys(Key::Key1), [] as [String; 0]);
+
+        assert!(toml::from_str::<Profile>("[keymap]\nG = [\"x\"]").is_err());
+        assert!(toml::from_str::<Profile>("[keymap]\n0 = [\"x\"]\n1 = [\"x\"]").is_err());
+    }
+}
@@ -1,18 +1,526 @@
+use chip8_core::asm;
+use chip8_core::audio::{self, AudioGenerator};
+use chip8_core::capture::{self, Recorder, RecordingFormat};
+use chip8_core::coverage::SourceMap;
+use chip8_core::display::Display;
+use chip8_core::headless::{self, RunOutcome, StopReason};
+use chip8_core::instruction::Instruction;
+use chip8_core::movie::Playback;
+use chip8_core::render::Palette;
 use chip8_core::*;
-use std::fs;
-use std::path::PathBuf;
-
-fn main() {
-    let mut system = Chip8::new();
-    // system.execute();
-
-    let rom_path: PathBuf = std::env::args()
-        .nth(1)
-        .expect("Rom path should be first arg")
-        .into();
-    // let program = fs::read("./roms/test_opcode.ch8").unwrap();
-    let program = fs::read(rom_path).unwrap();
-    system.memory[0x200..0x200 + program.len()].copy_from_slice(program.as_slice());
-    system.pc = 0x200;
-    system.run().unwrap();
+use clap::Parser;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+mod cli;
+mod tui;
+
+use cli::{Cli, CliError, Command, HeadlessArgs, MachineArgs};
+
+const SAMPLE_RATE: u32 = 44100;
+const FRAME_RATE: u32 = 60;
+/// Frame limit for headless runs given `--until` but not `--frames`, one minute of emulated time
+const DEFAULT_FRAME_LIMIT: usize = 60 * 60;
+
+// 1 is used for errors and 2 by clap for invalid arguments
+/// Exit status when the final display doesn't match the golden file
+const EXIT_GOLDEN_MISMATCH: u8 = 3;
+/// Exit status when none of the `--until` conditions were met
+const EXIT_CONDITION_NOT_MET: u8 = 4;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run_command(cli.command) {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_command(command: Command) -> Result<ExitCode, CliError> {
+    match command {
+        Command::Run {
+            machine,
+            headless,
+            keymap,
+            record_movie,
+            movie,
+        } => {
+            let movie = movie.as_deref().map(cli::read_movie).transpose()?;
+            let cli::Machine {
+                mut system,
+                cycles_per_frame,
+                keymap: profile_keymap,
+                palette,
+                ..
+            } = machine.load()?;
+            let keymap = keymap.load(profile_keymap)?;
+            let cycles_per_frame = movie
+                .as_ref()
+                .map_or(cycles_per_frame, |movie| movie.cycles_per_frame);
+            let playback = movie
+                .as_ref()
+                .map(|movie| movie.start_playback(&mut system));
+            if record_movie.is_some() {
+                system.start_recording(machine.seed);
+            }
+
+            let result = if headless.is_headless() {
+                run_headless(&mut system, cycles_per_frame, &palette, headless, playback)
+            } else {
+                tui::run(&mut system, cycles_per_frame, &keymap, playback)
+                    .map(|_| ExitCode::SUCCESS)
+                    .map_err(CliError::Terminal)
+            };
+            // save the movie even if the run failed, since that's when it's most useful
+            if let (Some(path), Some(movie)) = (record_movie, system.take_recording()) {
+                cli::write(&path, movie.to_string())?;
+            }
+            result
+        }
+        Command::Keymap { keymap, output } => {
+            let config = keymap.load(None)?.to_string();
+            match output {
+                Some(output) => cli::write(&output, config)?,
+                None => print!("{config}"),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Debug { machine } => {
+            let mut system = machine.load()?.system;
+            system.run()?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Trace {
+            machine,
+            frames,
+            registers,
+        } => trace(&machine, frames, registers).map(|_| ExitCode::SUCCESS),
+        Command::Disasm {
+            rom,
+            load_addr,
+            output,
+            source_map,
+        } => {
+            let source = asm::disassemble(&cli::read(&rom)?, load_addr);
+            match output {
+                Some(output) => {
+                    cli::write(&output, &source)?;
+                    if let Some(source_map) = source_map {
+                        // the disassembly always assembles, so this is the easiest way to map it
+                        let assembly = assemble(&source, &output, load_addr)?;
+                        cli::write(&source_map, assembly.source_map.to_string())?;
+                    }
+                }
+                None => print!("{source}"),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Asm {
+            source,
+            output,
+            load_addr,
+            source_map,
+        } => {
+            let text = String::from_utf8_lossy(&cli::read(&source)?).into_owned();
+            let assembly = assemble(&text, &source, load_addr)?;
+            cli::write(&output, &assembly.bytes)?;
+            if let Some(source_map) = source_map {
+                cli::write(&source_map, assembly.source_map.to_string())?;
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Transpile { machine, output } => {
+            let cli::Machine { system, rom, .. } = machine.load()?;
+            let name = machine
+                .rom
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let source = transpile::to_rust(&rom, system.load_address(), &system.quirks, &name);
+            match output {
+                Some(output) => cli::write(&output, &source)?,
+                None => print!("{source}"),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Info { rom, load_addr } => info(&rom, load_addr).map(|_| ExitCode::SUCCESS),
+        Command::Test {
+            machine,
+            frames,
+            annotate,
+            lcov,
+            source_map,
+        } => test(&machine, frames, annotate, lcov, source_map).map(|_| ExitCode::SUCCESS),
+    }
+}
+
+fn assemble(source: &str, path: &Path, load_address: u16) -> Result<asm::Assembly, CliError> {
+    asm::assemble(source, &path.display().to_string(), load_address).map_err(|source| {
+        CliError::Assemble {
+            path: path.to_owned(),
+            source,
+        }
+    })
+}
+
+/// Runs the ROM without a display until a stop condition or the frame limit,
+/// recording the audio, sound timer and display along the way
+fn run_headless(
+    system: &mut Chip8,
+    cycles_per_frame: usize,
+    palette: &Palette,
+    options: HeadlessArgs,
+    mut playback: Option<Playback>,
+) -> Result<ExitCode, CliError> {
+    // movies run to their end unless told otherwise
+    let max_frames = options
+        .frames
+        .or(playback
+            .as_ref()
+            .map(|playback| playback.movie().frames as usize))
+        .unwrap_or(DEFAULT_FRAME_LIMIT);
+    let mut generator = AudioGenerator::new(SAMPLE_RATE);
+    let mut samples = Vec::new();
+    let mut frame_samples = Vec::new();
+    let mut sound_log = String::from("frame,sound_timer\n");
+    let mut recorder = options
+        .record
+        .as_ref()
+        .map(|_| Recorder::new(*palette, options.scale));
+
+    let mut outcome = RunOutcome {
+        reason: StopReason::FrameLimit,
+        frames: 0,
+    };
+    for frame in 0..max_frames {
+        if let Some(playback) = &mut playback {
+            playback.apply_inputs(system);
+        }
+        let frame_outcome = headless::run_until(system, cycles_per_frame, 1, &options.until)?;
+        outcome = RunOutcome {
+            reason: frame_outcome.reason,
+            frames: frame + frame_outcome.frames,
+        };
+        sound_log.push_str(&format!("{},{}\n", frame, system.timers.sound));
+
+        frame_samples.resize(generator.samples_per_frame(FRAME_RATE), 0);
+        generator.render_chip8_i16(system, &mut frame_samples);
+        samples.extend_from_slice(&frame_samples);
+
+        if let Some(recorder) = &mut recorder {
+            recorder.capture(&system.display);
+        }
+        if frame_outcome.reason != StopReason::FrameLimit {
+            break;
+        }
+    }
+
+    if let Some(path) = options.wav {
+        let mut wav = Vec::new();
+        audio::write_wav(&mut wav, SAMPLE_RATE, &samples).expect("Writing to a Vec can't fail");
+        cli::write(&path, wav)?;
+    }
+
+    if let (Some(path), Some(recorder)) = (options.record, recorder) {
+        // anything that isn't a GIF is recorded as an animated PNG
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gif") => RecordingFormat::Gif,
+            _ => RecordingFormat::Apng,
+        };
+        cli::write(&path, recorder.encode(format)?)?;
+    }
+
+    if let Some(path) = options.screenshot {
+        let png = capture::screenshot_png(&system.display, palette, options.scale)?;
+        cli::write(&path, png)?;
+    }
+
+    if let Some(path) = options.sound_log {
+        cli::write(&path, sound_log)?;
+    }
+
+    if let Some(path) = &options.dump {
+        cli::write(
+            path,
+            dump_display(&system.display, path, palette, options.scale)?,
+        )?;
+    }
+
+    match outcome.reason {
+        StopReason::Condition(condition) => {
+            eprintln!("Stopped after {} frames: {condition}", outcome.frames)
+        }
+        StopReason::Halted => eprintln!("Halted after {} frames", outcome.frames),
+        StopReason::FrameLimit => eprintln!("Ran for {} frames", outcome.frames),
+    }
+    if !options.until.is_empty() && !matches!(outcome.reason, StopReason::Condition(_)) {
+        eprintln!("error: none of the stop conditions were met");
+        return Ok(ExitCode::from(EXIT_CONDITION_NOT_MET));
+    }
+
+    if let Some(path) = &options.golden {
+        if options.update_golden {
+            cli::write(
+                path,
+                dump_display(&system.display, path, palette, options.scale)?,
+            )?;
+        } else {
+            let text = String::from_utf8_lossy(&cli::read(path)?).into_owned();
+            let golden = headless::parse_dump(&text)
+                .ok_or_else(|| CliError::InvalidDump { path: path.clone() })?;
+            match headless::pixel_difference(&golden, &system.display) {
+                Some(0) => {}
+                difference => {
+                    match difference {
+                        Some(pixels) => {
+                            eprintln!("error: {pixels} pixels differ from {}", path.display())
+                        }
+                        None => eprintln!(
+                            "error: the display is {}x{}, but {} is {}x{}",
+                            system.display.get_width(),
+                            system.display.get_height(),
+                            path.display(),
+                            golden.get_width(),
+                            golden.get_height()
+                        ),
+                    }
+                    eprint!("{}", system.display);
+                    return Ok(ExitCode::from(EXIT_GOLDEN_MISMATCH));
+                }
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Dumps the display as a PNG or PBM image depending on the extension, or as text otherwise
+fn dump_display(
+    display: &Display,
+    path: &Path,
+    palette: &Palette,
+    scale: usize,
+) -> Result<Vec<u8>, CliError> {
+    let dump = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("png") => capture::screenshot_png(display, palette, scale)?,
+        Some("pbm") => headless::to_pbm(display).into_bytes(),
+        _ => display.to_string().into_bytes(),
+    };
+    Ok(dump)
+}
+
+/// Prints every executed instruction, frame by frame
+fn trace(machine: &MachineArgs, frames: usize, registers: bool) -> Result<(), CliError> {
+    let cli::Machine {
+        mut system,
+        cycles_per_frame,
+        ..
+    } = machine.load()?;
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    let mut write_line = |line: String| {
+        writeln!(stdout, "{line}").map_err(|source| CliError::Write {
+            path: "standard output".into(),
+            source,
+        })
+    };
+
+    for frame in 0..frames {
+        for _ in 0..cycles_per_frame {
+            if !system.running {
+                break;
+            }
+            let address = system.pc;
+            let opcode = system.get_u16(address as usize);
+            let Some(instruction) = system.step()? else {
+                write_line(format!("{frame:>6} {address:03X}  waiting for key"))?;
+                break;
+            };
+
+            let mut line = format!(
+                "{frame:>6} {address:03X}  {opcode:04X}  {:<20}",
+                asm::mnemonic(&instruction)
+            );
+            if registers {
+                for (index, value) in system.registers.iter().enumerate() {
+                    line.push_str(&format!(" V{index:X}={value:02X}"));
+                }
+                line.push_str(&format!(" I={:03X}", system.pointer));
+            }
+            write_line(line.trim_end().to_owned())?;
+
+            if system.quirks.display_wait && matches!(instruction, Instruction::Draw { .. }) {
+                break;
+            }
+        }
+        system.timers.tick();
+    }
+    Ok(())
+}
+
+/// Prints the size, checksum and instruction usage of a ROM
+fn info(path: &Path, load_address: u16) -> Result<(), CliError> {
+    let rom = cli::read(path)?;
+    // checks that the ROM fits
+    let system = Chip8Builder::new()
+        .load_address(load_address)
+        .rom(&rom)
+        .build()
+        .map_err(|source| CliError::Load {
+            path: path.to_owned(),
+            source,
+        })?;
+
+    let instructions: Vec<Instruction> = rom
+        .chunks_exact(2)
+        .map(|bytes| Instruction::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+        .collect();
+    let count =
+        |matches: fn(&Instruction) -> bool| instructions.iter().filter(|i| matches(i)).count();
+    let unknown = count(|instruction| {
+        matches!(
+            instruction,
+            Instruction::UndefinedOperation(_)
+                | Instruction::Math {
+                    operation: instruction::MathOperation::UnknownOperation(_),
+                    ..
+                }
+        )
+    });
+
+    let mut features = Vec::new();
+    let uses = [
+        (
+            "key input",
+            count(|i| {
+                matches!(
+                    i,
+                    Instruction::KeyPressed(_)
+                        | Instruction::KeyNotPressed(_)
+                        | Instruction::WaitKeyPress(_)
+                )
+            }),
+        ),
+        (
+            "sound",
+            count(|i| matches!(i, Instruction::SetSoundTimer(_))),
+        ),
+        (
+            "random numbers",
+            count(|i| matches!(i, Instruction::Random { .. })),
+        ),
+        (
+            "machine code calls",
+            count(|i| matches!(i, Instruction::MachineCodeCall(_))),
+        ),
+        (
+            "XO-CHIP audio",
+            count(|i| matches!(i, Instruction::LoadAudioPattern | Instruction::SetPitch(_))),
+        ),
+    ];
+    for (feature, uses) in uses {
+        if uses > 0 {
+            features.push(feature);
+        }
+    }
+
+    let end = load_address as usize + rom.len();
+    println!("File:          {}", path.display());
+    println!("Size:          {} bytes", rom.len());
+    println!(
+        "Loaded at:     {load_address:#05X} to {:#05X}",
+        end.saturating_sub(1)
+    );
+    println!("Free memory:   {} bytes", system.memory.len() - end);
+    println!("CRC-32:        {:08X}", crc32(&rom));
+    // sprite data is decoded as instructions too, so these are only estimates
+    println!(
+        "Instructions:  {} ({} unknown opcodes, likely data)",
+        instructions.len(),
+        unknown
+    );
+    if features.is_empty() {
+        println!("Uses:          nothing special");
+    } else {
+        println!("Uses:          {}", features.join(", "));
+    }
+    Ok(())
+}
+
+/// The CRC-32 used by zip and PNG, handy for identifying ROMs
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Runs a test ROM headless with coverage enabled, then prints the final screen
+fn test(
+    machine: &MachineArgs,
+    frames: usize,
+    annotate: Option<PathBuf>,
+    lcov: Option<PathBuf>,
+    source_map: Option<PathBuf>,
+) -> Result<(), CliError> {
+    let cli::Machine {
+        mut system,
+        rom,
+        cycles_per_frame,
+        ..
+    } = machine.load()?;
+    system.enable_coverage();
+    for _ in 0..frames {
+        if !system.running {
+            break;
+        }
+        system.run_frame(cycles_per_frame)?;
+    }
+    let coverage = system.take_coverage().unwrap_or_default();
+
+    println!("{}", system.display);
+    let start = system.load_address();
+    let end = (start as usize + rom.len()).min(system.memory.len());
+    let executed = (start as usize..end)
+        .step_by(2)
+        .filter(|&address| coverage.is_executed(address as u16))
+        .count();
+    println!(
+        "Executed {executed} of {} instruction addresses in the ROM",
+        rom.len().div_ceil(2)
+    );
+
+    if let Some(path) = annotate {
+        cli::write(
+            &path,
+            coverage.annotated_disassembly(&system.memory, start, end),
+        )?;
+    }
+    if let (Some(path), Some(source_map_path)) = (lcov, source_map) {
+        let text = String::from_utf8_lossy(&cli::read(&source_map_path)?).into_owned();
+        let map = SourceMap::parse(&text).map_err(|line| CliError::Read {
+            path: source_map_path.clone(),
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("malformed source map entry on line {line}"),
+            ),
+        })?;
+        let test_name = machine
+            .rom
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        cli::write(&path, coverage.lcov(&system.memory, &map, &test_name))?;
+    }
+    Ok(())
 }
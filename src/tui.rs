@@ -0,0 +1,430 @@
+//! Real-time terminal frontend
+//!
+//! The display is drawn with half-block characters when the terminal is big enough,
+//! and falls back to braille characters, which fit 128x64 in a 64x16 cell area.
+//! Most terminals only report key presses, so releases are emulated by holding each
+//! key for a few frames after the last press or auto-repeat.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use chip8_core::display::Display;
+use chip8_core::frontend::{AudioSink, Input, InputSource, RunStatus, Runner, Sound, VideoSink};
+use chip8_core::keypad::{Key, KeyMap};
+use chip8_core::movie::Playback;
+use chip8_core::Chip8;
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::Print;
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+
+/// Frames a key stays down after the first press, long enough to bridge the host's auto-repeat delay
+const INITIAL_HOLD_FRAMES: u32 = 30;
+/// Frames a key stays down after each auto-repeat
+const REPEAT_HOLD_FRAMES: u32 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Each character is 1x2 pixels
+    HalfBlock,
+    /// Each character is 2x4 pixels
+    Braille,
+}
+
+impl RenderMode {
+    /// Picks the most detailed mode that fits the display, and a status line, in the terminal
+    fn fit(display: &Display, columns: u16, rows: u16) -> Self {
+        let (width, height) = (display.get_width(), display.get_height());
+        if width <= columns as usize && height.div_ceil(2) < rows as usize {
+            RenderMode::HalfBlock
+        } else {
+            RenderMode::Braille
+        }
+    }
+}
+
+/// Converts the display into lines of text
+pub fn render_lines(display: &Display, mode: RenderMode) -> Vec<String> {
+    let (width, height) = (display.get_width(), display.get_height());
+    let pixel = |x: usize, y: usize| x < width && y < height && display.get_pixel(x, y);
+
+    match mode {
+        RenderMode::HalfBlock => (0..height)
+            .step_by(2)
+            .map(|y| {
+                (0..width)
+                    .map(|x| match (pixel(x, y), pixel(x, y + 1)) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    })
+                    .collect()
+            })
+            .collect(),
+        RenderMode::Braille => {
+            // dot bits for each position in the 2x4 cell, by row then column
+            const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+            (0..height)
+                .step_by(4)
+                .map(|y| {
+                    (0..width)
+                        .step_by(2)
+                        .map(|x| {
+                            let mut bits = 0;
+                            for (dy, row) in DOTS.iter().enumerate() {
+                                for (dx, dot) in row.iter().enumerate() {
+                                    if pixel(x + dx, y + dy) {
+                                        bits |= dot;
+                                    }
+                                }
+                            }
+                            char::from_u32(0x2800 + bits).expect("Braille block is valid")
+                        })
+                        .collect()
+                })
+                .collect()
+        }
+    }
+}
+
+/// Emulates key releases for terminals that only report presses
+#[derive(Debug, Default)]
+struct HeldKeys {
+    /// Frames left until each key is released
+    frames_left: [u32; 16],
+}
+
+impl HeldKeys {
+    /// # Returns
+    /// Returns the key press, unless the key was already held
+    fn press(&mut self, key: Key) -> Option<Input> {
+        let frames_left = &mut self.frames_left[key as usize];
+        if *frames_left == 0 {
+            *frames_left = INITIAL_HOLD_FRAMES;
+            Some(Input::Press(key))
+        } else {
+            *frames_left = (*frames_left).max(REPEAT_HOLD_FRAMES);
+            None
+        }
+    }
+
+    fn release(&mut self, key: Key) -> Input {
+        self.frames_left[key as usize] = 0;
+        Input::Release(key)
+    }
+
+    /// Counts down one frame, releasing any keys that expire
+    fn tick(&mut self) -> impl Iterator<Item = Input> + '_ {
+        self.frames_left
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, frames_left)| **frames_left > 0)
+            .filter_map(|(index, frames_left)| {
+                *frames_left -= 1;
+                (*frames_left == 0).then_some(Input::Release(Key::ALL[index]))
+            })
+    }
+}
+
+/// Host keys used to control the emulator, which can't be mapped to the keypad
+pub const RESERVED_KEYS: [&str; 4] = ["p", "m", "h", "space"];
+
+/// The CHIP-8 keypad layout, for showing which host keys to press
+const KEYPAD_ROWS: [[Key; 4]; 4] = [
+    [Key::Key1, Key::Key2, Key::Key3, Key::KeyC],
+    [Key::Key4, Key::Key5, Key::Key6, Key::KeyD],
+    [Key::Key7, Key::Key8, Key::Key9, Key::KeyE],
+    [Key::KeyA, Key::Key0, Key::KeyB, Key::KeyF],
+];
+
+/// Puts the terminal into raw mode on an alternate screen, and restores it when dropped,
+/// including when unwinding from a panic
+struct TerminalGuard {
+    enhanced_keyboard: bool,
+}
+
+impl TerminalGuard {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        // lets us receive real key releases on terminals that support it
+        let enhanced_keyboard = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced_keyboard {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(Self { enhanced_keyboard })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.enhanced_keyboard {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Reads key events, translating them through the keymap
+struct TerminalInput<'a> {
+    keymap: &'a KeyMap,
+    /// Whether the terminal reports key releases, so they don't need to be emulated
+    real_releases: bool,
+    held: HeldKeys,
+    /// Inputs read but not yet returned
+    pending: VecDeque<Input>,
+    /// Whether to show which host key presses each CHIP-8 key, shared with [`TerminalVideo`]
+    show_hints: Rc<Cell<bool>>,
+}
+
+impl TerminalInput<'_> {
+    fn handle_key(&mut self, event: KeyEvent) {
+        let input = match event.code {
+            KeyCode::Esc => Some(Input::Quit),
+            KeyCode::Backspace if event.kind == KeyEventKind::Press => Some(Input::Reset),
+            KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                Some(Input::Quit)
+            }
+            KeyCode::Enter => self
+                .keymap
+                .get("enter")
+                .and_then(|key| self.keypad_key(key, event.kind)),
+            KeyCode::Char(character) => {
+                let character = character.to_ascii_lowercase();
+                if let Some(key) = self.keymap.get(&character.to_string()) {
+                    self.keypad_key(key, event.kind)
+                } else if event.kind == KeyEventKind::Press {
+                    match character {
+                        'p' => Some(Input::Pause),
+                        'm' => Some(Input::Mute),
+                        'h' => {
+                            self.show_hints.set(!self.show_hints.get());
+                            None
+                        }
+                        ' ' => Some(Input::Step),
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        self.pending.extend(input);
+    }
+
+    fn keypad_key(&mut self, key: Key, kind: KeyEventKind) -> Option<Input> {
+        match (kind, self.real_releases) {
+            (KeyEventKind::Press, true) => Some(Input::Press(key)),
+            (KeyEventKind::Release, true) => Some(Input::Release(key)),
+            (KeyEventKind::Repeat, true) => None,
+            (KeyEventKind::Release, false) => Some(self.held.release(key)),
+            (_, false) => self.held.press(key),
+        }
+    }
+}
+
+impl InputSource for TerminalInput<'_> {
+    fn poll(&mut self) -> io::Result<Option<Input>> {
+        while self.pending.is_empty() && event::poll(Duration::ZERO)? {
+            if let Event::Key(key_event) = event::read()? {
+                self.handle_key(key_event);
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    fn next_frame(&mut self) {
+        let released: Vec<Input> = self.held.tick().collect();
+        self.pending.extend(released);
+    }
+}
+
+/// Rings the terminal bell when the sound timer starts
+struct Bell;
+
+impl AudioSink for Bell {
+    fn update(&mut self, _: &Chip8, sound: Sound) -> io::Result<()> {
+        if sound.started {
+            queue!(io::stdout(), Print('\x07'))?;
+        }
+        Ok(())
+    }
+}
+
+struct TerminalVideo<'a> {
+    keymap: &'a KeyMap,
+    show_hints: Rc<Cell<bool>>,
+    mode: Option<RenderMode>,
+    /// The display frame, status flags and hints last drawn, to skip redrawing when nothing changed
+    drawn: Option<(u32, String, bool)>,
+}
+
+impl VideoSink for TerminalVideo<'_> {
+    fn present(&mut self, system: &Chip8, status: &RunStatus) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        let (columns, rows) = terminal::size()?;
+        let mode = RenderMode::fit(&system.display, columns, rows);
+        let show_hints = self.show_hints.get();
+        let mut flags = String::new();
+        if status.paused {
+            flags.push_str("  [paused: Space steps]");
+        }
+        if status.muted {
+            flags.push_str("  [muted]");
+        }
+        if !system.running {
+            flags.push_str("  [halted]");
+        }
+        if system.recording.is_some() {
+            flags.push_str("  [recording]");
+        }
+        if status.replaying {
+            flags.push_str("  [replaying]");
+        }
+        if !status.message.is_empty() {
+            flags.push_str("  ");
+            flags.push_str(status.message);
+        }
+
+        // clear the hints from the screen when hiding them
+        let hints_hidden = self
+            .drawn
+            .as_ref()
+            .is_some_and(|drawn| drawn.2 && !show_hints);
+        if self.mode != Some(mode) || hints_hidden {
+            queue!(stdout, terminal::Clear(ClearType::All))?;
+            self.mode = Some(mode);
+            self.drawn = None;
+        }
+        let drawn = (system.display.frame(), flags, show_hints);
+        if self.drawn.as_ref() == Some(&drawn) {
+            return stdout.flush();
+        }
+        let (_, flags, _) = self.drawn.insert(drawn);
+
+        let lines = render_lines(&system.display, mode);
+        for (row, line) in lines.iter().enumerate() {
+            queue!(stdout, cursor::MoveTo(0, row as u16), Print(line))?;
+        }
+        queue!(
+            stdout,
+            cursor::MoveTo(0, lines.len() as u16),
+            terminal::Clear(ClearType::CurrentLine),
+            Print(format!("PC {:03X}{flags}", system.pc))
+        )?;
+
+        if show_hints {
+            for (row, keys) in KEYPAD_ROWS.iter().enumerate() {
+                let hints: Vec<String> = keys
+                    .iter()
+                    .map(|&key| {
+                        format!(
+                            "{:X}={:<6}",
+                            key as u8,
+                            self.keymap.hint(key).unwrap_or("-")
+                        )
+                    })
+                    .collect();
+                queue!(
+                    stdout,
+                    cursor::MoveTo(0, (lines.len() + 1 + row) as u16),
+                    Print(hints.join(" "))
+                )?;
+            }
+        }
+        stdout.flush()
+    }
+}
+
+/// Runs the system in the terminal at 60 Hz until the user quits
+///
+/// P pauses, Space steps a single instruction while paused, M mutes, Backspace restarts the ROM,
+/// H shows the keymap and Esc quits.
+/// `keymap` must not bind any of the [`RESERVED_KEYS`].
+/// The keypad is driven by `playback` until the movie finishes, if one is given.
+pub fn run(
+    system: &mut Chip8,
+    cycles_per_frame: usize,
+    keymap: &KeyMap,
+    playback: Option<Playback>,
+) -> io::Result<()> {
+    let guard = TerminalGuard::enter()?;
+    let show_hints = Rc::new(Cell::new(false));
+    let input = TerminalInput {
+        keymap,
+        real_releases: guard.enhanced_keyboard,
+        held: HeldKeys::default(),
+        pending: VecDeque::new(),
+        show_hints: show_hints.clone(),
+    };
+    let video = TerminalVideo {
+        keymap,
+        show_hints,
+        mode: None,
+        drawn: None,
+    };
+    let mut runner = Runner::new(video, Bell, input, cycles_per_frame);
+    if let Some(playback) = playback {
+        runner.replay(playback);
+    }
+    runner.run(system)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_block_lines() {
+        let mut display = Display::default();
+        display.draw_sprite(0, 0, 3, &[0b1000_0000, 0b1100_0000, 0b0100_0000], false);
+
+        let lines = render_lines(&display, RenderMode::HalfBlock);
+        assert_eq!(lines.len(), 16);
+        assert!(lines[0].starts_with("█▄ "));
+        assert!(lines[1].starts_with(" ▀ "));
+    }
+
+    #[test]
+    fn braille_lines() {
+        let mut display = Display::new(128, 64);
+        display.draw_sprite(0, 0, 4, &[0x80, 0x40, 0x00, 0xC0], false);
+
+        let lines = render_lines(&display, RenderMode::Braille);
+        assert_eq!(lines.len(), 16);
+        assert_eq!(lines[0].chars().count(), 64);
+        assert_eq!(lines[0].chars().next(), Some('\u{28D1}'));
+    }
+
+    #[test]
+    fn emulated_release() {
+        let mut held = HeldKeys::default();
+        assert_eq!(held.press(Key::Key5), Some(Input::Press(Key::Key5)));
+        for _ in 1..INITIAL_HOLD_FRAMES {
+            assert_eq!(held.tick().next(), None);
+        }
+        assert_eq!(
+            held.press(Key::Key5),
+            None,
+            "Auto-repeat keeps the key held"
+        );
+        for _ in 1..REPEAT_HOLD_FRAMES {
+            assert_eq!(held.tick().next(), None);
+        }
+        assert_eq!(held.tick().collect::<Vec<_>>(), [Input::Release(Key::Key5)]);
+    }
+}
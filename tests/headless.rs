@@ -0,0 +1,129 @@
+//! Runs the emulator binary headless, checking the exit status for each outcome
+
+use std::process::Command;
+
+const ROM: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/chip8_wasm/chip8-test-suite.ch8"
+);
+const SPLASH_GOLDEN: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/golden/chip8-test-suite-splash.txt"
+);
+
+fn run(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_chip8_emu"))
+        .arg("run")
+        .arg(ROM)
+        .args(args)
+        .output()
+        .expect("Couldn't run the emulator")
+        .status
+        .code()
+}
+
+#[test]
+fn matches_golden() {
+    assert_eq!(run(&["--frames", "30", "--golden", SPLASH_GOLDEN]), Some(0));
+}
+
+#[test]
+fn golden_mismatch() {
+    // the splash screen is still being drawn after the first frame
+    assert_eq!(run(&["--frames", "1", "--golden", SPLASH_GOLDEN]), Some(3));
+}
+
+#[test]
+fn stop_conditions() {
+    assert_eq!(run(&["--until", "pc=0x202"]), Some(0));
+    // the splash screen waits for a key, so it never reaches an infinite loop
+    assert_eq!(run(&["--frames", "30", "--until", "self-jump"]), Some(4));
+}
+
+#[test]
+fn load_error() {
+    assert_eq!(run(&["--frames", "1", "--load-addr", "0xF00"]), Some(1));
+    // the address is checked against the memory size, which is 64 KiB on XO-CHIP
+    assert_eq!(run(&["--frames", "1", "--load-addr", "0x1200"]), Some(1));
+    let xo_chip = ["--frames", "1", "--preset", "xo-chip", "--load-addr"];
+    assert_eq!(run(&[&xo_chip[..], &["0x1200"]].concat()), Some(0));
+    assert_eq!(run(&[&xo_chip[..], &["0xF800"]].concat()), Some(1));
+}
+
+#[test]
+fn movies() {
+    let dir = std::env::temp_dir().join(format!("chip8_emu-movies-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let recorded = dir.join("recorded.c8m");
+    let recorded = recorded.to_str().unwrap();
+    assert_eq!(
+        run(&["--frames", "30", "--seed", "7", "--record-movie", recorded]),
+        Some(0)
+    );
+    let movie = std::fs::read_to_string(recorded).unwrap();
+    assert!(movie.starts_with("chip8-movie 1\nseed 7\ncycles-per-frame 10\nframes 30\n"));
+
+    // pressing a key leaves the splash screen for the menu
+    let menu = dir.join("menu.c8m");
+    std::fs::write(
+        &menu,
+        "chip8-movie 1\nseed 7\ncycles-per-frame 10\nframes 60\nquirks\n40 press 1\n45 release 1\n",
+    )
+    .unwrap();
+    let menu = menu.to_str().unwrap();
+    assert_eq!(run(&["--frames", "60", "--golden", SPLASH_GOLDEN]), Some(0));
+    assert_eq!(
+        run(&["--frames", "60", "--golden", SPLASH_GOLDEN, "--movie", menu]),
+        Some(3)
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn profiles() {
+    let dir = std::env::temp_dir().join(format!("chip8_emu-profiles-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let toml = dir.join("game.toml");
+    std::fs::write(&toml, "platform = \"super-chip\"\nload_address = 0xF00\n").unwrap();
+    let toml = toml.to_str().unwrap();
+    assert_eq!(run(&["--frames", "1", "--profile", toml]), Some(1));
+    // options on the command line win over the profile
+    assert_eq!(
+        run(&["--frames", "1", "--profile", toml, "--load-addr", "0x200"]),
+        Some(0)
+    );
+
+    let json = dir.join("game.json");
+    std::fs::write(
+        &json,
+        r#"{"cycles_per_frame": 20, "quirks": {"alt_shift": true}}"#,
+    )
+    .unwrap();
+    let json = json.to_str().unwrap();
+    let recorded = dir.join("recorded.c8m");
+    let recorded = recorded.to_str().unwrap();
+    assert_eq!(
+        run(&[
+            "--frames",
+            "1",
+            "--profile",
+            json,
+            "--record-movie",
+            recorded
+        ]),
+        Some(0)
+    );
+    let movie = std::fs::read_to_string(recorded).unwrap();
+    assert!(movie.contains("cycles-per-frame 20\n"));
+    assert!(movie.contains(" alt_shift"));
+
+    let invalid = dir.join("invalid.toml");
+    std::fs::write(&invalid, "cycles = 20\n").unwrap();
+    assert_eq!(
+        run(&["--frames", "1", "--profile", invalid.to_str().unwrap()]),
+        Some(1)
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}